@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use rustler::env::OwnedEnv;
+use rustler::types::atom;
+use rustler::{Encoder, Env, NifResult, Term};
+use crate::error::{encode_error, SnpError};
+
+rustler::atoms! {
+    snp_result,
+}
+
+/// The most async NIF calls running at once. Beyond it, calls fail with
+/// `busy` rather than starting yet another thread.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// The number of async NIF calls currently running.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// A slot among the `MAX_IN_FLIGHT` running calls, released when dropped,
+/// including if the work panics.
+struct Slot;
+
+impl Slot {
+    fn acquire() -> Option<Slot> {
+        IN_FLIGHT
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+                (running < MAX_IN_FLIGHT).then_some(running + 1)
+            })
+            .ok()
+            .map(|_| Slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Runs a NIF's work on its own thread, off the BEAM schedulers.
+///
+/// Returns `{ok, Ref}` immediately. When the work completes, the calling
+/// process is sent `{snp_result, Ref, Result}`, where `Result` is the term
+/// the work encodes, i.e. what the synchronous NIF would have returned.
+///
+/// The work must own its inputs: Erlang terms cannot leave the calling NIF,
+/// so arguments are decoded before the work is spawned.
+///
+/// At most `MAX_IN_FLIGHT` calls run at once; past that, `{error, {busy,
+/// Detail}}` is returned and the work is not started.
+pub fn spawn<'a, F>(env: Env<'a>, work: F) -> NifResult<Term<'a>>
+where
+    F: for<'b> FnOnce(Env<'b>) -> Term<'b> + Send + 'static,
+{
+    let slot = match Slot::acquire() {
+        Some(slot) => slot,
+        None => {
+            let err = SnpError::Busy {
+                detail: format!("{MAX_IN_FLIGHT} async calls already running"),
+            };
+            return Ok(encode_error(env, &err));
+        }
+    };
+    let pid = env.pid();
+    let reference = env.make_ref();
+    let mut owned_env = OwnedEnv::new();
+    let saved_reference = owned_env.save(reference);
+    let spawned = thread::Builder::new().spawn(move || {
+        let _slot = slot;
+        // The caller may have exited, in which case there is no one to notify.
+        let _ = owned_env.send_and_clear(&pid, |env| {
+            let reference = saved_reference.load(env);
+            (snp_result(), reference, work(env)).encode(env)
+        });
+    });
+    if let Err(err) = spawned {
+        let err = SnpError::Internal {
+            detail: format!("Failed to start async call: {err}"),
+        };
+        return Ok(encode_error(env, &err));
+    }
+    Ok((atom::ok(), reference).encode(env))
+}
@@ -1,9 +1,17 @@
 use rustler::{Binary, Encoder, Env, NifResult, Term};
-use rustler::types::atom::{self, ok};
-use sev::firmware::guest::{Firmware, AttestationReport};
+use sev::firmware::guest::AttestationReport;
+use sev::firmware::host::{CertTableEntry, CertType};
 use serde_json::to_string;
+use crate::error::{encode_error, encode_result, SnpError, SnpResult};
+use crate::firmware;
+use crate::helpers::encode_binary;
 use crate::logging::log_message;
 
+rustler::atoms! {
+    report,
+    certs,
+}
+
 /// Generates an attestation report using the provided unique data and VMPL value.
 ///
 /// # Arguments
@@ -13,77 +21,152 @@ use crate::logging::log_message;
 ///
 /// # Returns
 /// A tuple containing an `ok` atom and the serialized attestation report in JSON format.
-/// If an error occurs during the generation or serialization process, an error is returned.
+/// If an error occurs during the generation or serialization process,
+/// `{error, {firmware_error | serialization_failed, Detail}}` is returned.
 ///
 /// # Example
 /// ```erlang
 /// {ok, JsonReport} = dev_snp_nif:generate_attestation_report(UniqueDataBinary, VMPL).
 /// ```
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn generate_attestation_report<'a>(
     env: Env<'a>,
     unique_data: Binary,
     vmpl: u32,
 ) -> NifResult<Term<'a>> {
-    log_message("INFO", file!(), line!(), "Starting attestation report generation...");
+    log_message("DEBUG", file!(), line!(), "Starting attestation report generation...");
 
     // Step 1: Convert the binary input to a fixed-size array.
     let unique_data_array: [u8; 64] = match unique_data.as_slice().try_into() {
         Ok(data) => data,
         Err(_) => {
-            let msg = "Input binary must be exactly 64 bytes long.";
-            log_message("ERROR", file!(), line!(), msg);
-            return Err(rustler::Error::BadArg);
+            let err = SnpError::invalid_argument(
+                "unique_data",
+                "Input binary must be exactly 64 bytes long.",
+            );
+            return Ok(encode_error(env, &err));
         }
     };
 
     // Step 2: Open the firmware interface.
-    let mut firmware = match Firmware::open() {
+    let mut firmware = match firmware::open() {
         Ok(fw) => {
-            log_message("INFO", file!(), line!(), "Firmware opened successfully.");
+            log_message("DEBUG", file!(), line!(), "Firmware opened successfully.");
             fw
         }
-        Err(err) => {
-            let msg = format!("Failed to open firmware: {:?}", err);
-            log_message("ERROR", file!(), line!(), &msg);
-            return Ok((atom::error(), msg).encode(env));
-        }
+        Err(err) => return Ok(encode_error(env, &err)),
     };
 
     // Step 3: Generate the attestation report.
-    let report: AttestationReport = match firmware.get_report(None, Some(unique_data_array), Some(vmpl)) {
+    let report: AttestationReport = match firmware.get_report(unique_data_array, vmpl) {
         Ok(report) => {
-            log_message("INFO", file!(), line!(), "Attestation report generated successfully.");
+            log_message("DEBUG", file!(), line!(), "Attestation report generated successfully.");
             report
         }
-        Err(err) => {
-            let msg = format!("Failed to generate attestation report: {:?}", err);
-            log_message("ERROR", file!(), line!(), &msg);
-            return Ok((atom::error(), msg).encode(env));
-        }
+        Err(err) => return Ok(encode_error(env, &err)),
     };
 
     // Step 4: Serialize the report into a JSON string for output.
     let report_json = match to_string(&report) {
         Ok(json) => {
-            log_message("INFO", file!(), line!(), "Attestation report serialized to JSON format.");
+            log_message("DEBUG", file!(), line!(), "Attestation report serialized to JSON format.");
             json
         }
         Err(err) => {
-            let msg = format!("Failed to serialize attestation report: {:?}", err);
-            log_message("ERROR", file!(), line!(), &msg);
-            return Ok((atom::error(), msg).encode(env));
+            let err = SnpError::Serialization {
+                detail: format!("Failed to serialize attestation report: {:?}", err),
+            };
+            return Ok(encode_error(env, &err));
         }
     };
 
     // Step 5: Log the serialized JSON for debugging purposes.
     log_message(
-        "INFO",
+        "DEBUG",
         file!(),
         line!(),
         &format!("Generated report JSON: {:?}", report_json),
     );
 
     // Step 6: Return the result as a tuple with the `ok` atom.
-    Ok((ok(), report_json).encode(env))
+    Ok(encode_result(env, Ok(report_json)))
+}
+
+/// Encodes the certificate table of an extended report as a map from
+/// certificate type (`ark`, `ask`, `vcek`, `vlek` or `crl`, or the GUID of
+/// other entries) to the certificate as provided by the host.
+fn encode_cert_table<'a>(env: Env<'a>, table: &[CertTableEntry]) -> SnpResult<Term<'a>> {
+    let map_err = |_| SnpError::Serialization {
+        detail: "Failed to build certificate map".to_string(),
+    };
+    let mut map = Term::map_new(env);
+    for entry in table {
+        let name = match &entry.cert_type {
+            CertType::ARK => "ark",
+            CertType::ASK => "ask",
+            CertType::VCEK => "vcek",
+            CertType::VLEK => "vlek",
+            CertType::CRL => "crl",
+            CertType::OTHER(guid) => {
+                let key = encode_binary(env, guid.to_string().as_bytes())?;
+                map = map.map_put(key, encode_binary(env, &entry.data)?).map_err(map_err)?;
+                continue;
+            }
+            _ => continue,
+        };
+        let key = rustler::Atom::from_str(env, name).map_err(map_err)?;
+        map = map.map_put(key, encode_binary(env, &entry.data)?).map_err(map_err)?;
+    }
+    Ok(map)
+}
+
+/// Requests an extended attestation report and encodes it with its
+/// certificate table.
+fn extended_report<'a>(env: Env<'a>, unique_data: &[u8], vmpl: u32) -> SnpResult<Term<'a>> {
+    let unique_data: [u8; 64] = unique_data.try_into().map_err(|_| {
+        SnpError::invalid_argument("unique_data", "Input binary must be exactly 64 bytes long.")
+    })?;
+    let (attestation_report, table) = firmware::open()?.get_ext_report(unique_data, vmpl)?;
+    let report_json = to_string(&attestation_report).map_err(|err| SnpError::Serialization {
+        detail: format!("Failed to serialize attestation report: {:?}", err),
+    })?;
+    let cert_map = encode_cert_table(env, &table)?;
+    Term::map_new(env)
+        .map_put(report(), report_json.encode(env))
+        .and_then(|map| map.map_put(certs(), cert_map))
+        .map_err(|_| SnpError::Serialization {
+            detail: "Failed to build extended report map".to_string(),
+        })
+}
+
+/// Generates an extended attestation report, which carries the certificates
+/// the hypervisor provides for the guest alongside the report.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `unique_data` - A 64-byte binary input containing unique data for the attestation report.
+/// * `vmpl` - The Virtual Machine Privilege Level (VMPL) to be used in the report.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and a map with the JSON report under
+/// `report` and the host's certificate table under `certs`, keyed by
+/// certificate type (`vcek` or `vlek`, `ask`, `ark`, and possibly `crl`).
+/// The `certs` map is empty if the host provides no certificates. It can be
+/// passed to `verify_signature/2` to verify the report without contacting
+/// the KDS.
+///
+/// # Example
+/// ```erlang
+/// {ok, #{ report := Report, certs := Certs }} =
+///     dev_snp_nif:generate_extended_report(UniqueDataBinary, VMPL),
+/// {ok, true} = dev_snp_nif:verify_signature(Report, #{ certs => Certs }).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn generate_extended_report<'a>(
+    env: Env<'a>,
+    unique_data: Binary,
+    vmpl: u32,
+) -> NifResult<Term<'a>> {
+    log_message("DEBUG", file!(), line!(), "Starting extended attestation report generation...");
+    Ok(encode_result(env, extended_report(env, unique_data.as_slice(), vmpl)))
 }
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::thread;
+use rustler::types::atom;
+use rustler::{Binary, Encoder, Env, MapIterator, NifResult, Term};
+use sev::firmware::guest::AttestationReport;
+use crate::error::{encode_error, encode_result, SnpError, SnpResult};
+use crate::kds::KeyType;
+use crate::product::{tcb_bytes, Product};
+use crate::report;
+use crate::verification::{check_report, parse_verify_opts, Authority, Signer, VerifyOpts};
+
+/// The certificates that sign a report are shared by all reports from the
+/// same chip at the same reported TCB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct SignerKey {
+    product: Product,
+    key_type: KeyType,
+    chip_id: [u8; 64],
+    tcb: [u8; 8],
+}
+
+impl SignerKey {
+    fn of(attestation_report: &AttestationReport, product: Option<Product>) -> SnpResult<SignerKey> {
+        Ok(SignerKey {
+            product: Product::of_report(attestation_report, product),
+            key_type: KeyType::from_report(attestation_report)?,
+            chip_id: attestation_report.chip_id,
+            tcb: tcb_bytes(&attestation_report.reported_tcb),
+        })
+    }
+}
+
+/// Applies `f` to every item on a pool of scoped threads, at most one per
+/// available CPU, and returns the results in order. If a worker panics, each
+/// item of its chunk gets an `Internal` error instead of a result.
+fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<SnpResult<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> SnpResult<R> + Sync,
+{
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = items.len().div_ceil(workers).max(1);
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| (chunk.len(), scope.spawn(|| chunk.iter().map(&f).collect::<Vec<_>>())))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|(len, handle)| {
+                handle.join().unwrap_or_else(|panic| {
+                    let reason = panic
+                        .downcast_ref::<&str>()
+                        .map(|reason| reason.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown panic".to_string());
+                    let err = SnpError::Internal {
+                        detail: format!("verification worker panicked: {reason}"),
+                    };
+                    (0..len).map(|_| Err(err.clone())).collect()
+                })
+            })
+            .collect()
+    })
+}
+
+/// Verifies a batch of reports, resolving the certificate authority of each
+/// product line and the signing key of each chip and TCB once.
+fn verify_batch(reports: &[Vec<u8>], opts: &VerifyOpts) -> Vec<SnpResult<bool>> {
+    // Step 1: Parse the reports and identify their signers.
+    let parsed: Vec<SnpResult<(AttestationReport, SignerKey)>> = parallel_map(reports, |raw| {
+        let attestation_report = report::parse(raw)?;
+        let key = SignerKey::of(&attestation_report, opts.product)?;
+        Ok((attestation_report, key))
+    });
+
+    // Step 2: Resolve the ARK, ASK and CRL of each product line and key type.
+    let mut authorities: HashMap<(Product, KeyType), SnpResult<Authority>> = HashMap::new();
+    for (_, key) in parsed.iter().flatten() {
+        authorities
+            .entry((key.product, key.key_type))
+            .or_insert_with(|| Authority::resolve(key.product, key.key_type, &opts.certs));
+    }
+
+    // Step 3: Resolve the signing key of each chip and TCB, in parallel.
+    let mut signer_reports: HashMap<SignerKey, &AttestationReport> = HashMap::new();
+    for (attestation_report, key) in parsed.iter().flatten() {
+        signer_reports.entry(*key).or_insert(attestation_report);
+    }
+    let signer_reports: Vec<(SignerKey, &AttestationReport)> = signer_reports.into_iter().collect();
+    let resolved = parallel_map(&signer_reports, |(key, attestation_report)| {
+        match &authorities[&(key.product, key.key_type)] {
+            Ok(authority) => authority.signer(attestation_report, &opts.certs),
+            Err(err) => Err(err.clone()),
+        }
+    });
+    let signers: HashMap<SignerKey, SnpResult<Signer>> = signer_reports
+        .iter()
+        .map(|(key, _)| *key)
+        .zip(resolved)
+        .collect();
+
+    // Step 4: Verify the reports, in parallel and in order.
+    parallel_map(&parsed, |entry| {
+        let (attestation_report, key) = entry.as_ref().map_err(Clone::clone)?;
+        let signer = signers[key].as_ref().map_err(Clone::clone)?;
+        check_report(attestation_report, opts, Some(signer))
+    })
+}
+
+/// Decodes the list of reports and verifies them.
+fn verify_reports_impl<'a>(env: Env<'a>, reports: Term<'a>, opts: &VerifyOpts) -> NifResult<Term<'a>> {
+    let reports: Vec<Binary> = reports.decode()?;
+    let reports: Vec<Vec<u8>> = reports.iter().map(|report| report.as_slice().to_vec()).collect();
+    let results: Vec<Term<'a>> = verify_batch(&reports, opts)
+        .into_iter()
+        .map(|result| encode_result(env, result))
+        .collect();
+    Ok((atom::ok(), results).encode(env))
+}
+
+/// Verifies the signatures of a batch of attestation reports.
+///
+/// Each certificate is requested once for the whole batch: the ARK, ASK and
+/// CRL once per product line, and the VCEK once per chip and reported TCB.
+/// The reports are then verified in parallel.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `reports` - A list of attestation reports, each raw or JSON encoded.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and a list with, in the order of the
+/// reports, the result that `verify_signature/1` gives for each.
+///
+/// # Example
+/// ```erlang
+/// {ok, [{ok, true}, {error, {signature_invalid, _}}]} =
+///     dev_snp_nif:verify_reports([PeerReport, ForgedReport]).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn verify_reports<'a>(env: Env<'a>, reports: Term<'a>) -> NifResult<Term<'a>> {
+    verify_reports_impl(env, reports, &VerifyOpts::default())
+}
+
+/// Verifies the signatures of a batch of attestation reports with explicit
+/// options.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `reports` - A list of attestation reports, each raw or JSON encoded.
+/// * `opts` - An Erlang map of verification options, as for
+///   `verify_signature/2`, applied to every report. With `replay_ttl`, only
+///   one copy of a report repeated in the batch is accepted, and a `nonce`
+///   is consumed by the first report bound to it.
+///
+/// # Returns
+/// The same result as `verify_reports/1`, or `{error, {Kind, Detail}}` if
+/// the options are invalid.
+///
+/// # Example
+/// ```erlang
+/// {ok, Results} = dev_snp_nif:verify_reports(Reports, #{ min_tcb => #{ snp => 22 } }).
+/// ```
+#[rustler::nif(name = "verify_reports", schedule = "DirtyIo")]
+pub fn verify_reports_with_opts<'a>(
+    env: Env<'a>,
+    reports: Term<'a>,
+    opts: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let map_iter = MapIterator::new(opts).ok_or(rustler::Error::BadArg)?;
+    match parse_verify_opts(map_iter) {
+        Ok(opts) => verify_reports_impl(env, reports, &opts),
+        Err(err) => Ok(encode_error(env, &err)),
+    }
+}
@@ -0,0 +1,373 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use rustler::{Env, NifResult, Term};
+use openssl::x509::{X509Crl, X509};
+use sev::firmware::host::TcbVersion;
+use crate::error::{encode_result, encode_unit, SnpError, SnpResult};
+use crate::helpers::decode_string;
+use crate::kds::KeyType;
+use crate::logging::log_message;
+use crate::product::tcb_bytes;
+
+/// Default location of the certificate cache, relative to the node's working directory.
+const DEFAULT_CACHE_DIR: &str = "cache-snp-certs";
+/// File name of the PEM-encoded ASK + ARK chain stored for each product line.
+const CERT_CHAIN_FILE: &str = "cert_chain.pem";
+/// File name of the DER-encoded CRL stored for each product line.
+const CRL_FILE: &str = "crl.der";
+/// Prefix of the files holding the ASVK + ARK chain and CRL used for VLEKs.
+const VLEK_PREFIX: &str = "vlek_";
+/// Directory (per product line) holding the DER-encoded VCEKs.
+const VCEK_DIR: &str = "vcek";
+
+/// The directory currently used for the certificate cache. `None` means the default.
+static CACHE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Counter making the temporary file of each cache write unique.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the root directory of the certificate cache.
+///
+/// The layout of the cache is:
+/// ```text
+/// <root>/<product>/cert_chain.pem
+/// <root>/<product>/crl.der
+/// <root>/<product>/vlek_cert_chain.pem
+/// <root>/<product>/vlek_crl.der
+/// <root>/<product>/vcek/<chip_id>/<tcb>.der
+/// ```
+/// where `<tcb>` is the hex encoding of the raw 8-byte TCB version, so that the
+/// key is unambiguous for every product line's TCB layout.
+pub fn cache_dir() -> PathBuf {
+    match CACHE_DIR.lock() {
+        Ok(dir) => dir.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR)),
+        Err(_) => PathBuf::from(DEFAULT_CACHE_DIR),
+    }
+}
+
+/// Sets the root directory of the certificate cache, creating it if needed.
+///
+/// # Arguments
+/// * `dir` - The directory to use for cached certificates.
+///
+/// # Errors
+/// Returns an error if the directory cannot be created.
+pub fn set_cache_dir(dir: PathBuf) -> SnpResult<()> {
+    fs::create_dir_all(&dir).map_err(|err| cache_error(&dir, err))?;
+    let mut current = CACHE_DIR.lock().map_err(|_| SnpError::Cache {
+        detail: "Certificate cache lock poisoned".to_string(),
+    })?;
+    *current = Some(dir);
+    Ok(())
+}
+
+/// Wraps an I/O error on a cache path.
+fn cache_error(path: &Path, err: std::io::Error) -> SnpError {
+    SnpError::Cache {
+        detail: format!("{}: {}", path.display(), err),
+    }
+}
+
+/// Name of a per-product file, which is prefixed for VLEK certificates.
+fn file_name(key_type: KeyType, name: &str) -> String {
+    match key_type {
+        KeyType::Vcek => name.to_string(),
+        KeyType::Vlek => format!("{VLEK_PREFIX}{name}"),
+    }
+}
+
+/// Path of the cached ASK (or ASVK) + ARK chain for a product line.
+fn cert_chain_path(root: &Path, product: &str, key_type: KeyType) -> PathBuf {
+    root.join(product).join(file_name(key_type, CERT_CHAIN_FILE))
+}
+
+/// Path of the cached CRL for a product line.
+fn crl_path(root: &Path, product: &str, key_type: KeyType) -> PathBuf {
+    root.join(product).join(file_name(key_type, CRL_FILE))
+}
+
+/// Path of the cached VCEK for a chip ID and TCB version.
+fn vcek_path(root: &Path, product: &str, chip_id: &[u8; 64], tcb: &TcbVersion) -> PathBuf {
+    root.join(product)
+        .join(VCEK_DIR)
+        .join(hex::encode(chip_id))
+        .join(format!("{}.der", hex::encode(tcb_bytes(tcb))))
+}
+
+/// Writes a file into the cache, going through a temporary file so that a
+/// concurrent reader never observes a partially written certificate. Each
+/// write has a temporary file of its own, as concurrent verifications may
+/// store the same certificate at once.
+fn write_atomic(path: &Path, contents: &[u8]) -> SnpResult<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| cache_error(parent, err))?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = PathBuf::from(tmp);
+    let written = fs::write(&tmp, contents)
+        .map_err(|err| cache_error(&tmp, err))
+        .and_then(|()| fs::rename(&tmp, path).map_err(|err| cache_error(path, err)));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Loads the cached PEM-encoded ASK (or ASVK) + ARK chain for a product line, if present.
+pub fn load_cert_chain(product: &str, key_type: KeyType) -> Option<Vec<u8>> {
+    fs::read(cert_chain_path(&cache_dir(), product, key_type)).ok()
+}
+
+/// Stores the PEM-encoded ASK (or ASVK) + ARK chain for a product line.
+pub fn store_cert_chain(product: &str, key_type: KeyType, pem: &[u8]) -> SnpResult<()> {
+    write_atomic(&cert_chain_path(&cache_dir(), product, key_type), pem)
+}
+
+/// Loads the cached DER-encoded CRL for a product line, if present.
+pub fn load_crl(product: &str, key_type: KeyType) -> Option<Vec<u8>> {
+    fs::read(crl_path(&cache_dir(), product, key_type)).ok()
+}
+
+/// Stores the DER-encoded CRL for a product line.
+pub fn store_crl(product: &str, key_type: KeyType, der: &[u8]) -> SnpResult<()> {
+    write_atomic(&crl_path(&cache_dir(), product, key_type), der)
+}
+
+/// Loads the cached DER-encoded VCEK for a chip ID and TCB version, if present.
+pub fn load_vcek(product: &str, chip_id: &[u8; 64], tcb: &TcbVersion) -> Option<Vec<u8>> {
+    fs::read(vcek_path(&cache_dir(), product, chip_id, tcb)).ok()
+}
+
+/// Stores the DER-encoded VCEK for a chip ID and TCB version.
+pub fn store_vcek(
+    product: &str,
+    chip_id: &[u8; 64],
+    tcb: &TcbVersion,
+    der: &[u8],
+) -> SnpResult<()> {
+    write_atomic(&vcek_path(&cache_dir(), product, chip_id, tcb), der)
+}
+
+/// Returns the common name of the subject of a certificate.
+fn common_name(cert: &X509) -> Option<String> {
+    cert.subject_name()
+        .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string())
+}
+
+/// Validates a PEM file holding an ASK (or ASVK) + ARK chain and returns the
+/// product line named by its ARK (e.g. `ARK-Milan` yields `Milan`), along with
+/// the key type the chain issues, as named by its intermediate
+/// (`SEV-Milan` for VCEKs, `SEV-VLEK-Milan` for VLEKs).
+fn chain_product(pem: &[u8]) -> Option<(String, KeyType)> {
+    let chain = X509::stack_from_pem(pem).ok()?;
+    if chain.len() < 2 {
+        return None;
+    }
+    let product = common_name(&chain[1])?.strip_prefix("ARK-")?.to_string();
+    let key_type = match common_name(&chain[0]) {
+        Some(name) if name.starts_with("SEV-VLEK-") => KeyType::Vlek,
+        _ => KeyType::Vcek,
+    };
+    Some((product, key_type))
+}
+
+/// Reads a certificate file (PEM or DER) and returns it DER-encoded.
+fn read_cert_as_der(path: &Path) -> SnpResult<Vec<u8>> {
+    let bytes = fs::read(path).map_err(|err| cache_error(path, err))?;
+    X509::from_der(&bytes)
+        .or_else(|_| X509::from_pem(&bytes))
+        .and_then(|cert| cert.to_der())
+        .map_err(|err| SnpError::InvalidCertificate {
+            detail: format!("{}: {}", path.display(), err),
+        })
+}
+
+/// Pre-seeds the certificate cache from a directory of PEM/DER files so that
+/// verification can run without access to AMD's KDS.
+///
+/// The source directory may either mirror the cache layout (see `cache_dir`)
+/// or contain loose PEM chains as downloaded from the KDS `cert_chain`
+/// endpoint, in which case the product line is taken from the ARK's subject
+/// and the key type (VCEK or VLEK) from the intermediate's.
+///
+/// # Arguments
+/// * `src` - The directory to import certificates from.
+///
+/// # Returns
+/// The number of certificate files imported into the cache.
+///
+/// # Errors
+/// Returns an error if the directory cannot be read or a file cannot be written.
+pub fn seed_from_dir(src: &Path) -> SnpResult<usize> {
+    let root = cache_dir();
+    let mut imported = 0;
+
+    for entry in fs::read_dir(src).map_err(|err| cache_error(src, err))? {
+        let path = entry.map_err(|err| cache_error(src, err))?.path();
+
+        // Loose chain files, e.g. `amd-vcek-v1-Milan-cert_chain.pem`.
+        if path.is_file() {
+            let pem = fs::read(&path).map_err(|err| cache_error(&path, err))?;
+            match chain_product(&pem) {
+                Some((product, key_type)) => {
+                    write_atomic(&cert_chain_path(&root, &product, key_type), &pem)?;
+                    imported += 1;
+                }
+                None => log_message(
+                    "WARN",
+                    file!(),
+                    line!(),
+                    &format!("Skipping unrecognised certificate file: {:?}", path),
+                ),
+            }
+            continue;
+        }
+
+        // Product directories mirroring the cache layout.
+        let product = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        for key_type in [KeyType::Vcek, KeyType::Vlek] {
+            let chain_file = path.join(file_name(key_type, CERT_CHAIN_FILE));
+            if chain_file.is_file() {
+                let pem = fs::read(&chain_file).map_err(|err| cache_error(&chain_file, err))?;
+                if chain_product(&pem).is_some() {
+                    write_atomic(&cert_chain_path(&root, &product, key_type), &pem)?;
+                    imported += 1;
+                } else {
+                    log_message(
+                        "WARN",
+                        file!(),
+                        line!(),
+                        &format!("Skipping invalid certificate chain: {:?}", chain_file),
+                    );
+                }
+            }
+            let crl_file = path.join(file_name(key_type, CRL_FILE));
+            if crl_file.is_file() {
+                let bytes = fs::read(&crl_file).map_err(|err| cache_error(&crl_file, err))?;
+                match X509Crl::from_der(&bytes).or_else(|_| X509Crl::from_pem(&bytes)) {
+                    Ok(crl) => {
+                        let der = crl.to_der().map_err(|err| SnpError::CrlInvalid {
+                            detail: format!("{}: {}", crl_file.display(), err),
+                        })?;
+                        write_atomic(&crl_path(&root, &product, key_type), &der)?;
+                        imported += 1;
+                    }
+                    Err(_) => log_message(
+                        "WARN",
+                        file!(),
+                        line!(),
+                        &format!("Skipping invalid CRL: {:?}", crl_file),
+                    ),
+                }
+            }
+        }
+        let vcek_dir = path.join(VCEK_DIR);
+        if !vcek_dir.is_dir() {
+            continue;
+        }
+        for hw_entry in fs::read_dir(&vcek_dir).map_err(|err| cache_error(&vcek_dir, err))? {
+            let hw_dir = hw_entry.map_err(|err| cache_error(&vcek_dir, err))?.path();
+            let hw_id = match hw_dir.file_name().and_then(|name| name.to_str()) {
+                Some(name) if hw_dir.is_dir() => name.to_string(),
+                _ => continue,
+            };
+            for cert_entry in fs::read_dir(&hw_dir).map_err(|err| cache_error(&hw_dir, err))? {
+                let cert_file = cert_entry.map_err(|err| cache_error(&hw_dir, err))?.path();
+                let stem = match cert_file.file_stem().and_then(|name| name.to_str()) {
+                    Some(stem) => stem.to_string(),
+                    None => continue,
+                };
+                match read_cert_as_der(&cert_file) {
+                    Ok(der) => {
+                        let dest = root
+                            .join(&product)
+                            .join(VCEK_DIR)
+                            .join(&hw_id)
+                            .join(format!("{stem}.der"));
+                        write_atomic(&dest, &der)?;
+                        imported += 1;
+                    }
+                    Err(err) => log_message(
+                        "WARN",
+                        file!(),
+                        line!(),
+                        &format!("Skipping invalid VCEK {:?}: {}", cert_file, err),
+                    ),
+                }
+            }
+        }
+    }
+
+    Ok(imported)
+}
+
+/// Sets the directory used to cache AMD certificates.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `dir` - The cache directory, as a binary or string.
+///
+/// # Returns
+/// `ok` if the directory could be created, or `{error, {cache_error, Detail}}`.
+///
+/// # Example
+/// ```erlang
+/// ok = dev_snp_nif:set_cert_cache_dir(<<"cache-snp-certs">>).
+/// ```
+#[rustler::nif]
+pub fn set_cert_cache_dir<'a>(env: Env<'a>, dir: Term<'a>) -> NifResult<Term<'a>> {
+    let dir = decode_string(dir)?;
+    Ok(encode_unit(env, set_cache_dir(PathBuf::from(dir))))
+}
+
+/// Returns the directory used to cache AMD certificates, so that it can be
+/// restored after a temporary `set_cert_cache_dir/1`.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the cache directory, as a binary.
+///
+/// # Example
+/// ```erlang
+/// {ok, Dir} = dev_snp_nif:cert_cache_dir().
+/// ```
+#[rustler::nif]
+pub fn cert_cache_dir(env: Env) -> NifResult<Term> {
+    let dir: SnpResult<String> = Ok(cache_dir().to_string_lossy().into_owned());
+    Ok(encode_result(env, dir))
+}
+
+/// Imports the certificates found in a directory into the certificate cache.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `dir` - The directory to import from, as a binary or string.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the number of imported certificate files.
+///
+/// # Example
+/// ```erlang
+/// {ok, Count} = dev_snp_nif:seed_cert_cache(<<"certificates">>).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn seed_cert_cache<'a>(env: Env<'a>, dir: Term<'a>) -> NifResult<Term<'a>> {
+    let dir = decode_string(dir)?;
+    Ok(encode_result(env, seed_from_dir(Path::new(&dir))))
+}
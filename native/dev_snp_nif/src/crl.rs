@@ -0,0 +1,213 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use openssl::asn1::Asn1Time;
+use openssl::x509::{CrlStatus, X509Crl, X509};
+use rustler::{Env, MapIterator, NifResult, Term};
+use sev::certs::snp::Certificate;
+use crate::cert_store;
+use crate::error::{encode_unit, SnpError, SnpResult};
+use crate::helpers::decode_string;
+use crate::kds::{self, KeyType};
+use crate::logging::log_message;
+use crate::product::Product;
+
+/// Where CRLs are obtained from.
+#[derive(Debug, Clone)]
+pub enum CrlSource {
+    /// The certificate provider (see `kds`), through the on-disk cache.
+    Provider,
+    /// A single CRL file, used for every product line. Intended for tests
+    /// and air-gapped deployments.
+    File(PathBuf),
+}
+
+/// The configured CRL source. `None` means `CrlSource::Provider`.
+static SOURCE: RwLock<Option<CrlSource>> = RwLock::new(None);
+
+/// Builds a `CrlInvalid` error.
+fn invalid(detail: impl Into<String>) -> SnpError {
+    SnpError::CrlInvalid {
+        detail: detail.into(),
+    }
+}
+
+/// Returns the configured CRL source.
+fn source() -> SnpResult<CrlSource> {
+    let source = SOURCE.read().map_err(|_| invalid("CRL source lock poisoned"))?;
+    Ok(source.clone().unwrap_or(CrlSource::Provider))
+}
+
+/// Parses a CRL given either DER or PEM encoded.
+fn parse(bytes: &[u8]) -> SnpResult<X509Crl> {
+    X509Crl::from_der(bytes)
+        .or_else(|_| X509Crl::from_pem(bytes))
+        .map_err(|err| invalid(format!("unable to parse CRL: {err}")))
+}
+
+/// Whether a CRL's `nextUpdate` time has passed.
+fn is_stale(crl: &X509Crl) -> bool {
+    let now = match Asn1Time::days_from_now(0) {
+        Ok(now) => now,
+        Err(_) => return true,
+    };
+    match crl.next_update() {
+        Some(next_update) => next_update.compare(&now).map_or(true, |o| o == Ordering::Less),
+        None => false,
+    }
+}
+
+/// Loads the CRL for a product line and key type from the configured source.
+/// A cached CRL is used until its `nextUpdate` time, after which a fresh one
+/// is fetched.
+fn load(product: Product, key_type: KeyType) -> SnpResult<X509Crl> {
+    match source()? {
+        CrlSource::File(path) => {
+            let bytes = fs::read(&path)
+                .map_err(|err| invalid(format!("{}: {}", path.display(), err)))?;
+            parse(&bytes)
+        }
+        CrlSource::Provider => {
+            if let Some(cached) = cert_store::load_crl(product.kds_name(), key_type) {
+                match parse(&cached) {
+                    Ok(crl) if !is_stale(&crl) => return Ok(crl),
+                    Ok(_) => {}
+                    Err(err) => log_message(
+                        "WARN",
+                        file!(),
+                        line!(),
+                        &format!("Ignoring corrupt cached CRL: {}", err),
+                    ),
+                }
+            }
+            let body = kds::provider()?.crl(product, key_type)?;
+            let crl = parse(&body)?;
+            let der = crl.to_der().map_err(|err| invalid(err.to_string()))?;
+            if let Err(err) = cert_store::store_crl(product.kds_name(), key_type, &der) {
+                log_message(
+                    "WARN",
+                    file!(),
+                    line!(),
+                    &format!("Failed to cache CRL: {}", err),
+                );
+            }
+            Ok(crl)
+        }
+    }
+}
+
+/// Converts a certificate from the SEV crate into an OpenSSL certificate.
+fn to_x509(cert: &Certificate) -> SnpResult<X509> {
+    cert.to_der()
+        .ok()
+        .and_then(|der| X509::from_der(&der).ok())
+        .ok_or_else(|| SnpError::InvalidCertificate {
+            detail: "unable to re-encode certificate".to_string(),
+        })
+}
+
+/// A CRL whose signature has been verified against a product line's ARK.
+pub struct VerifiedCrl {
+    crl: X509Crl,
+}
+
+impl VerifiedCrl {
+    /// Loads the CRL of a product line and key type, and verifies that it was
+    /// signed by the given ARK. The ARK must already have been validated.
+    pub fn load(product: Product, key_type: KeyType, ark: &Certificate) -> SnpResult<VerifiedCrl> {
+        let crl = load(product, key_type)?;
+        let ark_key = to_x509(ark)?
+            .public_key()
+            .map_err(|err| invalid(format!("unable to read ARK public key: {err}")))?;
+        match crl.verify(&ark_key) {
+            Ok(true) => Ok(VerifiedCrl { crl }),
+            _ => Err(invalid(format!(
+                "CRL for {} is not signed by its ARK",
+                product.kds_name()
+            ))),
+        }
+    }
+
+    /// Checks that a certificate has not been revoked.
+    ///
+    /// # Arguments
+    /// * `name` - The role of the certificate (e.g. `ASK`), for error messages.
+    /// * `cert` - The certificate to check.
+    pub fn ensure_not_revoked(&self, name: &str, cert: &Certificate) -> SnpResult<()> {
+        let cert = to_x509(cert)?;
+        match self.crl.get_by_cert(&cert) {
+            CrlStatus::NotRevoked => Ok(()),
+            CrlStatus::Revoked(entry) | CrlStatus::RemoveFromCrl(entry) => {
+                let serial = entry
+                    .serial_number()
+                    .to_bn()
+                    .and_then(|serial| serial.to_hex_str().map(|hex| hex.to_string()))
+                    .unwrap_or_default();
+                Err(SnpError::CertificateRevoked {
+                    detail: format!("{name} (serial {serial})"),
+                })
+            }
+        }
+    }
+}
+
+/// Configures where certificate revocation lists are obtained from.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `opts` - An Erlang map of CRL options.
+///
+/// # Expected Input Map Keys:
+/// - `"mode"`: `provider` (default) to fetch CRLs from the certificate provider
+///   and cache them, or `file` to read a single CRL from `"path"`.
+/// - `"path"`: Path of the CRL file (String, DER or PEM encoded).
+///
+/// # Returns
+/// `ok` if the source was configured, or `{error, {invalid_argument, Key}}`.
+///
+/// # Example
+/// ```erlang
+/// ok = dev_snp_nif:configure_crl(#{ mode => file, path => <<"test/crl.der">> }).
+/// ```
+#[rustler::nif]
+pub fn configure_crl<'a>(env: Env<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let map_iter = MapIterator::new(opts).ok_or(rustler::Error::BadArg)?;
+    Ok(encode_unit(env, configure_source(map_iter)))
+}
+
+/// Builds and installs a CRL source from `configure_crl` options.
+fn configure_source(map_iter: MapIterator) -> SnpResult<()> {
+    let mut from_file = false;
+    let mut path: Option<String> = None;
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        let invalid = |_| SnpError::invalid_argument(&key_str, "unexpected value");
+        match key_str.as_str() {
+            "mode" => match value.atom_to_string().map_err(invalid)?.as_str() {
+                "provider" => from_file = false,
+                "file" => from_file = true,
+                other => {
+                    return Err(SnpError::invalid_argument(
+                        "mode",
+                        format!("unknown mode {other}"),
+                    ))
+                }
+            },
+            "path" => path = Some(decode_string(value).map_err(invalid)?),
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+
+    let source = if from_file {
+        let path = path.ok_or_else(|| SnpError::invalid_argument("path", "missing"))?;
+        CrlSource::File(PathBuf::from(path))
+    } else {
+        CrlSource::Provider
+    };
+    let mut current = SOURCE.write().map_err(|_| invalid("CRL source lock poisoned"))?;
+    *current = Some(source);
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use rustler::{Env, MapIterator, NifResult, Term};
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::firmware;
+use crate::helpers::encode_binary;
+use crate::logging::log_message;
+use crate::product::tcb_bytes;
+
+/// Guest fields that can be mixed into a derived key, by name and bit of
+/// the `GUEST_FIELD_SELECT` field of the key request.
+const GUEST_FIELDS: [(&str, u32); 6] = [
+    ("guest_policy", 0),
+    ("image_id", 1),
+    ("family_id", 2),
+    ("measurement", 3),
+    ("guest_svn", 4),
+    ("tcb_version", 5),
+];
+
+/// The root key a derived key is derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RootKey {
+    /// The VCEK, bound to the chip and its TCB.
+    #[default]
+    Vcek,
+    /// The VM root key, bound to the migration agent's guest context.
+    Vmrk,
+}
+
+/// A request for a key derived by the SNP firmware.
+#[derive(Debug, Clone, Default)]
+pub struct KeyRequest {
+    pub root_key: RootKey,
+    /// The `GUEST_FIELD_SELECT` mask (see `GUEST_FIELDS`).
+    pub fields: u64,
+    pub vmpl: u32,
+    pub guest_svn: u32,
+    pub tcb_version: u64,
+}
+
+/// Sets the bit of a named guest field in a `GUEST_FIELD_SELECT` mask.
+pub fn select_field(fields: &mut u64, name: &str) -> SnpResult<()> {
+    let (_, bit) = GUEST_FIELDS
+        .iter()
+        .find(|(field, _)| *field == name)
+        .ok_or_else(|| SnpError::invalid_argument(&format!("fields.{name}"), "unknown guest field"))?;
+    *fields |= 1 << bit;
+    Ok(())
+}
+
+/// Decodes a key request passed from Erlang as a map, along with its
+/// `tcb_version`, if given (see `resolve_tcb_version`).
+pub fn parse_key_request(value: Term) -> SnpResult<(KeyRequest, Option<u64>)> {
+    let map_iter = MapIterator::new(value)
+        .ok_or_else(|| SnpError::invalid_argument("opts", "expected a map"))?;
+    let mut request = KeyRequest::default();
+    let mut tcb_version = None;
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        let invalid = |reason: &str| SnpError::invalid_argument(&key_str, reason);
+        match key_str.as_str() {
+            "root_key" => {
+                request.root_key = match value.atom_to_string().as_deref() {
+                    Ok("vcek") => RootKey::Vcek,
+                    Ok("vmrk") => RootKey::Vmrk,
+                    _ => return Err(invalid("expected vcek or vmrk")),
+                }
+            }
+            "fields" => {
+                let names: Vec<Term> = value
+                    .decode()
+                    .map_err(|_| invalid("expected a list of atoms"))?;
+                request.fields = 0;
+                for name in names {
+                    let name = name
+                        .atom_to_string()
+                        .map_err(|_| invalid("expected a list of atoms"))?;
+                    select_field(&mut request.fields, &name)?;
+                }
+            }
+            "vmpl" => request.vmpl = value.decode().map_err(|_| invalid("expected an integer"))?,
+            "guest_svn" => {
+                request.guest_svn = value.decode().map_err(|_| invalid("expected an integer"))?
+            }
+            "tcb_version" => {
+                tcb_version = Some(value.decode().map_err(|_| invalid("expected an integer"))?)
+            }
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+    if request.vmpl > 3 {
+        return Err(SnpError::invalid_argument("vmpl", "expected a VMPL from 0 to 3"));
+    }
+    Ok((request, tcb_version))
+}
+
+/// Sets the TCB version of a key request: the given one, or else, if the
+/// request binds the TCB version, the platform's current TCB as read from a
+/// fresh attestation report. Binding TCB version 0 instead would yield a key
+/// that firmware of any TCB can re-derive, defeating the binding.
+pub fn resolve_tcb_version(request: &mut KeyRequest, tcb_version: Option<u64>) -> SnpResult<()> {
+    let mut tcb_field = 0;
+    select_field(&mut tcb_field, "tcb_version")?;
+    request.tcb_version = match tcb_version {
+        Some(tcb_version) => tcb_version,
+        None if request.fields & tcb_field != 0 => {
+            let report = firmware::open()?.get_report([0u8; 64], request.vmpl)?;
+            u64::from_le_bytes(tcb_bytes(&report.current_tcb))
+        }
+        None => 0,
+    };
+    Ok(())
+}
+
+/// Requests a derived key from the SNP guest firmware.
+pub fn derive(request: &KeyRequest) -> SnpResult<[u8; 32]> {
+    firmware::open()?.get_derived_key(request)
+}
+
+/// Derives a key from the chip's root key, bound to the selected fields of
+/// the guest's identity. The same request made by a guest with the same
+/// values of those fields, on the same chip, yields the same key.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `opts` - An Erlang map describing the key request.
+///
+/// # Expected Input Map Keys (all optional):
+/// - `"root_key"`: `vcek` (default) or `vmrk`.
+/// - `"fields"`: The guest fields mixed into the key, from `guest_policy`,
+///   `image_id`, `family_id`, `measurement`, `guest_svn` and `tcb_version`.
+///   Defaults to none.
+/// - `"vmpl"`: The VMPL mixed into the key (0 to 3, default 0). It must be
+///   at least the VMPL of the caller.
+/// - `"guest_svn"`: The guest SVN mixed into the key, if selected (default 0).
+/// - `"tcb_version"`: The TCB version mixed into the key, if selected, as the
+///   little-endian integer of its raw encoding. It must be no newer than the
+///   current TCB, which is the default.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the 32-byte key, or
+/// `{error, {invalid_argument | firmware_error, Detail}}`.
+///
+/// # Example
+/// ```erlang
+/// {ok, Key} = dev_snp_nif:derive_key(#{ fields => [measurement, guest_policy], vmpl => 1 }).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn derive_key<'a>(env: Env<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let result = parse_key_request(opts)
+        .and_then(|(mut request, tcb_version)| {
+            resolve_tcb_version(&mut request, tcb_version)?;
+            derive(&request)
+        })
+        .and_then(|key| encode_binary(env, &key));
+    Ok(encode_result(env, result))
+}
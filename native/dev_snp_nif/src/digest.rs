@@ -1,25 +1,70 @@
-use rustler::{Encoder, Env, MapIterator, NifResult, Term};
-use rustler::types::atom::{self, ok};
+use rustler::{Env, MapIterator, NifResult, Term};
 use sev::measurement::snp::{snp_calc_launch_digest, SnpMeasurementArgs};
 use sev::measurement::vcpu_types::CpuType;
 use sev::measurement::vmsa::{GuestFeatures, VMMType};
+use crate::async_nif;
+use crate::error::{encode_error, encode_result, SnpError, SnpResult};
+use crate::helpers::{decode_string, encode_binary};
 use crate::logging::log_message;
+use crate::ovmf;
 use std::path::PathBuf;
-use bincode;
+
+rustler::atoms! {
+    measurement,
+    measurement_hex,
+    components,
+    firmware,
+    kernel,
+    initrd,
+    append,
+}
 
 /// Struct to hold launch digest arguments passed from Erlang
 #[derive(Debug)]
 struct LaunchDigestArgs {
-    vcpus: u32,
-    vcpu_type: u8,
-    vmm_type: u8,
-    guest_features: u64,
-    ovmf_hash_str: String,
+    vcpus: Option<u32>,
+    vcpu_type: Option<u8>,
+    vmm_type: Option<u8>,
+    guest_features: Option<u64>,
+    ovmf_hash_str: Option<String>,
+    ovmf_file: Option<String>,
     kernel_hash: String,
     initrd_hash: String,
     append_hash: String,
 }
 
+/// A computed launch digest and the component hashes that went into it.
+#[derive(Debug)]
+struct LaunchDigest {
+    /// The 48-byte measurement, as found in the attestation report.
+    measurement: Vec<u8>,
+    /// Hex-encoded OVMF, kernel, initrd and command line hashes.
+    firmware: String,
+    kernel: String,
+    initrd: String,
+    append: String,
+}
+
+impl LaunchDigest {
+    /// Encodes the digest as an Erlang map.
+    fn encode<'a>(&self, env: Env<'a>) -> SnpResult<Term<'a>> {
+        let map_err = |_| SnpError::Serialization {
+            detail: "Failed to build launch digest map".to_string(),
+        };
+        let components_map = Term::map_new(env)
+            .map_put(firmware(), &self.firmware)
+            .and_then(|map| map.map_put(kernel(), &self.kernel))
+            .and_then(|map| map.map_put(initrd(), &self.initrd))
+            .and_then(|map| map.map_put(append(), &self.append))
+            .map_err(map_err)?;
+        Term::map_new(env)
+            .map_put(measurement(), encode_binary(env, &self.measurement)?)
+            .and_then(|map| map.map_put(measurement_hex(), hex::encode(&self.measurement)))
+            .and_then(|map| map.map_put(components(), components_map))
+            .map_err(map_err)
+    }
+}
+
 /// Computes the launch digest using the input arguments provided as an Erlang map.
 ///
 /// # Arguments
@@ -27,122 +72,239 @@ struct LaunchDigestArgs {
 /// * `input_map` - An Erlang map containing the input parameters required for the calculation.
 ///
 /// # Returns
-/// A tuple containing an `ok` atom and the calculated and serialized launch digest.
+/// A tuple containing an `ok` atom and a map with the following keys:
+/// - `measurement`: The 48-byte launch measurement (binary).
+/// - `measurement_hex`: The hex encoding of the measurement (binary).
+/// - `components`: The hex-encoded hashes that were measured, under the
+///   `firmware`, `kernel`, `initrd` and `append` keys.
+///
 /// If the input is invalid or an error occurs during calculation, an error is returned.
 ///
 /// # Expected Input Map Keys:
-/// - `"vcpus"`: Number of virtual CPUs (u32).
-/// - `"vcpu_type"`: Type of the virtual CPU (u8).
-/// - `"vmm_type"`: Type of the Virtual Machine Monitor (u8).
+/// - `"vcpus"`: Number of virtual CPUs (u32, at least 1).
+/// - `"vcpu_type"`: Type of the virtual CPU, as numbered by `CpuType` (u8).
+/// - `"vmm_type"`: Type of the Virtual Machine Monitor, as numbered by `VMMType` (u8).
 /// - `"guest_features"`: Features of the guest (u64).
-/// - `"ovmf_hash_str"`: Hash of the OVMF firmware (String).
-/// - `"kernel_hash"`: Hash of the kernel (String).
-/// - `"initrd_hash"`: Hash of the initrd (String).
-/// - `"append_hash"`: Hash of the kernel command line arguments (String).
+/// - `"firmware"`: Hash of the OVMF firmware (String).
+/// - `"ovmf_file"`: Path of the OVMF image (String, optional).
+/// - `"kernel"`: Hash of the kernel (String).
+/// - `"initrd"`: Hash of the initrd (String).
+/// - `"append"`: Hash of the kernel command line arguments (String).
+///
+/// At least one of `"firmware"` and `"ovmf_file"` must be given, and if both
+/// are, `"firmware"` must be the hash of `"ovmf_file"`. Without `"ovmf_file"`,
+/// the image registered for the `"firmware"` hash (see `register_ovmf`) is
+/// used, and `{error, {invalid_argument, <<"firmware">>}}` is returned if
+/// none is registered: the digest cannot be computed from the hash alone.
 ///
 /// # Example
 /// ```erlang
-/// {ok, LaunchDigest} = dev_snp_nif:compute_launch_digest(InputMap).
+/// {ok, #{ measurement := Measurement }} = dev_snp_nif:compute_launch_digest(InputMap).
 /// ```
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyCpu")]
 pub fn compute_launch_digest<'a>(env: Env<'a>, input_map: Term<'a>) -> NifResult<Term<'a>> {
-    //log_message("INFO", file!(), line!(), "Starting launch digest calculation...");
+    log_message("DEBUG", file!(), line!(), "Starting launch digest calculation...");
 
-    // Step 1: Validate that the input is a map.
-    if !input_map.is_map() {
-        log_message("ERROR", file!(), line!(), "Provided input is not a map.");
-        return Err(rustler::Error::BadArg);
-    }
+    // Steps 1-2: Validate and parse the input map.
+    let args = match decode_args(input_map)? {
+        Ok(args) => args,
+        Err(err) => return Ok(encode_error(env, &err)),
+    };
 
-    // Step 2: Helper function to decode string values from the map.
-    fn decode_string(value: Term) -> NifResult<String> {
-        match value.get_type() {
-            rustler::TermType::List => {
-                let list: Vec<u8> = value.decode()?;
-                String::from_utf8(list).map_err(|_| rustler::Error::BadArg)
-            }
-            _ => value.decode(),
+    log_message("DEBUG", file!(), line!(), &format!("Parsed arguments: {:?}", args));
+
+    // Steps 3-5: Compute and encode the digest.
+    let result = launch_digest(&args).and_then(|digest| digest.encode(env));
+    Ok(encode_result(env, result))
+}
+
+/// Computes the launch digest without blocking the caller, which is sent the
+/// result once the digest has been computed.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `input_map` - An Erlang map of parameters, as for `compute_launch_digest/1`.
+///
+/// # Returns
+/// `{ok, Ref}`, `{error, {invalid_argument, Detail}}` if the parameters are
+/// invalid, or `{error, {busy, Detail}}` if too many async calls are already
+/// running. The result of `compute_launch_digest/1` is later sent to the
+/// caller as `{snp_result, Ref, Result}`.
+///
+/// # Example
+/// ```erlang
+/// {ok, Ref} = dev_snp_nif:compute_launch_digest_async(InputMap),
+/// receive {snp_result, Ref, {ok, #{ measurement := Measurement }}} -> ok end.
+/// ```
+#[rustler::nif]
+pub fn compute_launch_digest_async<'a>(env: Env<'a>, input_map: Term<'a>) -> NifResult<Term<'a>> {
+    let args = match decode_args(input_map)? {
+        Ok(args) => args,
+        Err(err) => return Ok(encode_error(env, &err)),
+    };
+    async_nif::spawn(env, move |env| {
+        let result = launch_digest(&args).and_then(|digest| digest.encode(env));
+        encode_result(env, result)
+    })
+}
+
+/// Decodes the input map of `compute_launch_digest/1`. Raises `badarg` if the
+/// input is not a map.
+fn decode_args(input_map: Term) -> NifResult<SnpResult<LaunchDigestArgs>> {
+    match MapIterator::new(input_map) {
+        Some(map_iter) => Ok(parse_args(map_iter)),
+        None => {
+            log_message("ERROR", file!(), line!(), "Provided input is not a map.");
+            Err(rustler::Error::BadArg)
         }
     }
+}
 
-    // Step 3: Parse input map into LaunchDigestArgs.
+/// Parses the `compute_launch_digest` input map into `LaunchDigestArgs`.
+fn parse_args(map_iter: MapIterator) -> SnpResult<LaunchDigestArgs> {
     let mut args = LaunchDigestArgs {
-        vcpus: 0,
-        vcpu_type: 0,
-        vmm_type: 0,
-        guest_features: 0,
-        ovmf_hash_str: String::new(),
+        vcpus: None,
+        vcpu_type: None,
+        vmm_type: None,
+        guest_features: None,
+        ovmf_hash_str: None,
+        ovmf_file: None,
         kernel_hash: String::new(),
         initrd_hash: String::new(),
         append_hash: String::new(),
     };
 
-    let map_iter = MapIterator::new(input_map).unwrap();
     for (key, value) in map_iter {
-        let key_str = key.atom_to_string()?.to_string();
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("input_map", "keys must be atoms"))?;
+        let invalid = |_| SnpError::invalid_argument(&key_str, "unexpected value");
         match key_str.as_str() {
-            "vcpus" => args.vcpus = value.decode()?,
-            "vcpu_type" => args.vcpu_type = value.decode()?,
-            "vmm_type" => args.vmm_type = value.decode()?,
-            "guest_features" => args.guest_features = value.decode()?,
-            "firmware" => args.ovmf_hash_str = decode_string(value)?,
-            "kernel" => args.kernel_hash = decode_string(value)?,
-            "initrd" => args.initrd_hash = decode_string(value)?,
-            "append" => args.append_hash = decode_string(value)?,
+            "vcpus" => args.vcpus = Some(value.decode().map_err(invalid)?),
+            "vcpu_type" => args.vcpu_type = Some(value.decode().map_err(invalid)?),
+            "vmm_type" => args.vmm_type = Some(value.decode().map_err(invalid)?),
+            "guest_features" => args.guest_features = Some(value.decode().map_err(invalid)?),
+            "firmware" => args.ovmf_hash_str = Some(decode_string(value).map_err(invalid)?),
+            "ovmf_file" => args.ovmf_file = Some(decode_string(value).map_err(invalid)?),
+            "kernel" => args.kernel_hash = decode_string(value).map_err(invalid)?,
+            "initrd" => args.initrd_hash = decode_string(value).map_err(invalid)?,
+            "append" => args.append_hash = decode_string(value).map_err(invalid)?,
             _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
         }
     }
 
-    //log_message("INFO", file!(), line!(), &format!("Parsed arguments: {:?}", args));
+    Ok(args)
+}
+
+/// Returns a required argument, or an error naming it if it was not supplied.
+fn required<T: Copy>(field: &str, value: Option<T>) -> SnpResult<T> {
+    value.ok_or_else(|| SnpError::invalid_argument(field, "missing"))
+}
+
+/// Decodes a hex-encoded SHA-256 hash, naming the field on failure.
+fn decode_hash(field: &str, hash: &str) -> SnpResult<[u8; 32]> {
+    let bad_hex = || SnpError::BadHex {
+        field: field.to_string(),
+    };
+    hex::decode(hash)
+        .map_err(|_| bad_hex())?
+        .try_into()
+        .map_err(|_| bad_hex())
+}
+
+/// Selects the OVMF image to measure: the caller's file if given (checked
+/// against the firmware hash, if that is given too), otherwise the image
+/// registered for the firmware hash. The image itself is always needed, as
+/// its metadata decides which pages the launch digest covers.
+fn ovmf_file(args: &LaunchDigestArgs) -> SnpResult<PathBuf> {
+    match (&args.ovmf_file, &args.ovmf_hash_str) {
+        (Some(file), hash) => {
+            let path = PathBuf::from(file);
+            if !path.is_file() {
+                return Err(SnpError::invalid_argument("ovmf_file", "not found"));
+            }
+            // A hash given alongside the file must be the file's, or the
+            // digest would silently mix two firmware images.
+            if let Some(hash) = hash {
+                if !ovmf::hash_file(&path)?.eq_ignore_ascii_case(hash) {
+                    return Err(SnpError::invalid_argument(
+                        "firmware",
+                        "does not match the hash of ovmf_file",
+                    ));
+                }
+            }
+            Ok(path)
+        }
+        (None, Some(hash)) => ovmf::lookup(hash).ok_or_else(|| {
+            SnpError::invalid_argument("firmware", "no OVMF image registered for hash")
+        }),
+        (None, None) => Err(SnpError::invalid_argument("firmware", "missing")),
+    }
+}
 
-    // Step 4: Prepare SnpMeasurementArgs for digest calculation.
-    let ovmf_file = "test/OVMF-1.55.fd".to_owned();
+/// Computes the launch digest described by `args`.
+fn launch_digest(args: &LaunchDigestArgs) -> SnpResult<LaunchDigest> {
+    // Step 3: Validate the VM shape and prepare SnpMeasurementArgs.
+    let vcpus = required("vcpus", args.vcpus)?;
+    if vcpus == 0 {
+        return Err(SnpError::invalid_argument("vcpus", "must be at least 1"));
+    }
+    let vcpu_type = required("vcpu_type", args.vcpu_type)?;
+    let vcpu_type = CpuType::try_from(vcpu_type).map_err(|_| {
+        SnpError::invalid_argument("vcpu_type", format!("unknown vCPU type {vcpu_type}"))
+    })?;
+    let vmm_type = required("vmm_type", args.vmm_type)?;
+    let vmm_type = VMMType::try_from(vmm_type).map_err(|_| {
+        SnpError::invalid_argument("vmm_type", format!("unknown VMM type {vmm_type}"))
+    })?;
+    let guest_features = required("guest_features", args.guest_features)?;
+    let ovmf_file = ovmf_file(args)?;
+    let ovmf_hash = match &args.ovmf_hash_str {
+        Some(hash) => hash.to_ascii_lowercase(),
+        None => ovmf::hash_file(&ovmf_file)?,
+    };
+    let kernel_hash = decode_hash("kernel", &args.kernel_hash)?;
+    let initrd_hash = decode_hash("initrd", &args.initrd_hash)?;
+    let append_hash = decode_hash("append", &args.append_hash)?;
     let measurement_args = SnpMeasurementArgs {
-        ovmf_file: Some(PathBuf::from(ovmf_file)),
+        ovmf_file: Some(ovmf_file),
         kernel_file: None,
         initrd_file: None,
         append: None,
-        // vcpus: args.vcpus,
-        // vcpu_type: CpuType::try_from(args.vcpu_type).unwrap(),
-        // vmm_type: Some(VMMType::try_from(args.vmm_type).unwrap()),
-        // guest_features: GuestFeatures(args.guest_features),
-		vcpus: 32,
-        vcpu_type: CpuType::EpycV4,
-        vmm_type: Some(VMMType::QEMU),
-        guest_features: GuestFeatures(0x1),
-        ovmf_hash_str: Some(args.ovmf_hash_str.as_str()),
-        kernel_hash: Some(hex::decode(args.kernel_hash).unwrap().try_into().unwrap()),
-        initrd_hash: Some(hex::decode(args.initrd_hash).unwrap().try_into().unwrap()),
-        append_hash: Some(hex::decode(args.append_hash).unwrap().try_into().unwrap()),
+        vcpus,
+        vcpu_type,
+        vmm_type: Some(vmm_type),
+        guest_features: GuestFeatures(guest_features),
+        ovmf_hash_str: Some(ovmf_hash.as_str()),
+        kernel_hash: Some(kernel_hash),
+        initrd_hash: Some(initrd_hash),
+        append_hash: Some(append_hash),
     };
 
-    // Step 5: Compute the launch digest.
-    let digest = match snp_calc_launch_digest(measurement_args) {
-        Ok(digest) => digest,
-        Err(err) => {
-            let msg = format!("Failed to compute launch digest: {:?}", err);
-            log_message("ERROR", file!(), line!(), &msg);
-            return Ok((atom::error(), msg).encode(env));
+    // Step 4: Compute the launch digest.
+    let digest = snp_calc_launch_digest(measurement_args).map_err(|err| {
+        SnpError::Measurement {
+            detail: format!("{:?}", err),
         }
-    };
+    })?;
 
-    // Step 6: Serialize the digest.
-    let serialized_digest = match bincode::serialize(&digest) {
-        Ok(serialized) => serialized,
-        Err(err) => {
-            let msg = format!("Failed to serialize launch digest: {:?}", err);
-            log_message("ERROR", file!(), line!(), &msg);
-            return Ok((atom::error(), msg).encode(env));
-        }
-    };
+    // Step 5: Collect the measurement and its components.
+    let measurement = hex::decode(digest.get_hex_ld()).map_err(|err| SnpError::Serialization {
+        detail: format!("Failed to decode launch digest: {:?}", err),
+    })?;
 
-    //log_message(
-    //    "INFO",
-    //    file!(),
-    //    line!(),
-    //    "Launch digest successfully computed and serialized.",
-    //);
+    log_message(
+        "DEBUG",
+        file!(),
+        line!(),
+        "Launch digest successfully computed and serialized.",
+    );
 
-    // Step 7: Return the calculated and serialized digest.
-    Ok((ok(), serialized_digest).encode(env))
+    Ok(LaunchDigest {
+        measurement,
+        firmware: ovmf_hash,
+        kernel: hex::encode(kernel_hash),
+        initrd: hex::encode(initrd_hash),
+        append: hex::encode(append_hash),
+    })
 }
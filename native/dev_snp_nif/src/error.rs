@@ -0,0 +1,209 @@
+use rustler::{Encoder, Env, Term};
+use rustler::types::atom::{self, ok};
+use snafu::Snafu;
+use crate::logging::log_message;
+
+rustler::atoms! {
+    kds_unreachable,
+    invalid_certificate,
+    cert_chain_invalid,
+    signature_invalid,
+    bad_hex,
+    invalid_report,
+    invalid_argument,
+    firmware_error,
+    measurement_failed,
+    cache_error,
+    serialization_failed,
+    tcb_below_minimum,
+    crl_invalid,
+    certificate_revoked,
+    untrusted_root,
+    unseal_failed,
+    seal_failed,
+    replayed_report,
+    internal_error,
+    busy,
+}
+
+/// Errors returned by the NIFs in this crate. Each variant maps to a stable
+/// Erlang atom, so callers can match on `{error, {Kind, Detail}}` without
+/// parsing messages.
+#[derive(Debug, Clone, Snafu)]
+pub enum SnpError {
+    /// The certificate provider (KDS, mirror, or local-only) could not supply a certificate.
+    #[snafu(display("KDS unreachable: {detail}"))]
+    KdsUnreachable { detail: String },
+
+    /// A certificate could not be parsed.
+    #[snafu(display("Invalid certificate: {detail}"))]
+    InvalidCertificate { detail: String },
+
+    /// The ARK/ASK/VCEK chain did not verify.
+    #[snafu(display("Certificate chain verification failed: {detail}"))]
+    CertChainInvalid { detail: String },
+
+    /// The report's signature did not verify against the VCEK.
+    #[snafu(display("Report signature verification failed: {detail}"))]
+    SignatureInvalid { detail: String },
+
+    /// A hex-encoded input was not valid hex or had the wrong length.
+    #[snafu(display("Bad hex in `{field}`"))]
+    BadHex { field: String },
+
+    /// A report field was missing or malformed.
+    #[snafu(display("Invalid report field `{field}`: {reason}"))]
+    InvalidReport { field: String, reason: String },
+
+    /// A NIF argument (or a key of an argument map) was invalid.
+    #[snafu(display("Invalid argument `{field}`: {reason}"))]
+    InvalidArgument { field: String, reason: String },
+
+    /// The SEV guest firmware rejected a request or could not be opened.
+    #[snafu(display("Firmware error: {detail}"))]
+    Firmware { detail: String },
+
+    /// The launch measurement could not be computed.
+    #[snafu(display("Failed to compute launch digest: {detail}"))]
+    Measurement { detail: String },
+
+    /// The on-disk certificate cache could not be read or written.
+    #[snafu(display("Certificate cache error: {detail}"))]
+    Cache { detail: String },
+
+    /// A value could not be serialized for return to Erlang.
+    #[snafu(display("Serialization failed: {detail}"))]
+    Serialization { detail: String },
+
+    /// A TCB component of the report is below the required minimum.
+    #[snafu(display("TCB component `{field}` below minimum: {reason}"))]
+    TcbBelowMinimum { field: String, reason: String },
+
+    /// The CRL could not be obtained, parsed or verified against the ARK.
+    #[snafu(display("Invalid CRL: {detail}"))]
+    CrlInvalid { detail: String },
+
+    /// A certificate in the chain has been revoked by AMD.
+    #[snafu(display("Certificate revoked: {detail}"))]
+    CertificateRevoked { detail: String },
+
+    /// The ARK of a certificate chain is not one of the pinned AMD roots.
+    #[snafu(display("Untrusted root key: {detail}"))]
+    UntrustedRoot { detail: String },
+
+    /// A sealed blob could not be decrypted: it was modified, or sealed to a
+    /// different guest identity.
+    #[snafu(display("Unseal failed: {detail}"))]
+    Unseal { detail: String },
+
+    /// Data could not be sealed.
+    #[snafu(display("Seal failed: {detail}"))]
+    Seal { detail: String },
+
+    /// The report has already been verified with replay protection.
+    #[snafu(display("Replayed report: {detail}"))]
+    Replayed { detail: String },
+
+    /// An unexpected failure inside the NIF, such as a panicked worker thread.
+    #[snafu(display("Internal error: {detail}"))]
+    Internal { detail: String },
+
+    /// Too many async calls are already running.
+    #[snafu(display("Busy: {detail}"))]
+    Busy { detail: String },
+}
+
+/// Convenience alias for results carrying an `SnpError`.
+pub type SnpResult<T> = Result<T, SnpError>;
+
+impl SnpError {
+    /// Shorthand for an `InvalidArgument` error.
+    pub fn invalid_argument(field: &str, reason: impl Into<String>) -> Self {
+        SnpError::InvalidArgument {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    /// The atom identifying the kind of error.
+    pub fn kind(&self) -> rustler::Atom {
+        match self {
+            SnpError::KdsUnreachable { .. } => kds_unreachable(),
+            SnpError::InvalidCertificate { .. } => invalid_certificate(),
+            SnpError::CertChainInvalid { .. } => cert_chain_invalid(),
+            SnpError::SignatureInvalid { .. } => signature_invalid(),
+            SnpError::BadHex { .. } => bad_hex(),
+            SnpError::InvalidReport { .. } => invalid_report(),
+            SnpError::InvalidArgument { .. } => invalid_argument(),
+            SnpError::Firmware { .. } => firmware_error(),
+            SnpError::Measurement { .. } => measurement_failed(),
+            SnpError::Cache { .. } => cache_error(),
+            SnpError::Serialization { .. } => serialization_failed(),
+            SnpError::TcbBelowMinimum { .. } => tcb_below_minimum(),
+            SnpError::CrlInvalid { .. } => crl_invalid(),
+            SnpError::CertificateRevoked { .. } => certificate_revoked(),
+            SnpError::UntrustedRoot { .. } => untrusted_root(),
+            SnpError::Unseal { .. } => unseal_failed(),
+            SnpError::Seal { .. } => seal_failed(),
+            SnpError::Replayed { .. } => replayed_report(),
+            SnpError::Internal { .. } => internal_error(),
+            SnpError::Busy { .. } => busy(),
+        }
+    }
+
+    /// The detail returned to Erlang: the offending field for input errors,
+    /// and a description of the failure otherwise.
+    pub fn detail(&self) -> String {
+        match self {
+            SnpError::BadHex { field }
+            | SnpError::InvalidReport { field, .. }
+            | SnpError::InvalidArgument { field, .. }
+            | SnpError::TcbBelowMinimum { field, .. } => field.clone(),
+            SnpError::KdsUnreachable { detail }
+            | SnpError::InvalidCertificate { detail }
+            | SnpError::CertChainInvalid { detail }
+            | SnpError::SignatureInvalid { detail }
+            | SnpError::Firmware { detail }
+            | SnpError::Measurement { detail }
+            | SnpError::Cache { detail }
+            | SnpError::Serialization { detail }
+            | SnpError::CrlInvalid { detail }
+            | SnpError::CertificateRevoked { detail }
+            | SnpError::UntrustedRoot { detail }
+            | SnpError::Unseal { detail }
+            | SnpError::Seal { detail }
+            | SnpError::Replayed { detail }
+            | SnpError::Internal { detail }
+            | SnpError::Busy { detail } => detail.clone(),
+        }
+    }
+}
+
+impl Encoder for SnpError {
+    /// Encodes the error as `{Kind, Detail}`.
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        (self.kind(), self.detail()).encode(env)
+    }
+}
+
+/// Encodes a result as `{ok, Value}` or `{error, {Kind, Detail}}`, logging errors.
+pub fn encode_result<'a, T: Encoder>(env: Env<'a>, result: SnpResult<T>) -> Term<'a> {
+    match result {
+        Ok(value) => (ok(), value).encode(env),
+        Err(err) => encode_error(env, &err),
+    }
+}
+
+/// Encodes an error as `{error, {Kind, Detail}}`, logging it.
+pub fn encode_error<'a>(env: Env<'a>, err: &SnpError) -> Term<'a> {
+    log_message("ERROR", file!(), line!(), &err.to_string());
+    (atom::error(), err).encode(env)
+}
+
+/// Encodes a result without a value as `ok` or `{error, {Kind, Detail}}`.
+pub fn encode_unit<'a>(env: Env<'a>, result: SnpResult<()>) -> Term<'a> {
+    match result {
+        Ok(()) => ok().encode(env),
+        Err(err) => encode_error(env, &err),
+    }
+}
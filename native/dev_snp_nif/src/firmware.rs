@@ -0,0 +1,102 @@
+use sev::firmware::guest::{AttestationReport, DerivedKey, Firmware, GuestFieldSelect};
+use sev::firmware::host::CertTableEntry;
+use crate::derived_key::{KeyRequest, RootKey};
+use crate::error::{SnpError, SnpResult};
+
+/// The guest device through which the SNP firmware is reached.
+pub const DEVICE_PATH: &str = "/dev/sev-guest";
+
+/// The requests the NIFs make of the SNP guest firmware. Implemented by
+/// `/dev/sev-guest` and, with the `mock-firmware` feature, by a software
+/// emulator for tests (see `mock_firmware`).
+pub trait GuestFirmware {
+    /// The name of the backend, as reported by `probe_snp_support/0`.
+    fn backend(&self) -> &'static str;
+
+    /// Requests an attestation report.
+    fn get_report(&mut self, unique_data: [u8; 64], vmpl: u32) -> SnpResult<AttestationReport>;
+
+    /// Requests an attestation report and the host's certificate table.
+    fn get_ext_report(
+        &mut self,
+        unique_data: [u8; 64],
+        vmpl: u32,
+    ) -> SnpResult<(AttestationReport, Vec<CertTableEntry>)>;
+
+    /// Requests a key derived from the chip's root key.
+    fn get_derived_key(&mut self, request: &KeyRequest) -> SnpResult<[u8; 32]>;
+}
+
+/// The guest firmware of an SNP guest, through `/dev/sev-guest`.
+struct SevFirmware(Firmware);
+
+impl GuestFirmware for SevFirmware {
+    fn backend(&self) -> &'static str {
+        "sev_guest"
+    }
+
+    fn get_report(&mut self, unique_data: [u8; 64], vmpl: u32) -> SnpResult<AttestationReport> {
+        self.0
+            .get_report(None, Some(unique_data), Some(vmpl))
+            .map_err(|err| SnpError::Firmware {
+                detail: format!("Failed to generate attestation report: {:?}", err),
+            })
+    }
+
+    fn get_ext_report(
+        &mut self,
+        unique_data: [u8; 64],
+        vmpl: u32,
+    ) -> SnpResult<(AttestationReport, Vec<CertTableEntry>)> {
+        self.0
+            .get_ext_report(None, Some(unique_data), Some(vmpl))
+            .map(|(report, table)| (report, table.unwrap_or_default()))
+            .map_err(|err| SnpError::Firmware {
+                detail: format!("Failed to generate extended attestation report: {:?}", err),
+            })
+    }
+
+    fn get_derived_key(&mut self, request: &KeyRequest) -> SnpResult<[u8; 32]> {
+        let key_request = DerivedKey::new(
+            request.root_key == RootKey::Vmrk,
+            GuestFieldSelect(request.fields),
+            request.vmpl,
+            request.guest_svn,
+            request.tcb_version,
+        );
+        self.0
+            .get_derived_key(None, key_request)
+            .map_err(|err| SnpError::Firmware {
+                detail: format!("Failed to derive key: {:?}", err),
+            })
+    }
+}
+
+/// Opens the guest firmware.
+///
+/// # Errors
+/// Returns `Firmware` if `/dev/sev-guest` cannot be opened, i.e. when not
+/// running in an SNP guest.
+#[cfg(not(feature = "mock-firmware"))]
+pub fn open() -> SnpResult<Box<dyn GuestFirmware>> {
+    let firmware = Firmware::open().map_err(|err| SnpError::Firmware {
+        detail: format!("Failed to open firmware: {:?}", err),
+    })?;
+    Ok(Box::new(SevFirmware(firmware)))
+}
+
+/// Opens the guest firmware: `/dev/sev-guest` if present, and the software
+/// emulator only if the device does not exist. Opening the emulator
+/// reconfigures certificate lookup for the whole process (see
+/// `mock_firmware::install`), so any other failure to open the device (e.g.
+/// permissions, or the device being busy) is returned as a `Firmware` error.
+#[cfg(feature = "mock-firmware")]
+pub fn open() -> SnpResult<Box<dyn GuestFirmware>> {
+    match Firmware::open() {
+        Ok(firmware) => Ok(Box::new(SevFirmware(firmware))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => crate::mock_firmware::open(),
+        Err(err) => Err(SnpError::Firmware {
+            detail: format!("Failed to open firmware: {:?}", err),
+        }),
+    }
+}
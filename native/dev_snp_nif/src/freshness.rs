@@ -0,0 +1,299 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use openssl::rand::rand_bytes;
+use rustler::{Binary, Env, MapIterator, NifResult, Term};
+use sev::firmware::guest::AttestationReport;
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::helpers::encode_binary;
+use crate::logging::log_message;
+use crate::report_data::{self, Binding};
+
+/// Size of an issued challenge nonce.
+const NONCE_SIZE: usize = 32;
+/// How long an issued nonce can be consumed if no `ttl` is given.
+const DEFAULT_NONCE_TTL: Duration = Duration::from_secs(300);
+/// The most reports remembered for replay protection. Beyond it, the oldest
+/// are forgotten first, whether or not they have expired.
+const MAX_SEEN_REPORTS: usize = 65536;
+/// The most nonces outstanding at once. Beyond it, the oldest are dropped.
+const MAX_ISSUED_NONCES: usize = 65536;
+
+/// A bounded set of keys that each expire after their own TTL.
+struct ExpiringSet<K> {
+    capacity: usize,
+    /// The expiry of each key.
+    entries: HashMap<K, Instant>,
+    /// Keys in insertion order, with the expiry they were inserted with.
+    /// Entries whose key has since been removed or reinserted are stale, and
+    /// skipped when popped.
+    order: VecDeque<(K, Instant)>,
+}
+
+impl<K: Copy + Eq + Hash> ExpiringSet<K> {
+    fn new(capacity: usize) -> Self {
+        ExpiringSet {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Whether `key` is present and unexpired.
+    fn contains(&self, key: &K, now: Instant) -> bool {
+        self.entries.get(key).is_some_and(|expiry| *expiry > now)
+    }
+
+    /// Removes the oldest entry, if it is still current.
+    fn pop_oldest(&mut self) {
+        if let Some((key, expiry)) = self.order.pop_front() {
+            if self.entries.get(&key) == Some(&expiry) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Removes expired entries from the front, and the oldest entries while
+    /// the set is full.
+    fn evict(&mut self, now: Instant) {
+        while let Some((_, expiry)) = self.order.front() {
+            if *expiry > now && self.entries.len() < self.capacity {
+                break;
+            }
+            self.pop_oldest();
+        }
+        // Drop stale entries left behind by removals.
+        if self.order.len() > 2 * self.capacity {
+            let entries = &self.entries;
+            self.order.retain(|(key, expiry)| entries.get(key) == Some(expiry));
+        }
+    }
+
+    /// Inserts `key` until `now + ttl`. Returns `false`, leaving the set
+    /// unchanged, if it is already present and unexpired.
+    fn insert(&mut self, key: K, ttl: Duration, now: Instant) -> bool {
+        if self.contains(&key, now) {
+            return false;
+        }
+        self.evict(now);
+        let expiry = now + ttl;
+        self.entries.insert(key, expiry);
+        self.order.push_back((key, expiry));
+        true
+    }
+
+    /// Removes `key`. Returns whether it was present and unexpired.
+    fn remove(&mut self, key: &K, now: Instant) -> bool {
+        self.entries.remove(key).is_some_and(|expiry| expiry > now)
+    }
+}
+
+/// The `report_id` and `report_data` of each report recently accepted with
+/// replay protection. `None` until the first is recorded.
+static SEEN_REPORTS: Mutex<Option<ExpiringSet<[u8; 96]>>> = Mutex::new(None);
+/// The challenge nonces issued and not yet consumed.
+static ISSUED_NONCES: Mutex<Option<ExpiringSet<[u8; NONCE_SIZE]>>> = Mutex::new(None);
+
+/// The error returned if a thread panicked while holding a cache lock.
+fn poisoned() -> SnpError {
+    SnpError::Cache {
+        detail: "Freshness cache lock poisoned".to_string(),
+    }
+}
+
+/// Decodes a TTL in milliseconds.
+pub fn decode_ttl(field: &str, value: Term) -> SnpResult<Duration> {
+    value
+        .decode::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|_| SnpError::invalid_argument(field, "expected a TTL in milliseconds"))
+}
+
+/// Records a verified report as seen for `ttl`, rejecting it if a report
+/// with the same `report_id` and `report_data` was seen within its own TTL.
+///
+/// # Errors
+/// Returns `Replayed` if the report has already been seen.
+pub fn record_report(attestation_report: &AttestationReport, ttl: Duration) -> SnpResult<()> {
+    let mut key = [0u8; 96];
+    key[..32].copy_from_slice(&attestation_report.report_id);
+    key[32..].copy_from_slice(&attestation_report.report_data);
+    let mut seen = SEEN_REPORTS.lock().map_err(|_| poisoned())?;
+    let seen = seen.get_or_insert_with(|| ExpiringSet::new(MAX_SEEN_REPORTS));
+    if !seen.insert(key, ttl, Instant::now()) {
+        return Err(SnpError::Replayed {
+            detail: format!(
+                "report {} with this report_data was already verified",
+                hex::encode(attestation_report.report_id)
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Issues a random nonce that can be consumed once, within `ttl`.
+fn issue(ttl: Duration) -> SnpResult<[u8; NONCE_SIZE]> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand_bytes(&mut nonce).map_err(|err| SnpError::Internal {
+        detail: format!("Failed to generate nonce: {err}"),
+    })?;
+    let mut issued = ISSUED_NONCES.lock().map_err(|_| poisoned())?;
+    issued
+        .get_or_insert_with(|| ExpiringSet::new(MAX_ISSUED_NONCES))
+        .insert(nonce, ttl, Instant::now());
+    Ok(nonce)
+}
+
+/// Consumes an issued nonce. Returns whether it was outstanding.
+fn consume(nonce: &[u8]) -> SnpResult<bool> {
+    let nonce: [u8; NONCE_SIZE] = match nonce.try_into() {
+        Ok(nonce) => nonce,
+        Err(_) => return Ok(false),
+    };
+    let mut issued = ISSUED_NONCES.lock().map_err(|_| poisoned())?;
+    Ok(issued
+        .as_mut()
+        .is_some_and(|issued| issued.remove(&nonce, Instant::now())))
+}
+
+/// Decodes the options of `issue_nonce/1`.
+fn parse_nonce_opts(opts: Term) -> SnpResult<Duration> {
+    let map_iter = MapIterator::new(opts)
+        .ok_or_else(|| SnpError::invalid_argument("opts", "expected a map"))?;
+    let mut ttl = DEFAULT_NONCE_TTL;
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        match key_str.as_str() {
+            "ttl" => ttl = decode_ttl("ttl", value)?,
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+    Ok(ttl)
+}
+
+/// An issued nonce that a report must be bound to, as the `nonce` option of
+/// `verify_signature/2` and `verify_report/2`.
+#[derive(Clone)]
+pub struct NonceBinding {
+    nonce: [u8; NONCE_SIZE],
+    /// The other fields bound with the nonce and how, or `None` if the nonce
+    /// is placed at the start of `report_data` as is.
+    fields: Option<(Vec<Vec<u8>>, Binding)>,
+}
+
+impl std::fmt::Debug for NonceBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NonceBinding")
+            .field("nonce", &hex::encode(self.nonce))
+            .field("hashed", &self.fields.is_some())
+            .finish()
+    }
+}
+
+impl NonceBinding {
+    /// Decodes either a nonce, which must start the report's `report_data`,
+    /// or `{Nonce, Fields, Hash}`, where `report_data` must bind
+    /// `[Nonce | Fields]` as computed by `compute_report_data/2`.
+    pub fn decode(field: &str, value: Term) -> SnpResult<NonceBinding> {
+        let (nonce, fields) = match value.decode::<(Binary, Vec<Binary>, Term)>() {
+            Ok((nonce, fields, hash)) => {
+                let fields = fields.iter().map(|field| field.as_slice().to_vec()).collect();
+                (nonce, Some((fields, Binding::decode(hash)?)))
+            }
+            Err(_) => (
+                value
+                    .decode::<Binary>()
+                    .map_err(|_| SnpError::invalid_argument(field, "expected a nonce"))?,
+                None,
+            ),
+        };
+        let nonce = nonce
+            .as_slice()
+            .try_into()
+            .map_err(|_| SnpError::invalid_argument(field, "expected a 32-byte nonce"))?;
+        Ok(NonceBinding { nonce, fields })
+    }
+
+    /// Whether the report's `report_data` binds the nonce.
+    pub fn is_bound(&self, attestation_report: &AttestationReport) -> SnpResult<bool> {
+        match &self.fields {
+            None => Ok(attestation_report.report_data.starts_with(&self.nonce)),
+            Some((fields, binding)) => {
+                let mut bound: Vec<&[u8]> = vec![&self.nonce];
+                bound.extend(fields.iter().map(Vec::as_slice));
+                Ok(attestation_report.report_data == report_data::compute(&bound, *binding)?)
+            }
+        }
+    }
+
+    /// Checks that the report binds the nonce, and consumes it.
+    ///
+    /// # Errors
+    /// Returns `InvalidReport` for `report_data` if the report does not bind
+    /// the nonce, and `Replayed` if the nonce was not issued, has expired or
+    /// was already consumed.
+    pub fn consume(&self, attestation_report: &AttestationReport) -> SnpResult<()> {
+        if !self.is_bound(attestation_report)? {
+            return Err(SnpError::InvalidReport {
+                field: "report_data".to_string(),
+                reason: "does not bind the nonce".to_string(),
+            });
+        }
+        if !consume(&self.nonce)? {
+            return Err(SnpError::Replayed {
+                detail: "nonce was not issued, has expired or was already consumed".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Issues a challenge nonce for a peer to bind into the `report_data` of its
+/// next report, e.g. with `compute_report_data/2`.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `opts` - An Erlang map of options.
+///
+/// # Expected Input Map Keys (all optional):
+/// - `"ttl"`: How long the nonce can be consumed, in milliseconds (default
+///   five minutes).
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the 32-byte nonce.
+///
+/// # Example
+/// ```erlang
+/// {ok, Nonce} = dev_snp_nif:issue_nonce(#{ ttl => 60000 }).
+/// ```
+#[rustler::nif]
+pub fn issue_nonce<'a>(env: Env<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let result = parse_nonce_opts(opts)
+        .and_then(issue)
+        .and_then(|nonce| encode_binary(env, &nonce));
+    Ok(encode_result(env, result))
+}
+
+/// Consumes a challenge nonce issued by `issue_nonce/1`. Each nonce can be
+/// consumed once, so a report bound to it is only accepted once.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `nonce` - The nonce.
+///
+/// # Returns
+/// `{ok, true}` if the nonce was issued and had neither expired nor been
+/// consumed, `{ok, false}` otherwise.
+///
+/// # Example
+/// ```erlang
+/// {ok, true} = dev_snp_nif:consume_nonce(Nonce),
+/// {ok, true} = dev_snp_nif:verify_report_data(Report, [Nonce, Address], sha256).
+/// ```
+#[rustler::nif]
+pub fn consume_nonce<'a>(env: Env<'a>, nonce: Binary<'a>) -> NifResult<Term<'a>> {
+    Ok(encode_result(env, consume(nonce.as_slice())))
+}
@@ -1,67 +1,142 @@
+use openssl::x509::X509;
+use rustler::{Binary, Encoder, Env, MapIterator, NifResult, OwnedBinary, Term};
 use sev::certs::snp::{ca, Certificate};
 use sev::firmware::host::TcbVersion;
+use crate::cert_store;
+use crate::error::{SnpError, SnpResult};
+use crate::kds::{self, KeyType};
 use crate::logging::log_message;
-use reqwest::blocking::get; 
+use crate::pins;
+use crate::product::Product;
 
-/// Base URL for AMD's Key Distribution Service (KDS).
-const KDS_CERT_SITE: &str = "https://kdsintf.amd.com";
-/// Endpoint for the VCEK API.
-const KDS_VCEK: &str = "/vcek/v1";
-/// Endpoint for the Certificate Chain API.
-const KDS_CERT_CHAIN: &str = "cert_chain";
+/// Decodes a string value passed from Erlang as either a binary or a charlist.
+pub fn decode_string(value: Term) -> NifResult<String> {
+    match value.get_type() {
+        rustler::TermType::List => {
+            let list: Vec<u8> = value.decode()?;
+            String::from_utf8(list).map_err(|_| rustler::Error::BadArg)
+        }
+        _ => value.decode(),
+    }
+}
+
+/// Copies bytes into a new Erlang binary.
+pub fn encode_binary<'a>(env: Env<'a>, bytes: &[u8]) -> SnpResult<Term<'a>> {
+    let mut out = OwnedBinary::new(bytes.len()).ok_or_else(|| SnpError::Serialization {
+        detail: "Failed to allocate binary".to_string(),
+    })?;
+    out.as_mut_slice().copy_from_slice(bytes);
+    Ok(out.release(env).encode(env))
+}
+
+/// Parses a PEM-encoded ASK (or ASVK) + ARK chain, as served by the KDS
+/// `cert_chain` endpoint, accepting it only if its ARK is pinned for the
+/// product line.
+pub fn parse_cert_chain(product: Product, pem: &[u8]) -> SnpResult<ca::Chain> {
+    let invalid = |detail: String| SnpError::InvalidCertificate { detail };
+    let chain = X509::stack_from_pem(pem).map_err(|e| invalid(e.to_string()))?;
+    if chain.len() < 2 {
+        return Err(invalid(
+            "Expected at least two certificates (ARK and ASK) in the chain".to_string(),
+        ));
+    }
+
+    // Reject substituted roots before trusting anything the chain contains
+    pins::ensure_pinned(product, &chain[1])?;
+
+    // Convert ARK and ASK into the `ca::Chain` structure required by the SEV crate
+    let ark = chain[1].to_pem().map_err(|e| invalid(e.to_string()))?;
+    let ask = chain[0].to_pem().map_err(|e| invalid(e.to_string()))?;
+    ca::Chain::from_pem(&ark, &ask).map_err(|e| invalid(e.to_string()))
+}
 
-/// Requests the AMD certificate chain (ASK + ARK) for the given SEV product name.
+/// Requests the AMD certificate chain (ASK + ARK, or ASVK + ARK for VLEKs) for
+/// the given product line.
+///
+/// The on-disk certificate cache is consulted first; the configured certificate
+/// provider (see `kds`) is only used on a cache miss, and a successfully parsed
+/// response is written back to the cache.
 ///
 /// # Arguments
-/// * `sev_prod_name` - The SEV product name (e.g., "Milan").
+/// * `product` - The product line the chain is requested for.
+/// * `key_type` - The type of key the chain must issue.
 ///
 /// # Returns
-/// A `ca::Chain` containing the ASK and ARK certificates.
+/// A `ca::Chain` containing the ASK (or ASVK) and ARK certificates.
 ///
 /// # Errors
-/// Returns an error if the request fails, the response is invalid, or the certificate parsing fails.
+/// Returns `KdsUnreachable` if the certificate is not cached and cannot be
+/// fetched, `InvalidCertificate` if the response cannot be parsed, or
+/// `UntrustedRoot` if its ARK is not pinned (see `pins`), including when the
+/// cached chain's ARK is not pinned and no replacement can be fetched.
 ///
 /// # Example
 /// ```erlang
 /// {ok, CertChain} = dev_snp_nif:request_cert_chain("Milan").
-pub fn request_cert_chain(sev_prod_name: &str) -> Result<ca::Chain, Box<dyn std::error::Error>> {
-// Blocking version of reqwest
-    let url = format!("{KDS_CERT_SITE}{KDS_VCEK}/{sev_prod_name}/{KDS_CERT_CHAIN}");
-    // log_message(
-    //     "INFO",
-    //     file!(),
-    //     line!(),
-    //     &format!("Requesting AMD certificate chain from: {url}"),
-    // );
-
-    // Perform the blocking GET request
-    let response = get(&url)?;
-    let body = response.bytes()?;
+pub fn request_cert_chain(product: Product, key_type: KeyType) -> SnpResult<ca::Chain> {
+    // A cached chain with an unpinned root may just be stale, so it is
+    // refetched; but if no trusted chain can be fetched either, the untrusted
+    // root is what the caller needs to hear about.
+    let mut untrusted = None;
+    if let Some(pem) = cert_store::load_cert_chain(product.kds_name(), key_type) {
+        match parse_cert_chain(product, &pem) {
+            Ok(chain) => return Ok(chain),
+            Err(err) => {
+                log_message(
+                    "WARN",
+                    file!(),
+                    line!(),
+                    &format!("Ignoring cached certificate chain: {}", err),
+                );
+                if matches!(err, SnpError::UntrustedRoot { .. }) {
+                    untrusted = Some(err);
+                }
+            }
+        }
+    }
+
+    log_message(
+        "DEBUG",
+        file!(),
+        line!(),
+        &format!("Requesting AMD certificate chain for: {:?}", product),
+    );
+
+    let body = match kds::provider().and_then(|provider| provider.cert_chain(product, key_type)) {
+        Ok(body) => body,
+        Err(err) => return Err(untrusted.unwrap_or(err)),
+    };
 
     // Parse the response as a PEM-encoded certificate chain
-    let chain = openssl::x509::X509::stack_from_pem(&body)?;
-    if chain.len() < 2 {
-        return Err("Expected at least two certificates (ARK and ASK) in the chain".into());
-    }
+    let ca_chain = parse_cert_chain(product, &body)?;
 
-    // Convert ARK and ASK into the `ca::Chain` structure required by the SEV crate
-    let ark = chain[1].to_pem()?;
-    let ask = chain[0].to_pem()?;
-    let ca_chain = ca::Chain::from_pem(&ark, &ask)?;
+    // Cache the chain; a failure here only costs us a refetch next time.
+    if let Err(err) = cert_store::store_cert_chain(product.kds_name(), key_type, &body) {
+        log_message(
+            "WARN",
+            file!(),
+            line!(),
+            &format!("Failed to cache certificate chain: {}", err),
+        );
+    }
 
-    //log_message(
-    //    "INFO",
-    //    file!(),
-    //    line!(),
-    //    "Successfully fetched AMD certificate chain.",
-    //);
+    log_message(
+        "DEBUG",
+        file!(),
+        line!(),
+        "Successfully fetched AMD certificate chain.",
+    );
 
     Ok(ca_chain)
 }
 
 /// Requests the VCEK for the given chip ID and reported TCB.
 ///
+/// As with `request_cert_chain`, the on-disk certificate cache is consulted
+/// before the certificate provider, and fetched certificates are added to it.
+///
 /// # Arguments
+/// * `product` - The product line of the chip.
 /// * `chip_id` - The unique 64-byte chip ID.
 /// * `reported_tcb` - The TCB version of the platform.
 ///
@@ -69,42 +144,159 @@ pub fn request_cert_chain(sev_prod_name: &str) -> Result<ca::Chain, Box<dyn std:
 /// A `Certificate` representing the VCEK.
 ///
 /// # Errors
-/// Returns an error if the request fails, the response is invalid, or the certificate parsing fails.
+/// Returns `KdsUnreachable` if the certificate is not cached and cannot be
+/// fetched, or `InvalidCertificate` if the response cannot be parsed.
 ///
 /// # Example
 /// ```erlang
 /// {ok, VcekCert} = dev_snp_nif:request_vcek(ChipIdBinary, ReportedTcbMap).
 /// ```
 pub fn request_vcek(
+    product: Product,
     chip_id: [u8; 64],
     reported_tcb: TcbVersion,
-) -> Result<Certificate, Box<dyn std::error::Error>> {
-    use reqwest::blocking::get; // Blocking version of reqwest
-
-    let hw_id = chip_id
-        .iter()
-        .map(|byte| format!("{:02x}", byte))
-        .collect::<String>();
-
-    let url = format!(
-        "{KDS_CERT_SITE}{KDS_VCEK}/Milan/{hw_id}?blSPL={:02}&teeSPL={:02}&snpSPL={:02}&ucodeSPL={:02}",
-        reported_tcb.bootloader, reported_tcb.tee, reported_tcb.snp, reported_tcb.microcode
-    );
+) -> SnpResult<Certificate> {
+    if let Some(der) = cert_store::load_vcek(product.kds_name(), &chip_id, &reported_tcb) {
+        match Certificate::from_der(&der) {
+            Ok(cert) => return Ok(cert),
+            Err(err) => log_message(
+                "WARN",
+                file!(),
+                line!(),
+                &format!("Ignoring corrupt cached VCEK: {}", err),
+            ),
+        }
+    }
 
-    // log_message(
-    //     "INFO",
-    //     file!(),
-    //     line!(),
-    //     &format!("Requesting VCEK from: {url}"),
-    // );
+    log_message("DEBUG", file!(), line!(), "Requesting VCEK...");
 
-    // Perform the blocking GET request
-    let response = get(&url)?;
-    let rsp_bytes = response.bytes()?;
+    let rsp_bytes = kds::provider()?.vcek(product, &chip_id, &reported_tcb)?;
 
     // Parse the VCEK response as a DER-encoded certificate
-    let vcek_cert = Certificate::from_der(&rsp_bytes)?;
+    let vcek_cert =
+        Certificate::from_der(&rsp_bytes).map_err(|e| SnpError::InvalidCertificate {
+            detail: format!("VCEK: {}", e),
+        })?;
+
+    if let Err(err) = cert_store::store_vcek(product.kds_name(), &chip_id, &reported_tcb, &rsp_bytes) {
+        log_message(
+            "WARN",
+            file!(),
+            line!(),
+            &format!("Failed to cache VCEK: {}", err),
+        );
+    }
 
-    // log_message("INFO", file!(), line!(), "Successfully fetched VCEK.");
+    log_message("DEBUG", file!(), line!(), "Successfully fetched VCEK.");
     Ok(vcek_cert)
 }
+
+/// Certificates supplied by the caller, e.g. from the certificate table of an
+/// extended report, used instead of those from the cache or the KDS. Each
+/// certificate may be PEM or DER encoded.
+#[derive(Debug, Default, Clone)]
+pub struct SuppliedCerts {
+    /// The ARK.
+    pub ark: Option<Vec<u8>>,
+    /// The ASK, or the ASVK for VLEK-signed reports.
+    pub ask: Option<Vec<u8>>,
+    /// The VCEK or VLEK that signed the report.
+    pub vek: Option<Vec<u8>>,
+}
+
+/// Parses a single PEM or DER certificate.
+fn parse_x509(name: &str, bytes: &[u8]) -> SnpResult<X509> {
+    X509::from_pem(bytes)
+        .or_else(|_| X509::from_der(bytes))
+        .map_err(|e| SnpError::InvalidCertificate {
+            detail: format!("{}: {}", name, e),
+        })
+}
+
+/// Converts an OpenSSL certificate into a certificate of the SEV crate.
+fn to_certificate(name: &str, cert: &X509) -> SnpResult<Certificate> {
+    cert.to_pem()
+        .ok()
+        .and_then(|pem| Certificate::from_pem(&pem).ok())
+        .ok_or_else(|| SnpError::InvalidCertificate {
+            detail: format!("{}: unable to re-encode certificate", name),
+        })
+}
+
+impl SuppliedCerts {
+    /// Adds a VLEK, given either alone (PEM or DER) or as a PEM chain of VLEK,
+    /// ASVK and ARK. VLEKs cannot be fetched from the KDS by chip ID, so they
+    /// are obtained from the cloud provider (or the extended report).
+    pub fn add_vlek(&mut self, bytes: &[u8]) -> SnpResult<()> {
+        let certs = match X509::stack_from_pem(bytes) {
+            Ok(certs) if !certs.is_empty() => certs,
+            _ => {
+                self.vek = Some(bytes.to_vec());
+                return Ok(());
+            }
+        };
+        let to_der = |cert: &X509| {
+            cert.to_der().map_err(|e| SnpError::InvalidCertificate {
+                detail: format!("VLEK: {}", e),
+            })
+        };
+        self.vek = Some(to_der(&certs[0])?);
+        if certs.len() >= 3 {
+            self.ask = Some(to_der(&certs[1])?);
+            self.ark = Some(to_der(&certs[2])?);
+        }
+        Ok(())
+    }
+
+    /// Adds the certificates of a map keyed by `ark`, `ask`, `asvk`, `vcek`
+    /// or `vlek`, as returned by `generate_extended_report/2`. Other entries
+    /// of the certificate table (e.g. `crl`) are ignored.
+    pub fn add_map(&mut self, field: &str, value: Term) -> SnpResult<()> {
+        let map_iter = MapIterator::new(value)
+            .ok_or_else(|| SnpError::invalid_argument(field, "expected a map"))?;
+        for (key, value) in map_iter {
+            let key_str = match key.atom_to_string() {
+                Ok(key_str) => key_str,
+                Err(_) => continue,
+            };
+            let slot = match key_str.as_str() {
+                "ark" => &mut self.ark,
+                "ask" | "asvk" => &mut self.ask,
+                "vcek" | "vlek" => &mut self.vek,
+                _ => continue,
+            };
+            let cert: Binary = value.decode().map_err(|_| {
+                SnpError::invalid_argument(&format!("{field}.{key_str}"), "expected a binary")
+            })?;
+            *slot = Some(cert.as_slice().to_vec());
+        }
+        Ok(())
+    }
+
+    /// Returns the supplied ASK (or ASVK) + ARK chain, if both were supplied.
+    /// The chain is subject to the same ARK pinning as fetched chains.
+    pub fn chain(&self, product: Product) -> SnpResult<Option<ca::Chain>> {
+        let (ask, ark) = match (&self.ask, &self.ark) {
+            (Some(ask), Some(ark)) => (parse_x509("ASK", ask)?, parse_x509("ARK", ark)?),
+            _ => return Ok(None),
+        };
+        pins::ensure_pinned(product, &ark)?;
+        let ark = to_certificate("ARK", &ark)?;
+        let ask = to_certificate("ASK", &ask)?;
+        Ok(Some(ca::Chain { ark, ask }))
+    }
+
+    /// Returns the supplied signing key, if any.
+    ///
+    /// # Arguments
+    /// * `key_type` - The type of key that signed the report, for error messages.
+    pub fn vek(&self, key_type: KeyType) -> SnpResult<Option<Certificate>> {
+        match &self.vek {
+            Some(vek) => {
+                let vek = parse_x509(key_type.name(), vek)?;
+                Ok(Some(to_certificate(key_type.name(), &vek)?))
+            }
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use openssl::ec::{EcKey, EcKeyRef};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{HasParams, HasPublic, PKey, Private, Public};
+use rustler::{Binary, Encoder, Env, MapIterator, NifResult, Term};
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::helpers::encode_binary;
+use crate::logging::log_message;
+use crate::policy::fixed_bytes;
+use crate::report;
+
+rustler::atoms! {
+    id_block,
+    id_auth,
+    id_key_digest,
+    author_key_digest,
+    none,
+}
+
+/// The SNP ABI's identifier for ECDSA P-384 with SHA-384, the only
+/// algorithm defined for ID and author keys.
+const ALGO_ECDSA_P384_SHA384: u32 = 1;
+/// The SNP ABI's identifier for the P-384 curve in public keys.
+const CURVE_P384: u32 = 2;
+/// The guest policy used if none is given: SMT allowed, and the reserved bit
+/// 17 set.
+const DEFAULT_POLICY: u64 = 0x30000;
+
+/// Size of the ID block passed to `SNP_LAUNCH_FINISH`.
+pub const ID_BLOCK_SIZE: usize = 0x60;
+/// Size of the ID authentication information structure.
+pub const ID_AUTH_SIZE: usize = 0x1000;
+/// Size of a public key in the SNP ABI's format.
+const PUBLIC_KEY_SIZE: usize = 0x404;
+/// Size of a signature in the SNP ABI's format.
+const SIGNATURE_SIZE: usize = 0x200;
+/// Size of a little-endian ECDSA parameter (coordinate, `r` or `s`).
+const PARAM_SIZE: usize = 72;
+
+/// The fields of an ID block, which the firmware checks against the launch
+/// digest and policy before copying the IDs into every report.
+#[derive(Debug, Clone)]
+pub struct IdBlock {
+    pub measurement: [u8; 48],
+    pub family_id: [u8; 16],
+    pub image_id: [u8; 16],
+    pub guest_svn: u32,
+    pub policy: u64,
+}
+
+impl IdBlock {
+    /// Encodes the ID block (version 1).
+    pub fn encode(&self) -> [u8; ID_BLOCK_SIZE] {
+        let mut out = [0u8; ID_BLOCK_SIZE];
+        out[0x00..0x30].copy_from_slice(&self.measurement);
+        out[0x30..0x40].copy_from_slice(&self.family_id);
+        out[0x40..0x50].copy_from_slice(&self.image_id);
+        out[0x50..0x54].copy_from_slice(&1u32.to_le_bytes());
+        out[0x54..0x58].copy_from_slice(&self.guest_svn.to_le_bytes());
+        out[0x58..0x60].copy_from_slice(&self.policy.to_le_bytes());
+        out
+    }
+}
+
+/// Maps an OpenSSL failure to the error of the given key.
+fn key_error(field: &str) -> impl Fn(openssl::error::ErrorStack) -> SnpError + '_ {
+    move |err| SnpError::invalid_argument(field, format!("invalid ECDSA P-384 key: {err}"))
+}
+
+/// Writes a big-endian number as a little-endian, zero-extended parameter.
+fn write_param(out: &mut [u8], value: &BigNumRef) -> SnpResult<()> {
+    let mut bytes = value.to_vec_padded(PARAM_SIZE as i32).map_err(|err| SnpError::Serialization {
+        detail: format!("ECDSA parameter out of range: {err}"),
+    })?;
+    bytes.reverse();
+    out[..PARAM_SIZE].copy_from_slice(&bytes);
+    Ok(())
+}
+
+/// Encodes a P-384 public key in the SNP ABI's format.
+pub fn encode_public_key<T: HasParams + HasPublic>(key: &EcKeyRef<T>) -> SnpResult<[u8; PUBLIC_KEY_SIZE]> {
+    let openssl_err = |err: openssl::error::ErrorStack| SnpError::Serialization {
+        detail: format!("Failed to encode public key: {err}"),
+    };
+    let mut x = BigNum::new().map_err(openssl_err)?;
+    let mut y = BigNum::new().map_err(openssl_err)?;
+    let mut ctx = BigNumContext::new().map_err(openssl_err)?;
+    key.public_key()
+        .affine_coordinates(key.group(), &mut x, &mut y, &mut ctx)
+        .map_err(openssl_err)?;
+    let mut out = [0u8; PUBLIC_KEY_SIZE];
+    out[0..4].copy_from_slice(&CURVE_P384.to_le_bytes());
+    write_param(&mut out[4..], &x)?;
+    write_param(&mut out[4 + PARAM_SIZE..], &y)?;
+    Ok(out)
+}
+
+/// The SHA-384 digest of a public key in the SNP ABI's format, as found in
+/// the `id_key_digest` and `author_key_digest` fields of reports.
+pub fn key_digest<T: HasParams + HasPublic>(key: &EcKeyRef<T>) -> SnpResult<[u8; 48]> {
+    let digest = hash(MessageDigest::sha384(), &encode_public_key(key)?).map_err(|err| {
+        SnpError::Serialization {
+            detail: format!("Failed to hash public key: {err}"),
+        }
+    })?;
+    let mut out = [0u8; 48];
+    out.copy_from_slice(&digest);
+    Ok(out)
+}
+
+/// Signs data with ECDSA P-384 and SHA-384, in the SNP ABI's signature format.
+fn sign(key: &EcKeyRef<Private>, data: &[u8]) -> SnpResult<[u8; SIGNATURE_SIZE]> {
+    let openssl_err = |err: openssl::error::ErrorStack| SnpError::Serialization {
+        detail: format!("Failed to sign: {err}"),
+    };
+    let digest = hash(MessageDigest::sha384(), data).map_err(openssl_err)?;
+    let signature = EcdsaSig::sign(&digest, key).map_err(openssl_err)?;
+    let mut out = [0u8; SIGNATURE_SIZE];
+    write_param(&mut out, signature.r())?;
+    write_param(&mut out[PARAM_SIZE..], signature.s())?;
+    Ok(out)
+}
+
+/// Checks that a key is on the P-384 curve.
+fn ensure_p384<T: HasParams>(field: &str, key: EcKey<T>) -> SnpResult<EcKey<T>> {
+    match key.group().curve_name() {
+        Some(Nid::SECP384R1) => Ok(key),
+        _ => Err(SnpError::invalid_argument(field, "expected an ECDSA P-384 key")),
+    }
+}
+
+/// Parses a PEM or DER encoded P-384 private key.
+pub fn parse_private_key(field: &str, bytes: &[u8]) -> SnpResult<EcKey<Private>> {
+    let key = PKey::private_key_from_pem(bytes)
+        .or_else(|_| PKey::private_key_from_der(bytes))
+        .and_then(|key| key.ec_key())
+        .map_err(key_error(field))?;
+    ensure_p384(field, key)
+}
+
+/// Parses a PEM or DER encoded P-384 public key, or the public half of a
+/// private key.
+pub fn parse_public_key(field: &str, bytes: &[u8]) -> SnpResult<EcKey<Public>> {
+    if let Ok(key) = PKey::public_key_from_pem(bytes)
+        .or_else(|_| PKey::public_key_from_der(bytes))
+        .and_then(|key| key.ec_key())
+    {
+        return ensure_p384(field, key);
+    }
+    let private = parse_private_key(field, bytes)?;
+    let key = EcKey::from_public_key(private.group(), private.public_key()).map_err(key_error(field))?;
+    Ok(key)
+}
+
+/// The ID block and ID authentication information of a guest, for
+/// `SNP_LAUNCH_FINISH` (e.g. QEMU's `id-block` and `id-auth`).
+pub struct SignedIdBlock {
+    pub id_block: [u8; ID_BLOCK_SIZE],
+    pub id_auth: Vec<u8>,
+    pub id_key_digest: [u8; 48],
+    pub author_key_digest: Option<[u8; 48]>,
+}
+
+/// Signs an ID block with the ID key and, if given, the ID key with the
+/// author key.
+pub fn sign_id_block(
+    block: &IdBlock,
+    id_key: &EcKeyRef<Private>,
+    author_key: Option<&EcKeyRef<Private>>,
+) -> SnpResult<SignedIdBlock> {
+    let id_block = block.encode();
+    let id_public_key = encode_public_key(id_key)?;
+    let mut id_auth = vec![0u8; ID_AUTH_SIZE];
+    id_auth[0x000..0x004].copy_from_slice(&ALGO_ECDSA_P384_SHA384.to_le_bytes());
+    id_auth[0x040..0x240].copy_from_slice(&sign(id_key, &id_block)?);
+    id_auth[0x240..0x644].copy_from_slice(&id_public_key);
+    let author_key_digest = match author_key {
+        Some(author_key) => {
+            id_auth[0x004..0x008].copy_from_slice(&ALGO_ECDSA_P384_SHA384.to_le_bytes());
+            id_auth[0x680..0x880].copy_from_slice(&sign(author_key, &id_public_key)?);
+            id_auth[0x880..0xC84].copy_from_slice(&encode_public_key(author_key)?);
+            Some(key_digest(author_key)?)
+        }
+        None => None,
+    };
+    Ok(SignedIdBlock {
+        id_block,
+        id_auth,
+        id_key_digest: key_digest(id_key)?,
+        author_key_digest,
+    })
+}
+
+/// Decodes a trusted key as the digest found in reports: either a PEM or DER
+/// key (public or private), or its 48-byte digest, raw or hex encoded.
+pub fn trusted_key_digest(field: &str, value: Term) -> SnpResult<[u8; 48]> {
+    if let Ok(digest) = fixed_bytes::<48>(field, value) {
+        return Ok(digest);
+    }
+    let bytes: Binary = value
+        .decode()
+        .map_err(|_| SnpError::invalid_argument(field, "expected a key or key digest"))?;
+    key_digest(&*parse_public_key(field, bytes.as_slice())?)
+}
+
+/// Decodes a single trusted key or a list of them.
+pub fn trusted_key_digests(field: &str, value: Term) -> SnpResult<Vec<[u8; 48]>> {
+    match value.decode::<Vec<Term>>() {
+        Ok(items) => items.into_iter().map(|item| trusted_key_digest(field, item)).collect(),
+        Err(_) => Ok(vec![trusted_key_digest(field, value)?]),
+    }
+}
+
+/// Decodes the options of `generate_id_block/1`.
+fn parse_id_block_opts(opts: Term) -> SnpResult<(IdBlock, EcKey<Private>, Option<EcKey<Private>>)> {
+    let map_iter = MapIterator::new(opts)
+        .ok_or_else(|| SnpError::invalid_argument("opts", "expected a map"))?;
+    let mut measurement = None;
+    let mut id_key = None;
+    let mut author_key = None;
+    let mut block = IdBlock {
+        measurement: [0u8; 48],
+        family_id: [0u8; 16],
+        image_id: [0u8; 16],
+        guest_svn: 0,
+        policy: DEFAULT_POLICY,
+    };
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        let private_key = |value: Term| {
+            let pem: Binary = value
+                .decode()
+                .map_err(|_| SnpError::invalid_argument(&key_str, "expected a PEM or DER key"))?;
+            parse_private_key(&key_str, pem.as_slice())
+        };
+        match key_str.as_str() {
+            "measurement" => measurement = Some(fixed_bytes(&key_str, value)?),
+            "family_id" => block.family_id = fixed_bytes(&key_str, value)?,
+            "image_id" => block.image_id = fixed_bytes(&key_str, value)?,
+            "guest_svn" => {
+                block.guest_svn = value
+                    .decode()
+                    .map_err(|_| SnpError::invalid_argument(&key_str, "expected an integer"))?
+            }
+            "policy" => {
+                block.policy = value
+                    .decode()
+                    .map_err(|_| SnpError::invalid_argument(&key_str, "expected an integer"))?
+            }
+            "id_key" => id_key = Some(private_key(value)?),
+            "author_key" => author_key = Some(private_key(value)?),
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+    block.measurement =
+        measurement.ok_or_else(|| SnpError::invalid_argument("measurement", "required"))?;
+    let id_key = id_key.ok_or_else(|| SnpError::invalid_argument("id_key", "required"))?;
+    Ok((block, id_key, author_key))
+}
+
+/// Encodes a signed ID block as an Erlang map.
+fn encode_signed<'a>(env: Env<'a>, signed: &SignedIdBlock) -> SnpResult<Term<'a>> {
+    let author_digest = match &signed.author_key_digest {
+        Some(digest) => hex::encode(digest).encode(env),
+        None => none().encode(env),
+    };
+    let block = encode_binary(env, &signed.id_block)?;
+    let auth = encode_binary(env, &signed.id_auth)?;
+    Term::map_new(env)
+        .map_put(id_block(), block)
+        .and_then(|map| map.map_put(id_auth(), auth))
+        .and_then(|map| map.map_put(id_key_digest(), hex::encode(signed.id_key_digest)))
+        .and_then(|map| map.map_put(author_key_digest(), author_digest))
+        .map_err(|_| SnpError::Serialization {
+            detail: "Failed to build ID block map".to_string(),
+        })
+}
+
+/// Generates an SNP ID block and ID authentication information for a launch
+/// measurement. Launched with them, a guest's reports carry its family and
+/// image IDs and the digests of the keys that signed them, so that policies
+/// can trust the keys rather than individual measurements.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `opts` - An Erlang map describing the ID block.
+///
+/// # Expected Input Map Keys:
+/// - `"measurement"`: The expected launch measurement (48 bytes, raw or hex). Required.
+/// - `"id_key"`: The ECDSA P-384 private key signing the ID block (PEM or DER). Required.
+/// - `"author_key"`: The ECDSA P-384 private key signing the ID key (optional).
+/// - `"family_id"`, `"image_id"`: The IDs (16 bytes, raw or hex; default zero).
+/// - `"guest_svn"`: The guest SVN (default 0).
+/// - `"policy"`: The guest policy, which must match the launch policy
+///   (default `16#30000`).
+///
+/// # Returns
+/// A tuple containing an `ok` atom and a map with the 96-byte `id_block`,
+/// the 4096-byte `id_auth`, and the hex-encoded `id_key_digest` and
+/// `author_key_digest` (`none` without an author key) that reports will carry.
+///
+/// # Example
+/// ```erlang
+/// {ok, #{ id_block := IdBlock, id_auth := IdAuth }} =
+///     dev_snp_nif:generate_id_block(#{ measurement => Measurement, id_key => IdKeyPem }).
+/// ```
+#[rustler::nif]
+pub fn generate_id_block<'a>(env: Env<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let result = parse_id_block_opts(opts)
+        .and_then(|(block, id_key, author_key)| sign_id_block(&block, &id_key, author_key.as_deref()))
+        .and_then(|signed| encode_signed(env, &signed));
+    Ok(encode_result(env, result))
+}
+
+/// Checks the ID and author key digests of a report against the trusted keys
+/// of `verify_id_key/2`.
+fn check_id_keys(report: &[u8], opts: Term) -> SnpResult<bool> {
+    let parsed = report::parse(report)?;
+    let map_iter = MapIterator::new(opts)
+        .ok_or_else(|| SnpError::invalid_argument("opts", "expected a map"))?;
+    let mut checked = false;
+    let mut trusted = true;
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        let actual = match key_str.as_str() {
+            "id_key" => parsed.id_key_digest,
+            "author_key" => parsed.author_key_digest,
+            _ => {
+                log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str));
+                continue;
+            }
+        };
+        trusted &= trusted_key_digests(&key_str, value)?.contains(&actual);
+        checked = true;
+    }
+    if !checked {
+        return Err(SnpError::invalid_argument("opts", "expected id_key or author_key"));
+    }
+    Ok(trusted)
+}
+
+/// Checks that a report was launched with an ID block signed by a trusted
+/// ID key and, if given, a trusted author key.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `report` - The attestation report, either raw or JSON encoded.
+/// * `opts` - An Erlang map with `"id_key"` and/or `"author_key"`, each a
+///   trusted key (PEM or DER, public or private) or its digest (48 bytes,
+///   raw or hex), or a list of them.
+///
+/// # Returns
+/// `{ok, true}` if the report's `id_key_digest` and `author_key_digest` match
+/// one of the given keys, `{ok, false}` otherwise, or `{error, {Kind, Detail}}`.
+/// The report's signature is not verified.
+///
+/// # Example
+/// ```erlang
+/// {ok, true} = dev_snp_nif:verify_id_key(JsonReport, #{ author_key => AuthorPublicKeyPem }).
+/// ```
+#[rustler::nif]
+pub fn verify_id_key<'a>(env: Env<'a>, report: Binary<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let result = check_id_keys(report.as_slice(), opts);
+    Ok(encode_result(env, result))
+}
@@ -0,0 +1,202 @@
+use rustler::{Binary, Encoder, Env, NifResult, Term};
+use sev::firmware::guest::AttestationReport;
+use sev::firmware::host::TcbVersion;
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::kds::KeyType;
+use crate::product::{tcb_bytes, Product};
+use crate::report;
+use crate::tcb::Tcb;
+
+/// Single-bit flags of the guest policy, by name and bit. Bit 17 is reserved
+/// and always set.
+const GUEST_POLICY_FLAGS: [(&str, u32); 9] = [
+    ("smt_allowed", 16),
+    ("migrate_ma", 18),
+    ("debug", 19),
+    ("single_socket", 20),
+    ("cxl_allowed", 21),
+    ("mem_aes_256_xts", 22),
+    ("rapl_disabled", 23),
+    ("ciphertext_hiding", 24),
+    ("page_swap_disabled", 25),
+];
+
+/// Flags of the platform info, by name and bit.
+const PLATFORM_INFO_FLAGS: [(&str, u32); 6] = [
+    ("smt_enabled", 0),
+    ("tsme_enabled", 1),
+    ("ecc_enabled", 2),
+    ("rapl_disabled", 3),
+    ("ciphertext_hiding_enabled", 4),
+    ("alias_check_complete", 5),
+];
+
+/// Builds an Erlang map with atom keys, entry by entry.
+struct MapBuilder<'a> {
+    env: Env<'a>,
+    map: Term<'a>,
+}
+
+impl<'a> MapBuilder<'a> {
+    fn new(env: Env<'a>) -> Self {
+        MapBuilder {
+            env,
+            map: Term::map_new(env),
+        }
+    }
+
+    fn put(mut self, key: &str, value: impl Encoder) -> SnpResult<Self> {
+        let map_err = || SnpError::Serialization {
+            detail: format!("Failed to encode report field `{key}`"),
+        };
+        let key = rustler::Atom::from_str(self.env, key).map_err(|_| map_err())?;
+        self.map = self.map.map_put(key, value).map_err(|_| map_err())?;
+        Ok(self)
+    }
+
+    fn build(self) -> Term<'a> {
+        self.map
+    }
+}
+
+/// Whether a bit of a flags word is set.
+fn bit(flags: u64, bit: u32) -> bool {
+    flags & (1 << bit) != 0
+}
+
+/// Decodes a bit field into a map of named flags, with the raw value under `raw`.
+fn encode_flags<'a>(env: Env<'a>, raw: u64, flags: &[(&str, u32)]) -> SnpResult<MapBuilder<'a>> {
+    flags
+        .iter()
+        .try_fold(MapBuilder::new(env).put("raw", raw)?, |map, (name, index)| {
+            map.put(name, bit(raw, *index))
+        })
+}
+
+/// Decodes the guest policy, including the minimum ABI version it requires.
+fn encode_policy<'a>(env: Env<'a>, raw: u64) -> SnpResult<Term<'a>> {
+    Ok(encode_flags(env, raw, &GUEST_POLICY_FLAGS)?
+        .put("abi_major", (raw >> 8) as u8)?
+        .put("abi_minor", raw as u8)?
+        .build())
+}
+
+/// Decodes a TCB version into the SPLs of its product line's layout.
+fn encode_tcb<'a>(env: Env<'a>, product: Product, tcb: &TcbVersion) -> SnpResult<Term<'a>> {
+    Tcb::new(product, tcb)
+        .components()
+        .into_iter()
+        .try_fold(
+            MapBuilder::new(env).put("raw", hex::encode(tcb_bytes(tcb)))?,
+            |map, (component, level)| map.put(component, level),
+        )
+        .map(MapBuilder::build)
+}
+
+/// Names the key that signed the report, from bits 2-4 of its key info.
+fn signing_key(report: &AttestationReport) -> &'static str {
+    match KeyType::from_report(report) {
+        Ok(KeyType::Vcek) => "vcek",
+        Ok(KeyType::Vlek) => "vlek",
+        Err(_) if ((report._author_key_en >> 2) & 0x7) == 7 => "none",
+        Err(_) => "unknown",
+    }
+}
+
+/// Decodes a parsed report into a map of named, human-meaningful values.
+fn encode_report<'a>(env: Env<'a>, report: &AttestationReport) -> SnpResult<Term<'a>> {
+    let product = Product::of_report(report, None);
+    let atom = |name: &str| {
+        rustler::Atom::from_str(env, name).map_err(|_| SnpError::Serialization {
+            detail: format!("Failed to encode atom `{name}`"),
+        })
+    };
+    let signature_algorithm = match report.sig_algo {
+        1 => atom("ecdsa_p384_sha384")?.encode(env),
+        other => (atom("unknown")?, other).encode(env),
+    };
+    let firmware = |major: u8, minor: u8, build: u8| format!("{major}.{minor}.{build}");
+    let map = MapBuilder::new(env)
+        .put("version", report.version)?
+        .put("product", product.to_atom())?
+        .put("guest_svn", report.guest_svn)?
+        .put("vmpl", report.vmpl)?
+        .put("policy", encode_policy(env, report.policy.0)?)?
+        .put(
+            "platform_info",
+            encode_flags(env, report.plat_info.0, &PLATFORM_INFO_FLAGS)?.build(),
+        )?
+        .put("signing_key", atom(signing_key(report))?)?
+        .put("author_key_enabled", bit(report._author_key_en as u64, 0))?
+        .put("mask_chip_key", bit(report._author_key_en as u64, 1))?
+        .put("signature_algorithm", signature_algorithm)?
+        .put("current_tcb", encode_tcb(env, product, &report.current_tcb)?)?
+        .put("reported_tcb", encode_tcb(env, product, &report.reported_tcb)?)?
+        .put("committed_tcb", encode_tcb(env, product, &report.committed_tcb)?)?
+        .put("launch_tcb", encode_tcb(env, product, &report.launch_tcb)?)?
+        .put(
+            "current_firmware",
+            firmware(report.current_major, report.current_minor, report.current_build),
+        )?
+        .put(
+            "committed_firmware",
+            firmware(report.committed_major, report.committed_minor, report.committed_build),
+        )?
+        .put("measurement", hex::encode(report.measurement))?
+        .put("report_data", hex::encode(report.report_data))?
+        .put("host_data", hex::encode(report.host_data))?
+        .put("family_id", hex::encode(report.family_id))?
+        .put("image_id", hex::encode(report.image_id))?
+        .put("id_key_digest", hex::encode(report.id_key_digest))?
+        .put("author_key_digest", hex::encode(report.author_key_digest))?
+        .put("report_id", hex::encode(report.report_id))?
+        .put("report_id_ma", hex::encode(report.report_id_ma))?
+        .put("chip_id", hex::encode(report.chip_id))?
+        .put(
+            "signature",
+            MapBuilder::new(env)
+                .put("r", hex::encode(report.signature.r))?
+                .put("s", hex::encode(report.signature.s))?
+                .build(),
+        )?;
+    Ok(map.build())
+}
+
+/// Decodes an attestation report into human-meaningful values, for
+/// inspection and logging. The report is not verified.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `report` - The attestation report, either raw or JSON encoded.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and a map with the keys:
+/// - `version`, `guest_svn`, `vmpl`: As in the report.
+/// - `product`: The product line derived from the report.
+/// - `policy`: The guest policy's flags (`smt_allowed`, `migrate_ma`,
+///   `debug`, `single_socket`, `cxl_allowed`, `mem_aes_256_xts`,
+///   `rapl_disabled`, `ciphertext_hiding`, `page_swap_disabled`), its
+///   `abi_major` and `abi_minor`, and its `raw` value.
+/// - `platform_info`: The platform's flags (`smt_enabled`, `tsme_enabled`,
+///   `ecc_enabled`, `rapl_disabled`, `ciphertext_hiding_enabled`,
+///   `alias_check_complete`) and its `raw` value.
+/// - `signing_key`: `vcek`, `vlek`, `none` or `unknown`, along with the
+///   `author_key_enabled` and `mask_chip_key` flags.
+/// - `signature_algorithm`: `ecdsa_p384_sha384` or `{unknown, Value}`.
+/// - `current_tcb`, `reported_tcb`, `committed_tcb`, `launch_tcb`: The SPLs
+///   of each component for the product line, and the `raw` hex encoding.
+/// - `current_firmware`, `committed_firmware`: e.g. `<<"1.55.21">>`.
+/// - `measurement`, `report_data`, `host_data`, `family_id`, `image_id`,
+///   `id_key_digest`, `author_key_digest`, `report_id`, `report_id_ma`,
+///   `chip_id`: Hex encoded.
+/// - `signature`: The hex-encoded `r` and `s` components, as stored.
+///
+/// # Example
+/// ```erlang
+/// {ok, #{ policy := #{ debug := false } }} = dev_snp_nif:decode_report(JsonReport).
+/// ```
+#[rustler::nif]
+pub fn decode_report<'a>(env: Env<'a>, report: Binary<'a>) -> NifResult<Term<'a>> {
+    let result = report::parse(report.as_slice()).and_then(|parsed| encode_report(env, &parsed));
+    Ok(encode_result(env, result))
+}
@@ -0,0 +1,304 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use rustler::{Env, MapIterator, NifResult, Term};
+use reqwest::blocking::Client;
+use sev::firmware::guest::AttestationReport;
+use sev::firmware::host::TcbVersion;
+use crate::error::{encode_unit, SnpError, SnpResult};
+use crate::helpers::decode_string;
+use crate::logging::log_message;
+use crate::product::Product;
+
+/// Base URL for AMD's Key Distribution Service (KDS).
+pub const KDS_CERT_SITE: &str = "https://kdsintf.amd.com";
+/// Endpoint for the VCEK API.
+const KDS_VCEK: &str = "/vcek/v1";
+/// Endpoint for the VLEK API.
+const KDS_VLEK: &str = "/vlek/v1";
+/// Endpoint for the Certificate Chain API.
+const KDS_CERT_CHAIN: &str = "cert_chain";
+/// Endpoint for the Certificate Revocation List API.
+const KDS_CRL: &str = "crl";
+/// Default timeout applied to KDS requests.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The kind of key that signed an attestation report. VCEKs are unique to a
+/// chip and are issued under the ASK; VLEKs are provisioned by cloud
+/// providers and are issued under the ASVK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyType {
+    Vcek,
+    Vlek,
+}
+
+impl KeyType {
+    /// Reads the signing key from bits 2-4 of the report's key info field,
+    /// which the SEV crate exposes as `_author_key_en`.
+    ///
+    /// # Errors
+    /// Returns `SignatureInvalid` if the report is unsigned or names an
+    /// unknown key.
+    pub fn from_report(report: &AttestationReport) -> SnpResult<KeyType> {
+        match (report._author_key_en >> 2) & 0x7 {
+            0 => Ok(KeyType::Vcek),
+            1 => Ok(KeyType::Vlek),
+            7 => Err(SnpError::SignatureInvalid {
+                detail: "report is not signed".to_string(),
+            }),
+            other => Err(SnpError::SignatureInvalid {
+                detail: format!("unknown signing key {other}"),
+            }),
+        }
+    }
+
+    /// The KDS API serving this key type's certificates.
+    fn endpoint(&self) -> &'static str {
+        match self {
+            KeyType::Vcek => KDS_VCEK,
+            KeyType::Vlek => KDS_VLEK,
+        }
+    }
+
+    /// The name of the key type, as used in messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyType::Vcek => "VCEK",
+            KeyType::Vlek => "VLEK",
+        }
+    }
+}
+
+/// A source of AMD certificates. Providers return the raw encodings served by
+/// the KDS so that the certificate cache can store them unchanged.
+pub trait CertProvider: Send + Sync {
+    /// Returns the PEM-encoded ASK + ARK chain for a product line, or the
+    /// ASVK + ARK chain for VLEKs.
+    fn cert_chain(&self, product: Product, key_type: KeyType) -> SnpResult<Vec<u8>>;
+
+    /// Returns the DER-encoded VCEK for a chip ID and TCB version.
+    fn vcek(
+        &self,
+        product: Product,
+        chip_id: &[u8; 64],
+        tcb: &TcbVersion,
+    ) -> SnpResult<Vec<u8>>;
+
+    /// Returns the DER-encoded CRL issued by a product line's ARK for the
+    /// given key type.
+    fn crl(&self, product: Product, key_type: KeyType) -> SnpResult<Vec<u8>>;
+}
+
+/// Fetches certificates over HTTP from AMD's KDS or any service exposing the
+/// same API (e.g. an internal mirror or a local mock).
+pub struct KdsProvider {
+    base_url: String,
+    client: Client,
+}
+
+impl KdsProvider {
+    /// Creates a provider for the given base URL.
+    ///
+    /// # Arguments
+    /// * `base_url` - The KDS base URL, without a trailing slash.
+    /// * `timeout` - The timeout applied to every request.
+    /// * `proxy` - An optional proxy URL through which all requests are sent.
+    pub fn new(
+        base_url: &str,
+        timeout: Duration,
+        proxy: Option<&str>,
+    ) -> SnpResult<Self> {
+        let mut builder = Client::builder().timeout(timeout);
+        if let Some(proxy) = proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|err| SnpError::invalid_argument("proxy", err.to_string()))?;
+            builder = builder.proxy(proxy);
+        }
+        let client = builder
+            .build()
+            .map_err(|err| SnpError::invalid_argument("base_url", err.to_string()))?;
+        Ok(KdsProvider {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client,
+        })
+    }
+
+    /// Performs a blocking GET request, failing on non-success status codes.
+    fn fetch(&self, url: &str) -> SnpResult<Vec<u8>> {
+        log_message("DEBUG", file!(), line!(), &format!("Requesting: {url}"));
+        let unreachable = |err: reqwest::Error| SnpError::KdsUnreachable {
+            detail: format!("{url}: {err}"),
+        };
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(unreachable)?;
+        Ok(response.bytes().map_err(unreachable)?.to_vec())
+    }
+}
+
+impl CertProvider for KdsProvider {
+    fn cert_chain(&self, product: Product, key_type: KeyType) -> SnpResult<Vec<u8>> {
+        let url = format!(
+            "{}{}/{}/{KDS_CERT_CHAIN}",
+            self.base_url,
+            key_type.endpoint(),
+            product.kds_name()
+        );
+        self.fetch(&url)
+    }
+
+    fn vcek(
+        &self,
+        product: Product,
+        chip_id: &[u8; 64],
+        tcb: &TcbVersion,
+    ) -> SnpResult<Vec<u8>> {
+        let url = format!(
+            "{}{KDS_VCEK}/{}/{}?{}",
+            self.base_url,
+            product.kds_name(),
+            product.hw_id(chip_id),
+            product.vcek_query(tcb)
+        );
+        self.fetch(&url)
+    }
+
+    fn crl(&self, product: Product, key_type: KeyType) -> SnpResult<Vec<u8>> {
+        let url = format!(
+            "{}{}/{}/{KDS_CRL}",
+            self.base_url,
+            key_type.endpoint(),
+            product.kds_name()
+        );
+        self.fetch(&url)
+    }
+}
+
+/// A provider that never leaves the machine: only certificates already present
+/// in the on-disk cache can be used.
+pub struct LocalOnlyProvider;
+
+impl CertProvider for LocalOnlyProvider {
+    fn cert_chain(&self, product: Product, key_type: KeyType) -> SnpResult<Vec<u8>> {
+        Err(SnpError::KdsUnreachable {
+            detail: format!(
+                "No cached {} certificate chain for {} (local-only mode)",
+                key_type.name(),
+                product.kds_name()
+            ),
+        })
+    }
+
+    fn vcek(
+        &self,
+        product: Product,
+        _chip_id: &[u8; 64],
+        _tcb: &TcbVersion,
+    ) -> SnpResult<Vec<u8>> {
+        Err(SnpError::KdsUnreachable {
+            detail: format!("No cached VCEK for {} (local-only mode)", product.kds_name()),
+        })
+    }
+
+    fn crl(&self, product: Product, key_type: KeyType) -> SnpResult<Vec<u8>> {
+        Err(SnpError::KdsUnreachable {
+            detail: format!(
+                "No cached {} CRL for {} (local-only mode)",
+                key_type.name(),
+                product.kds_name()
+            ),
+        })
+    }
+}
+
+/// The provider used on a certificate cache miss. `None` means the default KDS.
+static PROVIDER: RwLock<Option<Arc<dyn CertProvider>>> = RwLock::new(None);
+
+/// Returns the currently configured certificate provider.
+pub fn provider() -> SnpResult<Arc<dyn CertProvider>> {
+    if let Some(provider) = PROVIDER.read().map_err(|_| poisoned())?.as_ref() {
+        return Ok(provider.clone());
+    }
+    let default: Arc<dyn CertProvider> =
+        Arc::new(KdsProvider::new(KDS_CERT_SITE, DEFAULT_TIMEOUT, None)?);
+    let mut current = PROVIDER.write().map_err(|_| poisoned())?;
+    Ok(current.get_or_insert(default).clone())
+}
+
+/// Replaces the certificate provider used on a cache miss.
+pub fn set_provider(provider: Arc<dyn CertProvider>) -> SnpResult<()> {
+    let mut current = PROVIDER.write().map_err(|_| poisoned())?;
+    *current = Some(provider);
+    Ok(())
+}
+
+/// The error returned if a thread panicked while holding the provider lock.
+fn poisoned() -> SnpError {
+    SnpError::KdsUnreachable {
+        detail: "Certificate provider lock poisoned".to_string(),
+    }
+}
+
+/// Configures where certificates are fetched from when they are not cached.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `opts` - An Erlang map of provider options.
+///
+/// # Expected Input Map Keys:
+/// - `"mode"`: `kds` (default) to fetch over HTTP, or `local` to use only the cache.
+/// - `"base_url"`: Base URL of the KDS or a mirror of it (String).
+/// - `"timeout"`: Request timeout in milliseconds (u64).
+/// - `"proxy"`: Proxy URL to send all requests through (String).
+///
+/// # Returns
+/// `ok` if the provider was configured, or `{error, {invalid_argument, Key}}`.
+///
+/// # Example
+/// ```erlang
+/// ok = dev_snp_nif:configure_cert_provider(#{ base_url => <<"http://localhost:8080">> }).
+/// ```
+#[rustler::nif]
+pub fn configure_cert_provider<'a>(env: Env<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let map_iter = MapIterator::new(opts).ok_or(rustler::Error::BadArg)?;
+    Ok(encode_unit(env, configure_provider(map_iter)))
+}
+
+/// Builds and installs a certificate provider from `configure_cert_provider` options.
+fn configure_provider(map_iter: MapIterator) -> SnpResult<()> {
+    let mut local_only = false;
+    let mut base_url = KDS_CERT_SITE.to_string();
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut proxy: Option<String> = None;
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        let invalid = |_| SnpError::invalid_argument(&key_str, "unexpected value");
+        match key_str.as_str() {
+            "mode" => match value.atom_to_string().map_err(invalid)?.as_str() {
+                "kds" => local_only = false,
+                "local" => local_only = true,
+                other => {
+                    return Err(SnpError::invalid_argument(
+                        "mode",
+                        format!("unknown mode {other}"),
+                    ))
+                }
+            },
+            "base_url" => base_url = decode_string(value).map_err(invalid)?,
+            "timeout" => timeout = Duration::from_millis(value.decode().map_err(invalid)?),
+            "proxy" => proxy = Some(decode_string(value).map_err(invalid)?),
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+
+    let provider: Arc<dyn CertProvider> = if local_only {
+        Arc::new(LocalOnlyProvider)
+    } else {
+        Arc::new(KdsProvider::new(&base_url, timeout, proxy.as_deref())?)
+    };
+    set_provider(provider)
+}
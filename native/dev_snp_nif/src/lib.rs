@@ -1,5 +1,5 @@
-/// Entry point for the Rustler NIF module.
-/// This file defines the available NIF functions and organizes them into modules.
+//! Entry point for the Rustler NIF module.
+//! This file defines the available NIF functions and organizes them into modules.
 
 mod logging;
 mod snp_support;
@@ -7,6 +7,27 @@ mod attestation;
 mod digest;
 mod verification;
 mod helpers;
+mod cert_store;
+mod kds;
+mod product;
+mod report;
+mod error;
+mod ovmf;
+mod policy;
+mod tcb;
+mod crl;
+mod pins;
+mod derived_key;
+mod seal;
+mod firmware;
+mod report_data;
+mod async_nif;
+mod batch;
+mod inspect;
+mod id_block;
+mod freshness;
+#[cfg(feature = "mock-firmware")]
+mod mock_firmware;
 
 rustler::init!(
     "dev_snp_nif"// Module name as used in Erlang.
@@ -1,10 +1,171 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::RwLock;
 use std::thread;
 use std::time::SystemTime;
+use rustler::env::OwnedEnv;
+use rustler::{Atom, Encoder, Env, LocalPid, MapIterator, NifResult, Term};
+use crate::error::{encode_unit, SnpError, SnpResult};
+
+rustler::atoms! {
+    snp_log,
+    level,
+    target,
+    file,
+    line,
+    message,
+    timestamp,
+    undefined,
+    debug,
+    info,
+    warn,
+    error,
+}
+
+/// The severity of a log event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Parses the level names used by `log_message`.
+    fn from_name(name: &str) -> Level {
+        match name {
+            "DEBUG" => Level::Debug,
+            "INFO" => Level::Info,
+            "WARN" => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+
+    fn to_atom(self) -> Atom {
+        match self {
+            Level::Debug => debug(),
+            Level::Info => info(),
+            Level::Warn => warn(),
+            Level::Error => error(),
+        }
+    }
+
+    /// Decodes a threshold: a level, or `none` to disable logging.
+    fn decode_threshold(field: &str, value: Term) -> SnpResult<Option<Level>> {
+        match value.atom_to_string().as_deref() {
+            Ok("debug") => Ok(Some(Level::Debug)),
+            Ok("info") => Ok(Some(Level::Info)),
+            Ok("warn") => Ok(Some(Level::Warn)),
+            Ok("error") => Ok(Some(Level::Error)),
+            Ok("none") => Ok(None),
+            _ => Err(SnpError::invalid_argument(
+                field,
+                "expected debug, info, warn, error or none",
+            )),
+        }
+    }
+}
+
+/// A log event, as delivered to the registered process.
+struct Event {
+    level: Level,
+    target: String,
+    file: String,
+    line: u32,
+    message: String,
+    timestamp: u64,
+}
+
+impl Encoder for Event {
+    /// Encodes the event as `{snp_log, #{level, target, file, line, message, timestamp}}`.
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let map = Term::map_new(env)
+            .map_put(level(), self.level.to_atom())
+            .and_then(|map| map.map_put(target(), self.target.as_str()))
+            .and_then(|map| map.map_put(file(), self.file.as_str()))
+            .and_then(|map| map.map_put(line(), self.line))
+            .and_then(|map| map.map_put(message(), self.message.as_str()))
+            .and_then(|map| map.map_put(timestamp(), self.timestamp))
+            .unwrap_or_else(|_| self.message.as_str().encode(env));
+        (snp_log(), map).encode(env)
+    }
+}
+
+/// Which events are logged, and where they go.
+#[derive(Clone)]
+struct LogConfig {
+    /// The threshold of modules without one of their own. `None` disables them.
+    default: Option<Level>,
+    /// Thresholds by module (e.g. `verification`), overriding the default.
+    targets: HashMap<String, Option<Level>>,
+    /// The channel to the thread delivering events to the registered
+    /// process. Events are printed to stdout if no process is registered.
+    sink: Option<Sender<Event>>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            default: Some(Level::Info),
+            targets: HashMap::new(),
+            sink: None,
+        }
+    }
+}
+
+impl LogConfig {
+    fn enabled(&self, level: Level, target: &str) -> bool {
+        let threshold = self.targets.get(target).copied().unwrap_or(self.default);
+        threshold.is_some_and(|threshold| level >= threshold)
+    }
+}
+
+/// The logging configuration. `None` means the default: `info` and above,
+/// printed to stdout.
+static CONFIG: RwLock<Option<LogConfig>> = RwLock::new(None);
+
+/// The module a log call comes from, named after its source file.
+fn target_of(file: &str) -> &str {
+    Path::new(file)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(file)
+}
+
+/// Starts a thread delivering events to `pid`, and returns the channel to it.
+/// The thread exits once the channel is dropped, i.e. when the logging
+/// configuration no longer refers to it.
+fn spawn_forwarder(pid: LocalPid) -> Sender<Event> {
+    let (sender, receiver) = mpsc::channel::<Event>();
+    thread::spawn(move || {
+        let mut env = OwnedEnv::new();
+        for event in receiver {
+            // Events sent after the process exits are dropped.
+            let _ = env.send_and_clear(&pid, |env| event.encode(env));
+        }
+    });
+    sender
+}
 
 /// Logs messages with details including thread ID, timestamp, file, and line number.
 ///
+/// Messages below the threshold of their module (see `configure_logging/1`)
+/// are dropped. The others are sent to the registered process as
+/// `{snp_log, Event}`, or printed to stdout if none is registered.
+///
 /// # Arguments
-/// - `log_level`: The log level (e.g., "INFO", "ERROR").
+/// - `log_level`: The log level (`DEBUG`, `INFO`, `WARN` or `ERROR`).
 /// - `file`: The file where the log is being generated.
 /// - `line`: The line number of the log statement.
 /// - `message`: The log message.
@@ -14,15 +175,130 @@ use std::time::SystemTime;
 /// log_message("INFO", file!(), line!(), "This is a log message.");
 /// ```
 pub fn log_message(log_level: &str, file: &str, line: u32, message: &str) {
+    let level = Level::from_name(log_level);
+    let target = target_of(file);
+    let config = match CONFIG.read() {
+        Ok(config) => config,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let enabled = match config.as_ref() {
+        Some(config) => config.enabled(level, target),
+        None => level >= Level::Info,
+    };
+    if !enabled {
+        return;
+    }
+
     let thread_id = thread::current().id();
     let now = SystemTime::now();
-    let timestamp = now
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .map(|d| d.as_secs())
-        .unwrap_or(0);
+    let since_epoch = now.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
 
+    if let Some(sink) = config.as_ref().and_then(|config| config.sink.as_ref()) {
+        let event = Event {
+            level,
+            target: target.to_string(),
+            file: file.to_string(),
+            line,
+            message: message.to_string(),
+            timestamp: since_epoch.as_millis() as u64,
+        };
+        if sink.send(event).is_ok() {
+            return;
+        }
+    }
     println!(
         "[{}#{:?} @ {}:{}] [{}] {}",
-        log_level, thread_id, file, line, timestamp, message
+        level.name(),
+        thread_id,
+        file,
+        line,
+        since_epoch.as_secs(),
+        message
     );
 }
+
+/// Applies the options of `configure_logging/1` to the configuration,
+/// returning the keys it did not recognize. They are logged by the caller,
+/// once the configuration lock is released.
+fn apply_opts(config: &mut LogConfig, opts: Term) -> SnpResult<Vec<String>> {
+    let mut unexpected = Vec::new();
+    let map_iter = MapIterator::new(opts)
+        .ok_or_else(|| SnpError::invalid_argument("opts", "expected a map"))?;
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        match key_str.as_str() {
+            "level" => config.default = Level::decode_threshold("level", value)?,
+            "targets" => {
+                let targets = MapIterator::new(value)
+                    .ok_or_else(|| SnpError::invalid_argument("targets", "expected a map"))?;
+                for (module, threshold) in targets {
+                    let module = module.atom_to_string().map_err(|_| {
+                        SnpError::invalid_argument("targets", "keys must be module atoms")
+                    })?;
+                    let field = format!("targets.{module}");
+                    let threshold = Level::decode_threshold(&field, threshold)?;
+                    config.targets.insert(module, threshold);
+                }
+            }
+            "pid" => {
+                config.sink = match value.decode::<LocalPid>() {
+                    Ok(pid) => Some(spawn_forwarder(pid)),
+                    Err(_) if value.decode::<Atom>().ok() == Some(undefined()) => None,
+                    Err(_) => {
+                        return Err(SnpError::invalid_argument("pid", "expected a pid or undefined"))
+                    }
+                }
+            }
+            _ => unexpected.push(key_str),
+        }
+    }
+    Ok(unexpected)
+}
+
+/// Configures the logging of the NIFs.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `opts` - An Erlang map of logging options. Only the given options change.
+///
+/// # Expected Input Map Keys (all optional):
+/// - `"level"`: The minimum level logged (`debug`, `info`, `warn` or
+///   `error`), or `none`. Defaults to `info`.
+/// - `"targets"`: A map from module (e.g. `verification`, `kds`) to a level
+///   or `none`, overriding `"level"` for that module.
+/// - `"pid"`: The process to send events to, as
+///   `{snp_log, #{ level, target, file, line, message, timestamp }}`, where
+///   `timestamp` is in milliseconds. `undefined` prints them to stdout again.
+///
+/// # Returns
+/// `ok`, or `{error, {invalid_argument, Detail}}`, in which case the
+/// configuration is unchanged.
+///
+/// # Example
+/// ```erlang
+/// ok = dev_snp_nif:configure_logging(#{ level => warn, targets => #{ kds => debug }, pid => self() }).
+/// ```
+#[rustler::nif]
+pub fn configure_logging<'a>(env: Env<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let result = match CONFIG.write() {
+        Ok(mut config) => {
+            let mut updated = config.clone().unwrap_or_default();
+            let unexpected = apply_opts(&mut updated, opts);
+            if unexpected.is_ok() {
+                *config = Some(updated);
+            }
+            unexpected
+        }
+        Err(_) => Err(SnpError::Serialization {
+            detail: "Logging configuration lock poisoned".to_string(),
+        }),
+    };
+    let result = result.map(|unexpected| {
+        for key_str in unexpected {
+            log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str));
+        }
+    });
+    Ok(encode_unit(env, result))
+}
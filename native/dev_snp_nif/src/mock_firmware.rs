@@ -0,0 +1,470 @@
+use std::env;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumRef, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::x509::extension::BasicConstraints;
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use rustler::{Env, MapIterator, NifResult, Term};
+use sev::firmware::guest::{AttestationReport, GuestPolicy};
+use sev::firmware::host::{CertTableEntry, CertType, TcbVersion};
+use crate::cert_store;
+use crate::derived_key::KeyRequest;
+use crate::error::{encode_unit, SnpError, SnpResult};
+use crate::firmware::{GuestFirmware, DEVICE_PATH};
+use crate::kds::{self, CertProvider, KeyType};
+use crate::logging::log_message;
+use crate::pins;
+use crate::policy::fixed_bytes;
+use crate::product::Product;
+use crate::report::{self, REPORT_SIZE};
+
+/// The measurement reported by default: that of `test/snp-attestation.json`.
+const DEFAULT_MEASUREMENT: &str =
+    "4460a293f792d04ad40b37d775212a7a11157c76cbb5a98a36da62a2d6131038238e40e5f08ff9529e7d3d1019d93160";
+/// The guest policy reported by default: SMT allowed, debugging disabled.
+const DEFAULT_POLICY: u64 = 0x30000;
+/// The TCB version reported by the emulated chip.
+const MOCK_TCB: TcbVersion = TcbVersion {
+    bootloader: 4,
+    tee: 0,
+    _reserved: [0; 4],
+    snp: 22,
+    microcode: 213,
+};
+/// The number of leading report bytes covered by the signature.
+const SIGNED_LEN: usize = 0x2A0;
+/// DER encoding of the ecdsa-with-SHA384 algorithm identifier.
+const ECDSA_WITH_SHA384: [u8; 12] = [
+    0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03,
+];
+
+/// The emulated chip: its certificate chain, VCEK and root secret. Generated
+/// once per process, so reports only verify within the process that made them.
+struct MockChip {
+    ark: X509,
+    ask: X509,
+    vcek: X509,
+    vcek_key: EcKey<Private>,
+    crl: Vec<u8>,
+    chip_id: [u8; 64],
+    root_secret: [u8; 32],
+}
+
+/// The guest identity reported by the emulator.
+#[derive(Debug, Clone, Copy)]
+struct MockConfig {
+    measurement: [u8; 48],
+    host_data: [u8; 32],
+    policy: u64,
+}
+
+static CHIP: Mutex<Option<Arc<MockChip>>> = Mutex::new(None);
+static CONFIG: RwLock<Option<MockConfig>> = RwLock::new(None);
+
+/// Wraps an OpenSSL error from setting up the emulator.
+fn mock_error(err: ErrorStack) -> SnpError {
+    SnpError::Firmware {
+        detail: format!("Mock firmware: {err}"),
+    }
+}
+
+/// The error returned if a thread panicked while holding an emulator lock.
+fn poisoned() -> SnpError {
+    SnpError::Firmware {
+        detail: "Mock firmware lock poisoned".to_string(),
+    }
+}
+
+/// The identity reported until `configure_mock_firmware/1` is called.
+fn default_config() -> SnpResult<MockConfig> {
+    let mut measurement = [0u8; 48];
+    hex::decode_to_slice(DEFAULT_MEASUREMENT, &mut measurement)
+        .map_err(|err| SnpError::Firmware {
+            detail: format!("Mock firmware: invalid default measurement: {err}"),
+        })?;
+    Ok(MockConfig {
+        measurement,
+        host_data: [0; 32],
+        policy: DEFAULT_POLICY,
+    })
+}
+
+/// The currently configured identity.
+fn config() -> SnpResult<MockConfig> {
+    let config = CONFIG.read().map_err(|_| poisoned())?;
+    config.map_or_else(default_config, Ok)
+}
+
+/// Generates a P-384 key, the curve of every key in AMD's SNP chains.
+fn generate_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// Issues a certificate for `key`, signed by `issuer` or self-signed. `ca`
+/// marks certificates that issue others (the ARK and ASK).
+fn issue(
+    common_name: &str,
+    key: &PKey<Private>,
+    issuer: Option<(&X509, &PKey<Private>)>,
+    ca: bool,
+) -> Result<X509, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", common_name)?;
+    let name = name.build();
+    let mut serial = BigNum::new()?;
+    serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(3650)?;
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    if ca {
+        builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    }
+    match issuer {
+        Some((issuer, issuer_key)) => {
+            builder.set_issuer_name(issuer.subject_name())?;
+            builder.sign(issuer_key, MessageDigest::sha384())?;
+        }
+        None => {
+            builder.set_issuer_name(&name)?;
+            builder.sign(key, MessageDigest::sha384())?;
+        }
+    }
+    Ok(builder.build())
+}
+
+/// Encodes a DER element.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let len = content.len();
+    let mut out = vec![tag];
+    if len < 0x80 {
+        out.push(len as u8);
+    } else if len < 0x100 {
+        out.extend([0x81, len as u8]);
+    } else {
+        out.extend([0x82, (len >> 8) as u8, len as u8]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Builds an empty CRL issued by the ARK, valid until 2099. OpenSSL has no
+/// CRL builder in the Rust bindings, so the `TBSCertList` is encoded by hand.
+fn empty_crl(ark: &X509, ark_key: &PKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+    let tbs = der(
+        0x30,
+        &[
+            der(0x02, &[1]),
+            ECDSA_WITH_SHA384.to_vec(),
+            ark.subject_name().to_der()?,
+            der(0x17, b"250101000000Z"),
+            der(0x18, b"20991231235959Z"),
+        ]
+        .concat(),
+    );
+    let mut signer = Signer::new(MessageDigest::sha384(), ark_key)?;
+    signer.update(&tbs)?;
+    let signature = [&[0u8][..], &signer.sign_to_vec()?].concat();
+    Ok(der(
+        0x30,
+        &[tbs, ECDSA_WITH_SHA384.to_vec(), der(0x03, &signature)].concat(),
+    ))
+}
+
+impl MockChip {
+    fn generate() -> Result<MockChip, ErrorStack> {
+        let ark_key = generate_key()?;
+        let ask_key = generate_key()?;
+        let vcek_key = generate_key()?;
+        let ark = issue("ARK-Milan", &ark_key, None, true)?;
+        let ask = issue("SEV-Milan", &ask_key, Some((&ark, &ark_key)), true)?;
+        let vcek = issue("SEV-VCEK", &vcek_key, Some((&ask, &ask_key)), false)?;
+        let crl = empty_crl(&ark, &ark_key)?;
+        let mut chip_id = [0u8; 64];
+        rand_bytes(&mut chip_id)?;
+        let mut root_secret = [0u8; 32];
+        rand_bytes(&mut root_secret)?;
+        Ok(MockChip {
+            ark,
+            ask,
+            vcek,
+            vcek_key: vcek_key.ec_key()?,
+            crl,
+            chip_id,
+            root_secret,
+        })
+    }
+
+    /// Builds a signed report.
+    fn report(
+        &self,
+        config: &MockConfig,
+        unique_data: [u8; 64],
+        vmpl: u32,
+    ) -> SnpResult<AttestationReport> {
+        // Start from a minimal valid raw report: version 2, ECDSA P-384.
+        let mut raw = vec![0u8; REPORT_SIZE];
+        raw[0x00..0x04].copy_from_slice(&2u32.to_le_bytes());
+        raw[0x34..0x38].copy_from_slice(&1u32.to_le_bytes());
+        let mut report = report::from_bytes(&raw)?;
+        report.policy = GuestPolicy(config.policy);
+        report.vmpl = vmpl;
+        report.report_data = unique_data;
+        report.measurement = config.measurement;
+        report.host_data = config.host_data;
+        report.chip_id = self.chip_id;
+        report.current_tcb = MOCK_TCB;
+        report.reported_tcb = MOCK_TCB;
+        report.committed_tcb = MOCK_TCB;
+        report.launch_tcb = MOCK_TCB;
+        report.current_major = 1;
+        report.current_minor = 55;
+        report.committed_major = 1;
+        report.committed_minor = 55;
+        rand_bytes(&mut report.report_id).map_err(mock_error)?;
+
+        let digest = hash(MessageDigest::sha384(), &report::to_bytes(&report)[..SIGNED_LEN])
+            .map_err(mock_error)?;
+        let signature = EcdsaSig::sign(&digest, &self.vcek_key).map_err(mock_error)?;
+        report.signature.r = little_endian(signature.r());
+        report.signature.s = little_endian(signature.s());
+        Ok(report)
+    }
+
+    /// The certificate table the emulated host provides with extended reports.
+    fn cert_table(&self) -> SnpResult<Vec<CertTableEntry>> {
+        let entry = |cert_type: CertType, cert: &X509| {
+            cert.to_der()
+                .map(|data| CertTableEntry { cert_type, data })
+                .map_err(mock_error)
+        };
+        Ok(vec![
+            entry(CertType::VCEK, &self.vcek)?,
+            entry(CertType::ASK, &self.ask)?,
+            entry(CertType::ARK, &self.ark)?,
+            CertTableEntry {
+                cert_type: CertType::CRL,
+                data: self.crl.clone(),
+            },
+        ])
+    }
+}
+
+/// Encodes a signature component as the SNP ABI does: 72 bytes, little endian.
+fn little_endian(value: &BigNumRef) -> [u8; 72] {
+    let mut out = [0u8; 72];
+    for (byte, value) in out.iter_mut().zip(value.to_vec().iter().rev()) {
+        *byte = *value;
+    }
+    out
+}
+
+/// Serves the emulated chip's certificates in place of the KDS.
+struct MockProvider {
+    cert_chain: Vec<u8>,
+    vcek: Vec<u8>,
+    crl: Vec<u8>,
+    chip_id: [u8; 64],
+}
+
+impl CertProvider for MockProvider {
+    fn cert_chain(&self, product: Product, key_type: KeyType) -> SnpResult<Vec<u8>> {
+        match key_type {
+            KeyType::Vcek => Ok(self.cert_chain.clone()),
+            KeyType::Vlek => Err(SnpError::KdsUnreachable {
+                detail: format!("No mock VLEK certificate chain for {}", product.kds_name()),
+            }),
+        }
+    }
+
+    fn vcek(
+        &self,
+        product: Product,
+        chip_id: &[u8; 64],
+        _tcb: &TcbVersion,
+    ) -> SnpResult<Vec<u8>> {
+        if *chip_id == self.chip_id {
+            Ok(self.vcek.clone())
+        } else {
+            Err(SnpError::KdsUnreachable {
+                detail: format!("No mock VCEK for this {} chip", product.kds_name()),
+            })
+        }
+    }
+
+    fn crl(&self, _product: Product, _key_type: KeyType) -> SnpResult<Vec<u8>> {
+        Ok(self.crl.clone())
+    }
+}
+
+/// Makes reports from the emulated chip verifiable: pins its ARK, serves its
+/// certificates in place of the KDS, and moves the certificate cache to a
+/// directory of its own so that cached AMD certificates do not shadow them.
+///
+/// This is process-wide: until they are configured again, certificate
+/// lookups for any chip are answered by the emulated chip, overriding any
+/// earlier `configure_cert_provider/1` or `set_cert_cache_dir/1`. The KDS cannot be
+/// scoped to the mock chip, as certificate chains are fetched per product
+/// line, not per chip, so the mock chain replaces AMD's Milan chain.
+fn install(chip: &MockChip) -> SnpResult<()> {
+    let to_pem = |cert: &X509| cert.to_pem().map_err(mock_error);
+    pins::add(Product::Milan, pins::fingerprint(&chip.ark)?)?;
+    kds::set_provider(Arc::new(MockProvider {
+        cert_chain: [to_pem(&chip.ask)?, to_pem(&chip.ark)?].concat(),
+        vcek: chip.vcek.to_der().map_err(mock_error)?,
+        crl: chip.crl.clone(),
+        chip_id: chip.chip_id,
+    }))?;
+    let cache_dir = env::temp_dir().join(format!("dev_snp_nif-mock-{}", std::process::id()));
+    cert_store::set_cache_dir(cache_dir)
+}
+
+/// Returns the emulated chip, generating and installing it on first use.
+fn chip() -> SnpResult<Arc<MockChip>> {
+    let mut chip = CHIP.lock().map_err(|_| poisoned())?;
+    if let Some(chip) = chip.as_ref() {
+        return Ok(chip.clone());
+    }
+    let generated = MockChip::generate().map_err(mock_error)?;
+    install(&generated)?;
+    Ok(chip.insert(Arc::new(generated)).clone())
+}
+
+/// A software SNP guest firmware, for tests on machines without SNP.
+struct MockFirmware {
+    chip: Arc<MockChip>,
+    config: MockConfig,
+}
+
+impl GuestFirmware for MockFirmware {
+    fn backend(&self) -> &'static str {
+        "mock"
+    }
+
+    fn get_report(&mut self, unique_data: [u8; 64], vmpl: u32) -> SnpResult<AttestationReport> {
+        self.chip.report(&self.config, unique_data, vmpl)
+    }
+
+    fn get_ext_report(
+        &mut self,
+        unique_data: [u8; 64],
+        vmpl: u32,
+    ) -> SnpResult<(AttestationReport, Vec<CertTableEntry>)> {
+        let report = self.chip.report(&self.config, unique_data, vmpl)?;
+        Ok((report, self.chip.cert_table()?))
+    }
+
+    /// Derives keys from the chip's root secret, mixing in the request and
+    /// the selected fields of the guest's identity like the firmware does.
+    fn get_derived_key(&mut self, request: &KeyRequest) -> SnpResult<[u8; 32]> {
+        let selected = |bit: u32| (request.fields >> bit) & 1 == 1;
+        let mut input = self.chip.root_secret.to_vec();
+        input.push(request.root_key as u8);
+        input.extend(request.vmpl.to_le_bytes());
+        input.extend(request.fields.to_le_bytes());
+        if selected(0) {
+            input.extend(self.config.policy.to_le_bytes());
+        }
+        if selected(3) {
+            input.extend(self.config.measurement);
+        }
+        if selected(4) {
+            input.extend(request.guest_svn.to_le_bytes());
+        }
+        if selected(5) {
+            input.extend(request.tcb_version.to_le_bytes());
+        }
+        let digest = hash(MessageDigest::sha256(), &input).map_err(mock_error)?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&digest);
+        Ok(key)
+    }
+}
+
+/// Opens the emulated firmware.
+pub fn open() -> SnpResult<Box<dyn GuestFirmware>> {
+    Ok(Box::new(MockFirmware {
+        chip: chip()?,
+        config: config()?,
+    }))
+}
+
+/// Sets the guest identity reported by the mock firmware. Only available when
+/// the NIF is built with the `mock-firmware` feature; the mock is used when
+/// `/dev/sev-guest` is absent.
+///
+/// **Test builds only.** The first use of the mock firmware replaces the
+/// certificate provider and cache directory of the whole node, and pins a
+/// Milan ARK generated in-process, so reports of real chips can no longer be
+/// verified by the node afterwards. Each call installs the emulator's
+/// certificates again, undoing any `configure_cert_provider/1`,
+/// `set_cert_cache_dir/1` or `seed_cert_cache/1` made since.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `opts` - An Erlang map of identity fields.
+///
+/// # Expected Input Map Keys (all optional):
+/// - `"measurement"`: The launch measurement (48 bytes, raw or hex). Defaults
+///   to that of `test/snp-attestation.json`.
+/// - `"host_data"`: The host data (32 bytes, raw or hex).
+/// - `"policy"`: The guest policy (integer).
+///
+/// # Returns
+/// `ok`, or `{error, {invalid_argument | bad_hex, Key}}`.
+///
+/// # Example
+/// ```erlang
+/// {ok, #{ measurement := Measurement }} = dev_snp_nif:compute_launch_digest(Args),
+/// ok = dev_snp_nif:configure_mock_firmware(#{ measurement => Measurement }).
+/// ```
+#[rustler::nif]
+pub fn configure_mock_firmware<'a>(env: Env<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let map_iter = MapIterator::new(opts).ok_or(rustler::Error::BadArg)?;
+    Ok(encode_unit(env, configure(map_iter)))
+}
+
+/// Applies `configure_mock_firmware` options to the current identity.
+fn configure(map_iter: MapIterator) -> SnpResult<()> {
+    let mut config = config()?;
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        match key_str.as_str() {
+            "measurement" => config.measurement = fixed_bytes(&key_str, value)?,
+            "host_data" => config.host_data = fixed_bytes(&key_str, value)?,
+            "policy" => {
+                config.policy = value
+                    .decode()
+                    .map_err(|_| SnpError::invalid_argument(&key_str, "expected an integer"))?
+            }
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+    *CONFIG.write().map_err(|_| poisoned())? = Some(config);
+    // Certificate lookup may have been reconfigured since the emulator was
+    // first used; make its reports verifiable again, unless a real device
+    // is in use.
+    if Path::new(DEVICE_PATH).exists() {
+        return Ok(());
+    }
+    install(&*chip()?)
+}
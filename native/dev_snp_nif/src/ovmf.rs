@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, RwLock};
+use rustler::{Env, NifResult, Term};
+use sev::measurement::snp::calc_snp_ovmf_hash;
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::helpers::decode_string;
+
+/// Known OVMF images, keyed by the lowercase hex of their SNP page hash.
+/// Nothing is registered by default: images must be registered explicitly
+/// with `register_ovmf/1`, so that no path is resolved against whatever the
+/// BEAM's working directory happens to be.
+static REGISTRY: LazyLock<RwLock<HashMap<String, PathBuf>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// The error returned if a thread panicked while holding the registry lock.
+fn poisoned() -> SnpError {
+    SnpError::Measurement {
+        detail: "OVMF registry lock poisoned".to_string(),
+    }
+}
+
+/// Returns the path of the registered OVMF image with the given hash, if any.
+pub fn lookup(hash: &str) -> Option<PathBuf> {
+    let hash = hash.to_ascii_lowercase();
+    REGISTRY.read().ok()?.get(&hash).cloned()
+}
+
+/// Computes the SNP page hash of an OVMF image, as hex.
+pub fn hash_file(path: &Path) -> SnpResult<String> {
+    calc_snp_ovmf_hash(path.to_path_buf())
+        .map(|hash| hash.get_hex_ld())
+        .map_err(|err| SnpError::Measurement {
+            detail: format!("Failed to hash {}: {:?}", path.display(), err),
+        })
+}
+
+/// Computes the SNP page hash of an OVMF image and registers the image under it.
+///
+/// # Arguments
+/// * `path` - The path of the OVMF image.
+///
+/// # Returns
+/// The hex-encoded hash under which the image was registered, as expected in
+/// the `firmware` key of `compute_launch_digest`.
+pub fn register(path: &Path) -> SnpResult<String> {
+    if !path.is_file() {
+        return Err(SnpError::invalid_argument("ovmf_file", "not found"));
+    }
+    let hash = hash_file(path)?;
+    let mut registry = REGISTRY.write().map_err(|_| poisoned())?;
+    registry.insert(hash.clone(), path.to_path_buf());
+    Ok(hash)
+}
+
+/// Registers an OVMF image so that launch digests can be computed from its
+/// hash alone.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `path` - The path of the OVMF image, as a binary or string.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the hex-encoded hash of the image.
+///
+/// # Example
+/// ```erlang
+/// {ok, FirmwareHash} = dev_snp_nif:register_ovmf(<<"test/OVMF-1.55.fd">>).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn register_ovmf<'a>(env: Env<'a>, path: Term<'a>) -> NifResult<Term<'a>> {
+    let path = decode_string(path)?;
+    Ok(encode_result(env, register(Path::new(&path))))
+}
@@ -0,0 +1,162 @@
+use std::sync::RwLock;
+use openssl::hash::{hash, MessageDigest};
+use openssl::x509::X509;
+use rustler::{Binary, Env, NifResult, Term};
+use crate::error::{encode_unit, SnpError, SnpResult};
+use crate::product::{decode_product, Product};
+
+/// SHA-256 fingerprints of the DER-encoded public keys of AMD's ARKs, as
+/// published in the KDS `cert_chain` responses. Bergamo shares Genoa's root;
+/// any other root must be pinned from Erlang with `pin_ark/2`.
+const EMBEDDED_PINS: [(Product, &str); 3] = [
+    (
+        Product::Milan,
+        "9f056bee44377e29308cb5ffa895bdfb62d18881fa6bed8d6f075b0204089cb9",
+    ),
+    (
+        Product::Genoa,
+        "429a69c9422aa258ee4d8db5fcda9c6470ef15f8cd5a9cebd6cbc7d90b863831",
+    ),
+    (
+        Product::Turin,
+        "4f125410563a2ab9a50356f9243f6fe0b6f73de98603f53f90339c70e9d7ad08",
+    ),
+];
+
+/// Additional pins supplied from Erlang, keyed by KDS product name.
+static EXTRA_PINS: RwLock<Vec<(&'static str, [u8; 32])>> = RwLock::new(Vec::new());
+
+/// The error returned if a thread panicked while holding the pin lock.
+fn poisoned() -> SnpError {
+    SnpError::UntrustedRoot {
+        detail: "ARK pin lock poisoned".to_string(),
+    }
+}
+
+/// Computes the fingerprint of a certificate's public key.
+pub fn fingerprint(cert: &X509) -> SnpResult<[u8; 32]> {
+    let invalid = |err: openssl::error::ErrorStack| SnpError::InvalidCertificate {
+        detail: err.to_string(),
+    };
+    let key = cert
+        .public_key()
+        .and_then(|key| key.public_key_to_der())
+        .map_err(invalid)?;
+    let digest = hash(MessageDigest::sha256(), &key).map_err(invalid)?;
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&digest);
+    Ok(out)
+}
+
+/// Whether a fingerprint is pinned for a product line. Product lines that
+/// share certificates (Genoa and Bergamo) share their pins.
+fn is_pinned(product: Product, fingerprint: &[u8; 32]) -> SnpResult<bool> {
+    let hex_fingerprint = hex::encode(fingerprint);
+    let embedded = EMBEDDED_PINS.iter().any(|(pinned, pin)| {
+        pinned.kds_name() == product.kds_name() && *pin == hex_fingerprint
+    });
+    if embedded {
+        return Ok(true);
+    }
+    let extra = EXTRA_PINS.read().map_err(|_| poisoned())?;
+    Ok(extra
+        .iter()
+        .any(|(name, pin)| *name == product.kds_name() && pin == fingerprint))
+}
+
+/// Checks that an ARK's public key is pinned for a product line.
+///
+/// # Errors
+/// Returns `UntrustedRoot` if the key is not pinned.
+pub fn ensure_pinned(product: Product, ark: &X509) -> SnpResult<()> {
+    let fingerprint = fingerprint(ark)?;
+    if is_pinned(product, &fingerprint)? {
+        return Ok(());
+    }
+    Err(SnpError::UntrustedRoot {
+        detail: format!(
+            "ARK for {} with key fingerprint {} is not pinned",
+            product.kds_name(),
+            hex::encode(fingerprint)
+        ),
+    })
+}
+
+/// Trusts an ARK public key for a product line, in addition to the embedded pins.
+pub fn add(product: Product, fingerprint: [u8; 32]) -> SnpResult<()> {
+    let mut extra = EXTRA_PINS.write().map_err(|_| poisoned())?;
+    if !extra.contains(&(product.kds_name(), fingerprint)) {
+        extra.push((product.kds_name(), fingerprint));
+    }
+    Ok(())
+}
+
+/// Stops trusting an ARK public key added with `add`. Embedded pins cannot
+/// be removed.
+pub fn remove(product: Product, fingerprint: [u8; 32]) -> SnpResult<()> {
+    let mut extra = EXTRA_PINS.write().map_err(|_| poisoned())?;
+    extra.retain(|pin| *pin != (product.kds_name(), fingerprint));
+    Ok(())
+}
+
+/// Decodes a pin given as a raw or hex-encoded fingerprint, or as a PEM or
+/// DER certificate whose public key is pinned.
+fn decode_pin(pin: &[u8]) -> SnpResult<[u8; 32]> {
+    if let Ok(raw) = pin.try_into() {
+        return Ok(raw);
+    }
+    if let Ok(cert) = X509::from_pem(pin).or_else(|_| X509::from_der(pin)) {
+        return fingerprint(&cert);
+    }
+    hex::decode(pin)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| SnpError::BadHex {
+            field: "pin".to_string(),
+        })
+}
+
+/// Adds a trusted ARK for a product line, in addition to the embedded pins.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `product` - The product line (`milan`, `genoa`, `bergamo`, `siena` or `turin`).
+/// * `pin` - The SHA-256 fingerprint of the ARK's DER-encoded public key (raw
+///   or hex), or the ARK certificate itself (PEM or DER).
+///
+/// # Returns
+/// `ok` if the pin was added, or `{error, {Kind, Detail}}`.
+///
+/// # Example
+/// ```erlang
+/// ok = dev_snp_nif:pin_ark(genoa, GenoaArkPem).
+/// ```
+#[rustler::nif]
+pub fn pin_ark<'a>(env: Env<'a>, product: Term<'a>, pin: Binary<'a>) -> NifResult<Term<'a>> {
+    let result = decode_product(product)
+        .map_err(|_| SnpError::invalid_argument("product", "unknown product line"))
+        .and_then(|product| add(product, decode_pin(pin.as_slice())?));
+    Ok(encode_unit(env, result))
+}
+
+/// Removes an ARK added with `pin_ark/2`. Embedded pins are unaffected.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `product` - The product line, as for `pin_ark/2`.
+/// * `pin` - The pin, in any of the forms accepted by `pin_ark/2`.
+///
+/// # Returns
+/// `ok`, whether or not the pin was present, or `{error, {Kind, Detail}}`.
+///
+/// # Example
+/// ```erlang
+/// ok = dev_snp_nif:unpin_ark(genoa, GenoaArkPem).
+/// ```
+#[rustler::nif]
+pub fn unpin_ark<'a>(env: Env<'a>, product: Term<'a>, pin: Binary<'a>) -> NifResult<Term<'a>> {
+    let result = decode_product(product)
+        .map_err(|_| SnpError::invalid_argument("product", "unknown product line"))
+        .and_then(|product| remove(product, decode_pin(pin.as_slice())?));
+    Ok(encode_unit(env, result))
+}
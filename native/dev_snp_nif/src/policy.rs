@@ -0,0 +1,324 @@
+use rustler::{Binary, Encoder, Env, MapIterator, NifResult, Term};
+use sev::firmware::guest::AttestationReport;
+use crate::error::{encode_error, encode_result, SnpError, SnpResult};
+use crate::freshness::NonceBinding;
+use crate::helpers::{decode_string, SuppliedCerts};
+use crate::id_block::trusted_key_digests;
+use crate::logging::log_message;
+use crate::product::{decode_product, Product};
+use crate::report;
+use crate::tcb::{parse_min_tcb_policy, MinTcbPolicy};
+use crate::verification::check_signature;
+
+rustler::atoms! {
+    pass,
+    fail,
+    valid,
+    checks,
+}
+
+/// `GuestPolicy` bits that a policy can require or forbid, by name.
+const GUEST_POLICY_BITS: [(&str, u32); 4] = [
+    ("smt", 16),
+    ("migrate_ma", 18),
+    ("debug", 19),
+    ("single_socket", 20),
+];
+
+/// A declarative attestation policy. Only the checks that are set are evaluated.
+#[derive(Debug, Default)]
+pub struct Policy {
+    measurements: Option<Vec<[u8; 48]>>,
+    min_tcb: Option<MinTcbPolicy>,
+    guest_policy: Vec<(&'static str, u32, bool)>,
+    vmpl: Option<u32>,
+    family_ids: Option<Vec<[u8; 16]>>,
+    image_ids: Option<Vec<[u8; 16]>>,
+    id_keys: Option<Vec<[u8; 48]>>,
+    author_keys: Option<Vec<[u8; 48]>>,
+    host_data: Option<[u8; 32]>,
+    report_data: Option<[u8; 64]>,
+    nonce: Option<NonceBinding>,
+    signature: bool,
+    product: Option<Product>,
+    certs: SuppliedCerts,
+}
+
+/// The outcome of a single policy check: `Err` carries the rejection reason.
+pub type CheckResult = Result<(), String>;
+
+/// Decodes a fixed-size byte string given either raw or hex encoded.
+pub fn fixed_bytes<const N: usize>(field: &str, value: Term) -> SnpResult<[u8; N]> {
+    if let Ok(bin) = value.decode::<Binary>() {
+        if let Ok(raw) = bin.as_slice().try_into() {
+            return Ok(raw);
+        }
+    }
+    let bad_hex = || SnpError::BadHex {
+        field: field.to_string(),
+    };
+    let hex_str = decode_string(value).map_err(|_| bad_hex())?;
+    hex::decode(hex_str)
+        .map_err(|_| bad_hex())?
+        .try_into()
+        .map_err(|_| bad_hex())
+}
+
+/// Decodes a single byte string or a list of them.
+fn fixed_bytes_list<const N: usize>(field: &str, value: Term) -> SnpResult<Vec<[u8; N]>> {
+    match value.decode::<Vec<Term>>() {
+        Ok(items) => items.into_iter().map(|item| fixed_bytes(field, item)).collect(),
+        Err(_) => Ok(vec![fixed_bytes(field, value)?]),
+    }
+}
+
+/// Decodes a boolean atom.
+fn boolean(field: &str, value: Term) -> SnpResult<bool> {
+    value
+        .decode()
+        .map_err(|_| SnpError::invalid_argument(field, "expected a boolean"))
+}
+
+/// Decodes the `guest_policy` map of a policy.
+fn parse_guest_policy(value: Term) -> SnpResult<Vec<(&'static str, u32, bool)>> {
+    let map_iter = MapIterator::new(value)
+        .ok_or_else(|| SnpError::invalid_argument("guest_policy", "expected a map"))?;
+    let mut bits = Vec::new();
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("guest_policy", "keys must be atoms"))?;
+        let field = format!("guest_policy.{key_str}");
+        let (name, bit) = GUEST_POLICY_BITS
+            .iter()
+            .find(|(name, _)| *name == key_str)
+            .ok_or_else(|| SnpError::invalid_argument(&field, "unknown policy bit"))?;
+        bits.push((*name, *bit, boolean(&field, value)?));
+    }
+    Ok(bits)
+}
+
+/// Decodes an attestation policy passed from Erlang as a map.
+pub fn parse_policy(value: Term) -> SnpResult<Policy> {
+    let map_iter = MapIterator::new(value)
+        .ok_or_else(|| SnpError::invalid_argument("policy", "expected a map"))?;
+    let mut policy = Policy::default();
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("policy", "keys must be atoms"))?;
+        match key_str.as_str() {
+            "measurements" => policy.measurements = Some(fixed_bytes_list(&key_str, value)?),
+            "min_tcb" => policy.min_tcb = Some(parse_min_tcb_policy(&key_str, value)?),
+            "guest_policy" => policy.guest_policy = parse_guest_policy(value)?,
+            "vmpl" => {
+                policy.vmpl = Some(value.decode().map_err(|_| {
+                    SnpError::invalid_argument(&key_str, "expected an integer")
+                })?)
+            }
+            "family_ids" => policy.family_ids = Some(fixed_bytes_list(&key_str, value)?),
+            "image_ids" => policy.image_ids = Some(fixed_bytes_list(&key_str, value)?),
+            "id_keys" => policy.id_keys = Some(trusted_key_digests(&key_str, value)?),
+            "author_keys" => policy.author_keys = Some(trusted_key_digests(&key_str, value)?),
+            "host_data" => policy.host_data = Some(fixed_bytes(&key_str, value)?),
+            "report_data" => policy.report_data = Some(fixed_bytes(&key_str, value)?),
+            "nonce" => policy.nonce = Some(NonceBinding::decode(&key_str, value)?),
+            "signature" => policy.signature = boolean(&key_str, value)?,
+            "product" => {
+                policy.product = Some(decode_product(value).map_err(|_| {
+                    SnpError::invalid_argument(&key_str, "unknown product line")
+                })?)
+            }
+            "vlek" => {
+                let vlek: Binary = value
+                    .decode()
+                    .map_err(|_| SnpError::invalid_argument(&key_str, "expected a binary"))?;
+                policy.certs.add_vlek(vlek.as_slice())?
+            }
+            "certs" => policy.certs.add_map(&key_str, value)?,
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+    Ok(policy)
+}
+
+/// Checks that a value is one of the allowed values.
+fn allowed<const N: usize>(name: &str, actual: &[u8; N], allowed: &[[u8; N]]) -> CheckResult {
+    if allowed.contains(actual) {
+        Ok(())
+    } else {
+        Err(format!("{name} {} is not allowed", hex::encode(actual)))
+    }
+}
+
+/// Checks that a value equals the expected one.
+fn equal<const N: usize>(name: &str, actual: &[u8; N], expected: &[u8; N]) -> CheckResult {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected {name} {}, got {}",
+            hex::encode(expected),
+            hex::encode(actual)
+        ))
+    }
+}
+
+/// Checks the required and forbidden `GuestPolicy` bits.
+fn check_guest_policy(report: &AttestationReport, bits: &[(&str, u32, bool)]) -> CheckResult {
+    let violations: Vec<String> = bits
+        .iter()
+        .filter(|(_, bit, required)| ((report.policy.0 >> bit) & 1 == 1) != *required)
+        .map(|(name, _, required)| {
+            if *required {
+                format!("{name} must be set")
+            } else {
+                format!("{name} must not be set")
+            }
+        })
+        .collect();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations.join(", "))
+    }
+}
+
+/// Evaluates every check set in a policy against a report.
+///
+/// # Returns
+/// The name and outcome of each evaluated check, in a stable order.
+pub fn evaluate(report: &AttestationReport, policy: &Policy) -> Vec<(&'static str, CheckResult)> {
+    let mut results = Vec::new();
+    if let Some(measurements) = &policy.measurements {
+        results.push(("measurement", allowed("measurement", &report.measurement, measurements)));
+    }
+    if let Some(min_tcb) = &policy.min_tcb {
+        let product = Product::of_report(report, policy.product);
+        let violations = min_tcb.violations(report, product);
+        let result = if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("TCB below minimum: {}", violations.join(", ")))
+        };
+        results.push(("min_tcb", result));
+    }
+    if !policy.guest_policy.is_empty() {
+        results.push(("guest_policy", check_guest_policy(report, &policy.guest_policy)));
+    }
+    if let Some(vmpl) = policy.vmpl {
+        let result = if report.vmpl == vmpl {
+            Ok(())
+        } else {
+            Err(format!("expected VMPL {vmpl}, got {}", report.vmpl))
+        };
+        results.push(("vmpl", result));
+    }
+    if let Some(family_ids) = &policy.family_ids {
+        results.push(("family_id", allowed("family ID", &report.family_id, family_ids)));
+    }
+    if let Some(image_ids) = &policy.image_ids {
+        results.push(("image_id", allowed("image ID", &report.image_id, image_ids)));
+    }
+    if let Some(id_keys) = &policy.id_keys {
+        results.push(("id_key", allowed("ID key digest", &report.id_key_digest, id_keys)));
+    }
+    if let Some(author_keys) = &policy.author_keys {
+        let digest = &report.author_key_digest;
+        results.push(("author_key", allowed("author key digest", digest, author_keys)));
+    }
+    if let Some(host_data) = &policy.host_data {
+        results.push(("host_data", equal("host data", &report.host_data, host_data)));
+    }
+    if let Some(report_data) = &policy.report_data {
+        results.push(("report_data", equal("report data", &report.report_data, report_data)));
+    }
+    if policy.signature {
+        let result = check_signature(report, policy.product, &policy.certs).map_err(|err| err.to_string());
+        results.push(("signature", result));
+    }
+    // Last, so that the nonce is only consumed by a report that passes.
+    if let Some(nonce) = &policy.nonce {
+        let result = match nonce.is_bound(report) {
+            Ok(false) => Err("report data does not bind the nonce".to_string()),
+            Err(err) => Err(err.to_string()),
+            Ok(true) if results.iter().any(|(_, result)| result.is_err()) => {
+                Err("nonce not consumed, as other checks failed".to_string())
+            }
+            Ok(true) => nonce.consume(report).map_err(|err| err.to_string()),
+        };
+        results.push(("nonce", result));
+    }
+    results
+}
+
+/// Encodes check results as `#{ valid => Bool, checks => #{ Check => pass | {fail, Reason} } }`.
+fn encode_checks<'a>(env: Env<'a>, results: &[(&str, CheckResult)]) -> SnpResult<Term<'a>> {
+    let map_err = |_| SnpError::Serialization {
+        detail: "Failed to build policy result map".to_string(),
+    };
+    let mut checks_map = Term::map_new(env);
+    for (name, result) in results {
+        let key = rustler::Atom::from_str(env, name).map_err(map_err)?;
+        let value = match result {
+            Ok(()) => pass().encode(env),
+            Err(reason) => (fail(), reason).encode(env),
+        };
+        checks_map = checks_map.map_put(key, value).map_err(map_err)?;
+    }
+    let is_valid = results.iter().all(|(_, result)| result.is_ok());
+    Term::map_new(env)
+        .map_put(valid(), is_valid)
+        .and_then(|map| map.map_put(checks(), checks_map))
+        .map_err(map_err)
+}
+
+/// Evaluates an attestation policy against a report.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `report` - The attestation report, either raw or JSON encoded.
+/// * `policy` - An Erlang map describing the policy.
+///
+/// # Expected Policy Map Keys (all optional):
+/// - `"measurements"`: Allowed launch measurements (48 bytes each).
+/// - `"min_tcb"`: Minimum TCB of the reported, committed and launch TCB versions, as for
+///   `verify_signature/2`.
+/// - `"guest_policy"`: Required (`true`) or forbidden (`false`) policy bits: `debug`,
+///   `migrate_ma`, `smt` and `single_socket`.
+/// - `"vmpl"`: The required VMPL.
+/// - `"family_ids"`, `"image_ids"`: Allowed family and image IDs (16 bytes each).
+/// - `"id_keys"`, `"author_keys"`: Trusted keys that may have signed the ID
+///   block and the ID key, as PEM or DER keys or their digests (48 bytes).
+/// - `"host_data"`: The expected host data (32 bytes).
+/// - `"report_data"`: The expected report data (64 bytes).
+/// - `"nonce"`: A nonce from `issue_nonce/1` that the report must be bound
+///   to, as for `verify_signature/2`. It is consumed only if every other
+///   check passes.
+/// - `"signature"`: Whether to verify the report's signature (boolean).
+/// - `"product"`: The product line of the report, overriding the one derived from it.
+/// - `"vlek"`, `"certs"`: Supplied certificates, as for `verify_signature/2`.
+///
+/// Byte strings may be given raw or hex encoded, and lists of allowed values
+/// may also be given as a single value.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and a map with a `valid` boolean and the
+/// outcome of every evaluated check under `checks`, as either `pass` or
+/// `{fail, Reason}`.
+///
+/// # Example
+/// ```erlang
+/// {ok, #{ valid := true }} =
+///     dev_snp_nif:verify_report(Report, #{ guest_policy => #{ debug => false } }).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn verify_report<'a>(env: Env<'a>, report: Binary<'a>, policy: Term<'a>) -> NifResult<Term<'a>> {
+    let policy = match parse_policy(policy) {
+        Ok(policy) => policy,
+        Err(err) => return Ok(encode_error(env, &err)),
+    };
+    let result = report::parse(report.as_slice())
+        .and_then(|parsed| encode_checks(env, &evaluate(&parsed, &policy)));
+    Ok(encode_result(env, result))
+}
@@ -0,0 +1,158 @@
+use rustler::{Binary, Env, NifResult, Term};
+use sev::firmware::guest::AttestationReport;
+use sev::firmware::host::TcbVersion;
+use crate::error::encode_result;
+use crate::helpers::decode_string;
+use crate::report;
+
+rustler::atoms! {
+    milan,
+    genoa,
+    bergamo,
+    turin,
+}
+
+/// The EPYC product lines whose attestation reports can be verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Product {
+    Milan,
+    Genoa,
+    Bergamo,
+    Turin,
+}
+
+impl Product {
+    /// The product line assumed for reports that do not identify their CPU.
+    pub const DEFAULT: Product = Product::Milan;
+
+    /// Parses a product line name, case-insensitively. Siena parts are
+    /// reported as Bergamo, which shares its certificates with Genoa.
+    pub fn from_name(name: &str) -> Option<Product> {
+        match name.to_ascii_lowercase().as_str() {
+            "milan" => Some(Product::Milan),
+            "genoa" => Some(Product::Genoa),
+            "bergamo" | "siena" => Some(Product::Bergamo),
+            "turin" => Some(Product::Turin),
+            _ => None,
+        }
+    }
+
+    /// Maps the combined CPUID family and model IDs to a product line.
+    pub fn from_cpuid(family: u8, model: u8) -> Option<Product> {
+        match (family, model) {
+            (0x19, 0x00..=0x0F) => Some(Product::Milan),
+            (0x19, 0x10..=0x1F) => Some(Product::Genoa),
+            (0x19, 0xA0..=0xAF) => Some(Product::Bergamo),
+            (0x1A, 0x00..=0x11) => Some(Product::Turin),
+            _ => None,
+        }
+    }
+
+    /// Derives the product line from the CPUID fields of a report. These are
+    /// only populated from report version 3 onwards, at offsets 0x188 (family)
+    /// and 0x189 (model), which the SEV crate exposes as `_reserved_1`.
+    pub fn from_report(version: u32, reserved_1: &[u8; 24]) -> Option<Product> {
+        if version < 3 {
+            return None;
+        }
+        Product::from_cpuid(reserved_1[0], reserved_1[1])
+    }
+
+    /// The product line of a report: `product` if given, otherwise the one
+    /// derived from the report, falling back to `DEFAULT`.
+    pub fn of_report(report: &AttestationReport, product: Option<Product>) -> Product {
+        product
+            .or_else(|| Product::from_report(report.version, &report._reserved_1))
+            .unwrap_or(Product::DEFAULT)
+    }
+
+    /// The name under which the KDS serves this product line's certificates.
+    pub fn kds_name(&self) -> &'static str {
+        match self {
+            Product::Milan => "Milan",
+            // Bergamo and Siena VCEKs are issued under the Genoa ARK/ASK.
+            Product::Genoa | Product::Bergamo => "Genoa",
+            Product::Turin => "Turin",
+        }
+    }
+
+    /// The hardware ID used to look up a VCEK. Turin only uses the first
+    /// eight bytes of the chip ID.
+    pub fn hw_id(&self, chip_id: &[u8; 64]) -> String {
+        match self {
+            Product::Turin => hex::encode(&chip_id[..8]),
+            _ => hex::encode(chip_id),
+        }
+    }
+
+    /// The KDS query string describing a TCB version. Turin adds an FMC
+    /// component and lays out the TCB bytes differently from earlier parts.
+    pub fn vcek_query(&self, tcb: &TcbVersion) -> String {
+        let raw = tcb_bytes(tcb);
+        match self {
+            Product::Turin => format!(
+                "fmcSPL={:02}&blSPL={:02}&teeSPL={:02}&snpSPL={:02}&ucodeSPL={:02}",
+                raw[0], raw[1], raw[2], raw[3], raw[7]
+            ),
+            _ => format!(
+                "blSPL={:02}&teeSPL={:02}&snpSPL={:02}&ucodeSPL={:02}",
+                raw[0], raw[1], raw[6], raw[7]
+            ),
+        }
+    }
+
+    /// Encodes the product line as an Erlang atom.
+    pub fn to_atom(self) -> rustler::Atom {
+        match self {
+            Product::Milan => milan(),
+            Product::Genoa => genoa(),
+            Product::Bergamo => bergamo(),
+            Product::Turin => turin(),
+        }
+    }
+}
+
+/// Returns the raw 8-byte encoding of a TCB version, as it appears in the report.
+pub fn tcb_bytes(tcb: &TcbVersion) -> [u8; 8] {
+    [
+        tcb.bootloader,
+        tcb.tee,
+        tcb._reserved[0],
+        tcb._reserved[1],
+        tcb._reserved[2],
+        tcb._reserved[3],
+        tcb.snp,
+        tcb.microcode,
+    ]
+}
+
+/// Decodes a product line passed from Erlang as an atom, binary or string.
+pub fn decode_product(value: Term) -> NifResult<Product> {
+    let name = match value.atom_to_string() {
+        Ok(name) => name,
+        Err(_) => decode_string(value)?,
+    };
+    Product::from_name(&name).ok_or(rustler::Error::BadArg)
+}
+
+/// Determines the product line of an attestation report.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `report` - The attestation report, either raw or JSON encoded.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and one of `milan`, `genoa`, `bergamo` or
+/// `turin`. Reports older than version 3 carry no CPUID information and are
+/// assumed to come from Milan.
+///
+/// # Example
+/// ```erlang
+/// {ok, milan} = dev_snp_nif:report_product(JsonReport).
+/// ```
+#[rustler::nif]
+pub fn report_product<'a>(env: Env<'a>, report: Binary<'a>) -> NifResult<Term<'a>> {
+    let result =
+        report::parse(report.as_slice()).map(|parsed| Product::of_report(&parsed, None).to_atom());
+    Ok(encode_result(env, result))
+}
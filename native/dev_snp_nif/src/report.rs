@@ -0,0 +1,358 @@
+use rustler::{Binary, Encoder, Env, NifResult, Term};
+use serde_json::Value;
+use sev::certs::snp::ecdsa::Signature;
+use sev::firmware::guest::{AttestationReport, GuestPolicy, PlatformInfo};
+use sev::firmware::host::TcbVersion;
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::helpers::encode_binary;
+use crate::product::tcb_bytes;
+
+rustler::atoms! {
+    json,
+    binary,
+}
+
+/// Size in bytes of an attestation report as returned by the firmware.
+pub const REPORT_SIZE: usize = 0x4A0;
+
+/// The oldest report format understood by the parser.
+const MIN_REPORT_VERSION: u32 = 2;
+/// The only signature algorithm defined by the SNP ABI (ECDSA P-384 with SHA-384).
+const SIG_ALGO_ECDSA_P384_SHA384: u32 = 1;
+/// The highest VMPL a report can be requested for.
+const MAX_VMPL: u32 = 3;
+
+/// Builds the error for a missing or malformed report field.
+fn invalid(field: &str, reason: impl Into<String>) -> SnpError {
+    SnpError::InvalidReport {
+        field: field.to_string(),
+        reason: reason.into(),
+    }
+}
+
+/// Parses a report given either as the raw firmware encoding or as JSON.
+///
+/// A raw report is exactly `REPORT_SIZE` bytes long and starts with its
+/// (little-endian) version number, so it can never begin with `{`.
+pub fn parse(input: &[u8]) -> SnpResult<AttestationReport> {
+    if input.len() == REPORT_SIZE && input[0] != b'{' {
+        return from_bytes(input);
+    }
+    let value: Value = serde_json::from_slice(input)
+        .map_err(|err| invalid("report", format!("not valid JSON: {}", err)))?;
+    from_json(&value)
+}
+
+/// Checks the fields whose values are constrained by the SNP ABI.
+fn validate(report: &AttestationReport) -> SnpResult<()> {
+    if report.version < MIN_REPORT_VERSION {
+        return Err(invalid(
+            "version",
+            format!("unsupported report version {}", report.version),
+        ));
+    }
+    if report.vmpl > MAX_VMPL {
+        return Err(invalid("vmpl", format!("VMPL {} out of range", report.vmpl)));
+    }
+    if report.sig_algo != SIG_ALGO_ECDSA_P384_SHA384 {
+        return Err(invalid(
+            "sig_algo",
+            format!("unknown signature algorithm {}", report.sig_algo),
+        ));
+    }
+    Ok(())
+}
+
+/// A cursor over the raw report, reading little-endian fields in order.
+struct Reader<'r> {
+    bytes: &'r [u8],
+    offset: usize,
+}
+
+impl<'r> Reader<'r> {
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut out = [0u8; N];
+        out.copy_from_slice(&self.bytes[self.offset..self.offset + N]);
+        self.offset += N;
+        out
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.array::<1>()[0]
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array())
+    }
+
+    fn tcb(&mut self) -> TcbVersion {
+        let raw: [u8; 8] = self.array();
+        TcbVersion {
+            bootloader: raw[0],
+            tee: raw[1],
+            _reserved: [raw[2], raw[3], raw[4], raw[5]],
+            snp: raw[6],
+            microcode: raw[7],
+        }
+    }
+}
+
+/// Parses the raw 1184-byte report produced by the SNP firmware.
+///
+/// # Errors
+/// Returns an error if the input has the wrong length or a field holds a
+/// value not permitted by the SNP ABI.
+pub fn from_bytes(raw: &[u8]) -> SnpResult<AttestationReport> {
+    if raw.len() != REPORT_SIZE {
+        return Err(invalid(
+            "report",
+            format!("expected {} bytes, got {}", REPORT_SIZE, raw.len()),
+        ));
+    }
+
+    let mut r = Reader { bytes: raw, offset: 0 };
+    let report = AttestationReport {
+        version: r.u32(),
+        guest_svn: r.u32(),
+        policy: GuestPolicy(r.u64()),
+        family_id: r.array(),
+        image_id: r.array(),
+        vmpl: r.u32(),
+        sig_algo: r.u32(),
+        current_tcb: r.tcb(),
+        plat_info: PlatformInfo(r.u64()),
+        _author_key_en: r.u32(),
+        _reserved_0: r.u32(),
+        report_data: r.array(),
+        measurement: r.array(),
+        host_data: r.array(),
+        id_key_digest: r.array(),
+        author_key_digest: r.array(),
+        report_id: r.array(),
+        report_id_ma: r.array(),
+        reported_tcb: r.tcb(),
+        _reserved_1: r.array(),
+        chip_id: r.array(),
+        committed_tcb: r.tcb(),
+        current_build: r.u8(),
+        current_minor: r.u8(),
+        current_major: r.u8(),
+        _reserved_2: r.u8(),
+        committed_build: r.u8(),
+        committed_minor: r.u8(),
+        committed_major: r.u8(),
+        _reserved_3: r.u8(),
+        launch_tcb: r.tcb(),
+        _reserved_4: r.array(),
+        signature: Signature {
+            r: r.array(),
+            s: r.array(),
+            _reserved: r.array(),
+        },
+    };
+    debug_assert_eq!(r.offset, REPORT_SIZE);
+
+    validate(&report)?;
+    Ok(report)
+}
+
+/// Serializes a report into the raw firmware encoding.
+pub fn to_bytes(report: &AttestationReport) -> Vec<u8> {
+    let mut out = Vec::with_capacity(REPORT_SIZE);
+    out.extend_from_slice(&report.version.to_le_bytes());
+    out.extend_from_slice(&report.guest_svn.to_le_bytes());
+    out.extend_from_slice(&report.policy.0.to_le_bytes());
+    out.extend_from_slice(&report.family_id);
+    out.extend_from_slice(&report.image_id);
+    out.extend_from_slice(&report.vmpl.to_le_bytes());
+    out.extend_from_slice(&report.sig_algo.to_le_bytes());
+    out.extend_from_slice(&tcb_bytes(&report.current_tcb));
+    out.extend_from_slice(&report.plat_info.0.to_le_bytes());
+    out.extend_from_slice(&report._author_key_en.to_le_bytes());
+    out.extend_from_slice(&report._reserved_0.to_le_bytes());
+    out.extend_from_slice(&report.report_data);
+    out.extend_from_slice(&report.measurement);
+    out.extend_from_slice(&report.host_data);
+    out.extend_from_slice(&report.id_key_digest);
+    out.extend_from_slice(&report.author_key_digest);
+    out.extend_from_slice(&report.report_id);
+    out.extend_from_slice(&report.report_id_ma);
+    out.extend_from_slice(&tcb_bytes(&report.reported_tcb));
+    out.extend_from_slice(&report._reserved_1);
+    out.extend_from_slice(&report.chip_id);
+    out.extend_from_slice(&tcb_bytes(&report.committed_tcb));
+    out.extend_from_slice(&[
+        report.current_build,
+        report.current_minor,
+        report.current_major,
+        report._reserved_2,
+        report.committed_build,
+        report.committed_minor,
+        report.committed_major,
+        report._reserved_3,
+    ]);
+    out.extend_from_slice(&tcb_bytes(&report.launch_tcb));
+    out.extend_from_slice(&report._reserved_4);
+    out.extend_from_slice(&report.signature.r);
+    out.extend_from_slice(&report.signature.s);
+    out.extend_from_slice(&report.signature._reserved);
+    out
+}
+
+/// The dotted path of a (possibly nested) field, used in error messages.
+fn path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}.{name}")
+    }
+}
+
+/// Looks up a field of a JSON object, failing if it is absent.
+fn field<'v>(value: &'v Value, parent: &str, name: &str) -> SnpResult<&'v Value> {
+    value
+        .get(name)
+        .ok_or_else(|| invalid(&path(parent, name), "missing"))
+}
+
+/// Reads an unsigned integer field that must fit in `T`.
+fn uint<T: TryFrom<u64>>(value: &Value, parent: &str, name: &str) -> SnpResult<T> {
+    let path = path(parent, name);
+    let number = field(value, parent, name)?
+        .as_u64()
+        .ok_or_else(|| invalid(&path, "not an unsigned integer"))?;
+    T::try_from(number).map_err(|_| invalid(&path, format!("{} out of range", number)))
+}
+
+/// Reads a fixed-length byte array field, encoded as a JSON array of bytes.
+fn bytes<const N: usize>(value: &Value, parent: &str, name: &str) -> SnpResult<[u8; N]> {
+    let path = path(parent, name);
+    let items = field(value, parent, name)?
+        .as_array()
+        .ok_or_else(|| invalid(&path, "not an array"))?;
+    if items.len() != N {
+        return Err(invalid(
+            &path,
+            format!("expected {} bytes, got {}", N, items.len()),
+        ));
+    }
+    let mut out = [0u8; N];
+    for (byte, item) in out.iter_mut().zip(items) {
+        *byte = item
+            .as_u64()
+            .and_then(|b| u8::try_from(b).ok())
+            .ok_or_else(|| invalid(&path, "contains a non-byte element"))?;
+    }
+    Ok(out)
+}
+
+/// Reads a TCB version object.
+fn tcb(value: &Value, name: &str) -> SnpResult<TcbVersion> {
+    let object = field(value, "", name)?;
+    if !object.is_object() {
+        return Err(invalid(name, "not an object"));
+    }
+    Ok(TcbVersion {
+        bootloader: uint(object, name, "bootloader")?,
+        tee: uint(object, name, "tee")?,
+        _reserved: bytes(object, name, "_reserved")?,
+        snp: uint(object, name, "snp")?,
+        microcode: uint(object, name, "microcode")?,
+    })
+}
+
+/// Parses a report from its JSON encoding (as produced by the SEV crate's
+/// `Serialize` implementation), requiring every field to be present and valid.
+///
+/// # Errors
+/// Returns an error naming the first field that is missing or malformed.
+pub fn from_json(value: &Value) -> SnpResult<AttestationReport> {
+    if !value.is_object() {
+        return Err(invalid("report", "not a JSON object"));
+    }
+
+    let signature = field(value, "", "signature")?;
+    let report = AttestationReport {
+        version: uint(value, "", "version")?,
+        guest_svn: uint(value, "", "guest_svn")?,
+        policy: GuestPolicy(uint(value, "", "policy")?),
+        family_id: bytes(value, "", "family_id")?,
+        image_id: bytes(value, "", "image_id")?,
+        vmpl: uint(value, "", "vmpl")?,
+        sig_algo: uint(value, "", "sig_algo")?,
+        current_tcb: tcb(value, "current_tcb")?,
+        plat_info: PlatformInfo(uint(value, "", "plat_info")?),
+        _author_key_en: uint(value, "", "_author_key_en")?,
+        _reserved_0: uint(value, "", "_reserved_0")?,
+        report_data: bytes(value, "", "report_data")?,
+        measurement: bytes(value, "", "measurement")?,
+        host_data: bytes(value, "", "host_data")?,
+        id_key_digest: bytes(value, "", "id_key_digest")?,
+        author_key_digest: bytes(value, "", "author_key_digest")?,
+        report_id: bytes(value, "", "report_id")?,
+        report_id_ma: bytes(value, "", "report_id_ma")?,
+        reported_tcb: tcb(value, "reported_tcb")?,
+        _reserved_1: bytes(value, "", "_reserved_1")?,
+        chip_id: bytes(value, "", "chip_id")?,
+        committed_tcb: tcb(value, "committed_tcb")?,
+        current_build: uint(value, "", "current_build")?,
+        current_minor: uint(value, "", "current_minor")?,
+        current_major: uint(value, "", "current_major")?,
+        _reserved_2: uint(value, "", "_reserved_2")?,
+        committed_build: uint(value, "", "committed_build")?,
+        committed_minor: uint(value, "", "committed_minor")?,
+        committed_major: uint(value, "", "committed_major")?,
+        _reserved_3: uint(value, "", "_reserved_3")?,
+        launch_tcb: tcb(value, "launch_tcb")?,
+        _reserved_4: bytes(value, "", "_reserved_4")?,
+        signature: Signature {
+            r: bytes(signature, "signature", "r")?,
+            s: bytes(signature, "signature", "s")?,
+            _reserved: bytes(signature, "signature", "_reserved")?,
+        },
+    };
+
+    validate(&report)?;
+    Ok(report)
+}
+
+/// Converts an attestation report between its raw and JSON encodings.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `report` - The report, either raw (1184 bytes) or JSON encoded.
+/// * `format` - The target encoding: `binary` or `json`.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the re-encoded report, or
+/// `{error, {invalid_report, Field}}` if the input could not be parsed.
+///
+/// # Example
+/// ```erlang
+/// {ok, RawReport} = dev_snp_nif:convert_report(JsonReport, binary).
+/// ```
+#[rustler::nif]
+pub fn convert_report<'a>(
+    env: Env<'a>,
+    report: Binary<'a>,
+    format: rustler::Atom,
+) -> NifResult<Term<'a>> {
+    let result = parse(report.as_slice()).and_then(|parsed| {
+        if format == binary() {
+            encode_binary(env, &to_bytes(&parsed))
+        } else if format == json() {
+            serde_json::to_string(&parsed)
+                .map(|report_json| report_json.encode(env))
+                .map_err(|err| SnpError::Serialization {
+                    detail: format!("Failed to serialize attestation report: {:?}", err),
+                })
+        } else {
+            Err(SnpError::invalid_argument("format", "expected `binary` or `json`"))
+        }
+    });
+    Ok(encode_result(env, result))
+}
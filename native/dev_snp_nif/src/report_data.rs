@@ -0,0 +1,164 @@
+use openssl::hash::{hash, MessageDigest};
+use rustler::{Binary, Env, NifResult, Term};
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::firmware;
+use crate::helpers::encode_binary;
+use crate::report;
+
+/// Prefix of the hashed binding, so that its digests cannot be confused with
+/// other uses of the same hash.
+const DOMAIN: &[u8] = b"dev_snp_nif/report_data/v1";
+
+/// How the fields bound into a report are turned into its 64-byte
+/// `report_data`.
+#[derive(Clone, Copy)]
+pub enum Binding {
+    /// The fields concatenated, zero-padded to 64 bytes. Only unambiguous
+    /// for fixed-length fields.
+    Raw,
+    /// A digest of `DOMAIN` and the length-prefixed fields, zero-padded to
+    /// 64 bytes.
+    Hash(MessageDigest),
+}
+
+impl Binding {
+    /// Decodes a binding from `none`, `sha256`, `sha384` or `sha512`.
+    pub fn decode(value: Term) -> SnpResult<Binding> {
+        let invalid = || SnpError::invalid_argument("hash", "expected none, sha256, sha384 or sha512");
+        match value.atom_to_string().map_err(|_| invalid())?.as_str() {
+            "none" => Ok(Binding::Raw),
+            "sha256" => Ok(Binding::Hash(MessageDigest::sha256())),
+            "sha384" => Ok(Binding::Hash(MessageDigest::sha384())),
+            "sha512" => Ok(Binding::Hash(MessageDigest::sha512())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Computes the `report_data` binding the given fields.
+///
+/// # Errors
+/// Returns `InvalidArgument` for `fields` if raw fields exceed 64 bytes.
+pub fn compute(fields: &[&[u8]], binding: Binding) -> SnpResult<[u8; 64]> {
+    let bound = match binding {
+        Binding::Raw => fields.concat(),
+        Binding::Hash(digest) => {
+            let mut input = DOMAIN.to_vec();
+            for field in fields {
+                input.extend((field.len() as u64).to_be_bytes());
+                input.extend_from_slice(field);
+            }
+            hash(digest, &input)
+                .map_err(|err| SnpError::Serialization {
+                    detail: format!("Failed to hash report data: {err}"),
+                })?
+                .to_vec()
+        }
+    };
+    if bound.len() > 64 {
+        return Err(SnpError::invalid_argument(
+            "fields",
+            format!("{} bytes do not fit in report_data", bound.len()),
+        ));
+    }
+    let mut report_data = [0u8; 64];
+    report_data[..bound.len()].copy_from_slice(&bound);
+    Ok(report_data)
+}
+
+/// Decodes the list of fields passed from Erlang.
+fn decode_fields<'a>(fields: Term<'a>) -> SnpResult<Vec<Binary<'a>>> {
+    fields
+        .decode()
+        .map_err(|_| SnpError::invalid_argument("fields", "expected a list of binaries"))
+}
+
+/// Decodes the fields and binding of a NIF call and computes `report_data`.
+fn compute_from_terms(fields: Term, hash: Term) -> SnpResult<[u8; 64]> {
+    let fields = decode_fields(fields)?;
+    let fields: Vec<&[u8]> = fields.iter().map(|field| field.as_slice()).collect();
+    compute(&fields, Binding::decode(hash)?)
+}
+
+/// Computes the `report_data` that binds a list of fields into a report.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `fields` - The fields to bind, as a list of binaries of any length.
+/// * `hash` - `sha256`, `sha384` or `sha512` to hash the length-prefixed
+///   fields, or `none` to concatenate them (at most 64 bytes in total).
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the 64-byte `report_data`, zero-padded.
+///
+/// # Example
+/// ```erlang
+/// {ok, ReportData} = dev_snp_nif:compute_report_data([Address, NodeMsgID], none).
+/// ```
+#[rustler::nif]
+pub fn compute_report_data<'a>(env: Env<'a>, fields: Term<'a>, hash: Term<'a>) -> NifResult<Term<'a>> {
+    let result = compute_from_terms(fields, hash).and_then(|data| encode_binary(env, &data));
+    Ok(encode_result(env, result))
+}
+
+/// Generates an attestation report whose `report_data` binds a list of
+/// fields, as computed by `compute_report_data/2`.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `fields` - The fields to bind, as a list of binaries.
+/// * `hash` - The binding, as for `compute_report_data/2`.
+/// * `vmpl` - The VMPL to request the report for.
+///
+/// # Returns
+/// The same result as `generate_attestation_report/2`.
+///
+/// # Example
+/// ```erlang
+/// {ok, JsonReport} = dev_snp_nif:generate_bound_report([Address, NodeMsgID], sha512, 1).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn generate_bound_report<'a>(
+    env: Env<'a>,
+    fields: Term<'a>,
+    hash: Term<'a>,
+    vmpl: u32,
+) -> NifResult<Term<'a>> {
+    let result = compute_from_terms(fields, hash).and_then(|report_data| {
+        let report = firmware::open()?.get_report(report_data, vmpl)?;
+        serde_json::to_string(&report).map_err(|err| SnpError::Serialization {
+            detail: format!("Failed to serialize attestation report: {:?}", err),
+        })
+    });
+    Ok(encode_result(env, result))
+}
+
+/// Checks that the `report_data` of a report binds a list of fields.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `report` - The attestation report, either raw or JSON encoded.
+/// * `fields` - The fields the report is expected to bind.
+/// * `hash` - The binding, as for `compute_report_data/2`.
+///
+/// # Returns
+/// `{ok, true}` if the report binds the fields, `{ok, false}` if it does
+/// not, or `{error, {Kind, Detail}}` if the arguments are invalid.
+///
+/// # Example
+/// ```erlang
+/// {ok, true} = dev_snp_nif:verify_report_data(JsonReport, [Address, NodeMsgID], sha512).
+/// ```
+#[rustler::nif]
+pub fn verify_report_data<'a>(
+    env: Env<'a>,
+    report: Binary<'a>,
+    fields: Term<'a>,
+    hash: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let result = report::parse(report.as_slice()).and_then(|parsed| {
+        let expected = compute_from_terms(fields, hash)?;
+        Ok(parsed.report_data == expected)
+    });
+    Ok(encode_result(env, result))
+}
@@ -0,0 +1,350 @@
+use std::path::Path;
+#[cfg(feature = "software-sealing")]
+use openssl::hash::{hash, MessageDigest};
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rustler::{Binary, Env, MapIterator, NifResult, Term};
+use crate::derived_key::{self, parse_key_request, resolve_tcb_version, select_field, KeyRequest, RootKey};
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::firmware::DEVICE_PATH;
+use crate::helpers::encode_binary;
+use crate::logging::log_message;
+
+rustler::atoms! {
+    fields,
+    software_fallback,
+}
+
+/// Identifies a sealed blob, followed by the version of its layout.
+const MAGIC: &[u8; 4] = b"SNPS";
+const VERSION: u8 = 1;
+
+/// Sealed blobs are laid out as the header, the AES-256-GCM ciphertext and
+/// its tag. The header is authenticated as associated data:
+///
+/// | Offset | Size | Field                                  |
+/// |--------|------|----------------------------------------|
+/// | 0      | 4    | `MAGIC`                                |
+/// | 4      | 1    | `VERSION`                              |
+/// | 5      | 1    | Key backend (0 firmware, 1 software)   |
+/// | 6      | 1    | Root key (0 VCEK, 1 VMRK)              |
+/// | 7      | 1    | VMPL                                   |
+/// | 8      | 8    | Guest field select (little endian)     |
+/// | 16     | 4    | Guest SVN (little endian)              |
+/// | 20     | 8    | TCB version (little endian)            |
+/// | 28     | 12   | Nonce                                  |
+const HEADER_LEN: usize = 40;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Root secret of the software key derivation. Only compiled in with the
+/// `software-sealing` feature.
+///
+/// **WARNING: this is a public constant. Anything sealed with the software
+/// fallback can be unsealed by anyone who has the blob, on any machine. It
+/// offers no confidentiality at all, and exists only so that tests can run
+/// without `/dev/sev-guest`. Never seal real secrets with
+/// `software_fallback => true`, and never enable the feature in production.**
+#[cfg(feature = "software-sealing")]
+const SOFTWARE_ROOT_KEY: &[u8] = b"dev_snp_nif software sealing root key";
+
+/// Where the sealing key of a blob comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    /// The SNP firmware's key derivation.
+    Firmware,
+    /// A software derivation, for tests on machines without SNP.
+    Software,
+}
+
+/// The binding of a sealed blob: how its key is derived, and its nonce.
+struct Header {
+    backend: Backend,
+    request: KeyRequest,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[0..4].copy_from_slice(MAGIC);
+        out[4] = VERSION;
+        out[5] = match self.backend {
+            Backend::Firmware => 0,
+            Backend::Software => 1,
+        };
+        out[6] = match self.request.root_key {
+            RootKey::Vcek => 0,
+            RootKey::Vmrk => 1,
+        };
+        out[7] = self.request.vmpl as u8;
+        out[8..16].copy_from_slice(&self.request.fields.to_le_bytes());
+        out[16..20].copy_from_slice(&self.request.guest_svn.to_le_bytes());
+        out[20..28].copy_from_slice(&self.request.tcb_version.to_le_bytes());
+        out[28..40].copy_from_slice(&self.nonce);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> SnpResult<Header> {
+        let malformed = |reason: &str| SnpError::invalid_argument("blob", reason);
+        if bytes.len() < HEADER_LEN + TAG_LEN || bytes[0..4] != MAGIC[..] {
+            return Err(malformed("not a sealed blob"));
+        }
+        if bytes[4] != VERSION {
+            return Err(malformed("unsupported version"));
+        }
+        let backend = match bytes[5] {
+            0 => Backend::Firmware,
+            1 => Backend::Software,
+            _ => return Err(malformed("unknown key backend")),
+        };
+        let root_key = match bytes[6] {
+            0 => RootKey::Vcek,
+            1 => RootKey::Vmrk,
+            _ => return Err(malformed("unknown root key")),
+        };
+        let le_u64 = |range: std::ops::Range<usize>| {
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(&bytes[range]);
+            u64::from_le_bytes(raw)
+        };
+        let mut guest_svn = [0u8; 4];
+        guest_svn.copy_from_slice(&bytes[16..20]);
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&bytes[28..40]);
+        Ok(Header {
+            backend,
+            request: KeyRequest {
+                root_key,
+                fields: le_u64(8..16),
+                vmpl: bytes[7] as u32,
+                guest_svn: u32::from_le_bytes(guest_svn),
+                tcb_version: le_u64(20..28),
+            },
+            nonce,
+        })
+    }
+}
+
+/// Whether the software key derivation may be used: the caller opted in with
+/// `software_fallback`, the NIF was built with the `software-sealing`
+/// feature, and there is no `/dev/sev-guest`, whose keys must be used if
+/// present. Opting in without the feature, and without the device, is an
+/// error.
+fn software_allowed(opted_in: bool) -> SnpResult<bool> {
+    if !opted_in || Path::new(DEVICE_PATH).exists() {
+        return Ok(false);
+    }
+    if !cfg!(feature = "software-sealing") {
+        return Err(SnpError::invalid_argument(
+            "software_fallback",
+            "the NIF is built without the software-sealing feature",
+        ));
+    }
+    Ok(true)
+}
+
+/// Derives the sealing key for a header. Software keys depend only on the
+/// key request, so they bind nothing about the guest.
+fn sealing_key(header: &Header) -> SnpResult<[u8; 32]> {
+    match header.backend {
+        Backend::Firmware => derived_key::derive(&header.request),
+        Backend::Software => software_key(header),
+    }
+}
+
+/// Derives a software sealing key from `SOFTWARE_ROOT_KEY`.
+#[cfg(feature = "software-sealing")]
+fn software_key(header: &Header) -> SnpResult<[u8; 32]> {
+    let encoded = header.encode();
+    let input = [SOFTWARE_ROOT_KEY, &encoded[4..28]].concat();
+    let digest = hash(MessageDigest::sha256(), &input).map_err(|err| SnpError::Seal {
+        detail: format!("Software key derivation failed: {err}"),
+    })?;
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&digest);
+    Ok(key)
+}
+
+/// Without the `software-sealing` feature, software blobs cannot be made or
+/// opened.
+#[cfg(not(feature = "software-sealing"))]
+fn software_key(_header: &Header) -> SnpResult<[u8; 32]> {
+    Err(SnpError::Unseal {
+        detail: "the NIF is built without the software-sealing feature".to_string(),
+    })
+}
+
+/// Decodes the options of `unseal/2`: whether blobs sealed in software are
+/// accepted.
+fn parse_unseal_opts(opts: Term) -> SnpResult<bool> {
+    let map_iter = MapIterator::new(opts)
+        .ok_or_else(|| SnpError::invalid_argument("opts", "expected a map"))?;
+    let mut allow_software = false;
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        match key_str.as_str() {
+            "software_fallback" => {
+                allow_software = value.decode().map_err(|_| {
+                    SnpError::invalid_argument("software_fallback", "expected a boolean")
+                })?
+            }
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+    Ok(allow_software)
+}
+
+/// Decodes a sealing policy: the options of `derive_key/1`, plus
+/// `software_fallback`. The key is bound to the measurement and guest policy
+/// unless `fields` is given. The `tcb_version`, if given, is returned
+/// separately, as it is only resolved once the key backend is known.
+fn parse_seal_policy(policy: Term) -> SnpResult<(KeyRequest, Option<u64>, bool)> {
+    let mut key_opts = policy;
+    let mut allow_software = false;
+    if let Ok(value) = policy.map_get(software_fallback()) {
+        allow_software = value.decode().map_err(|_| {
+            SnpError::invalid_argument("software_fallback", "expected a boolean")
+        })?;
+        key_opts = policy
+            .map_remove(software_fallback())
+            .map_err(|_| SnpError::invalid_argument("policy", "expected a map"))?;
+    }
+    let (mut request, tcb_version) = parse_key_request(key_opts)?;
+    if key_opts.map_get(fields()).is_err() {
+        select_field(&mut request.fields, "measurement")?;
+        select_field(&mut request.fields, "guest_policy")?;
+    }
+    Ok((request, tcb_version, allow_software))
+}
+
+/// Encrypts data under a key bound to the guest's identity.
+fn seal_data(
+    data: &[u8],
+    mut request: KeyRequest,
+    tcb_version: Option<u64>,
+    allow_software: bool,
+) -> SnpResult<Vec<u8>> {
+    let backend = if software_allowed(allow_software)? {
+        Backend::Software
+    } else {
+        Backend::Firmware
+    };
+    match backend {
+        Backend::Firmware => resolve_tcb_version(&mut request, tcb_version)?,
+        // There is no current TCB to read, and software keys bind nothing.
+        Backend::Software => request.tcb_version = tcb_version.unwrap_or(0),
+    }
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce).map_err(|err| SnpError::Internal {
+        detail: format!("Failed to generate nonce: {err}"),
+    })?;
+    let header = Header {
+        backend,
+        request,
+        nonce,
+    };
+    let key = sealing_key(&header)?;
+    let aad = header.encode();
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &key, Some(&nonce), &aad, data, &mut tag)
+        .map_err(|err| SnpError::Seal {
+            detail: format!("Encryption failed: {err}"),
+        })?;
+    Ok([&aad[..], &ciphertext[..], &tag[..]].concat())
+}
+
+/// Decrypts a sealed blob, re-deriving its key from the binding in its header.
+/// Software blobs are refused unless `allow_software` is set.
+fn unseal_data(blob: &[u8], allow_software: bool) -> SnpResult<Vec<u8>> {
+    let header = Header::decode(blob)?;
+    if header.backend == Backend::Software && !software_allowed(allow_software)? {
+        return Err(SnpError::Unseal {
+            detail: "blob was sealed with the software key derivation".to_string(),
+        });
+    }
+    let key = sealing_key(&header)?;
+    let (ciphertext, tag) = blob[HEADER_LEN..].split_at(blob.len() - HEADER_LEN - TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(&header.nonce),
+        &blob[..HEADER_LEN],
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| SnpError::Unseal {
+        detail: "blob was modified or sealed to a different identity".to_string(),
+    })
+}
+
+/// Seals data to the guest's measured identity. The data is encrypted with
+/// AES-256-GCM under a key derived by the SNP firmware (see `derive_key/1`),
+/// and can only be unsealed by a guest for which the same key request yields
+/// the same key: on the same chip, with the same values of the bound fields.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `data` - The data to seal.
+/// * `policy` - An Erlang map describing the binding.
+///
+/// # Expected Policy Map Keys (all optional):
+/// - The keys of `derive_key/1`. The key is bound to `measurement` and
+///   `guest_policy` unless `"fields"` is given; add `tcb_version` to bind to
+///   the TCB, by default the current one.
+/// - `"software_fallback"`: Whether to derive the key in software if
+///   `/dev/sev-guest` is absent (default `false`). Only available when the
+///   NIF is built with the `software-sealing` feature. Such blobs are
+///   encrypted under a key derived from a public constant, so anyone can
+///   unseal them; `unseal/2` refuses them on SNP guests and unless asked to
+///   accept them, and they are only meant for tests.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the sealed blob, whose header records
+/// the binding, or `{error, {Kind, Detail}}`.
+///
+/// # Example
+/// ```erlang
+/// {ok, Blob} = dev_snp_nif:seal(WalletKey, #{ fields => [measurement, tcb_version] }).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn seal<'a>(env: Env<'a>, data: Binary<'a>, policy: Term<'a>) -> NifResult<Term<'a>> {
+    let result = parse_seal_policy(policy)
+        .and_then(|(request, tcb_version, allow_software)| {
+            seal_data(data.as_slice(), request, tcb_version, allow_software)
+        })
+        .and_then(|blob| encode_binary(env, &blob));
+    Ok(encode_result(env, result))
+}
+
+/// Unseals a blob produced by `seal/2`.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `blob` - The sealed blob.
+/// * `opts` - An Erlang map of options (`unseal/1` passes none).
+///
+/// # Expected Input Map Keys (all optional):
+/// - `"software_fallback"`: Whether to accept blobs sealed in software
+///   (default `false`), as for `seal/2`. They are still refused on SNP guests.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and the original data, or
+/// `{error, {unseal_failed, Detail}}` if the blob was modified or sealed to a
+/// different identity, or `{error, {invalid_argument, <<"blob">>}}` if it is
+/// not a sealed blob.
+///
+/// # Example
+/// ```erlang
+/// {ok, WalletKey} = dev_snp_nif:unseal(Blob),
+/// {ok, TestKey} = dev_snp_nif:unseal(TestBlob, #{ software_fallback => true }).
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn unseal<'a>(env: Env<'a>, blob: Binary<'a>, opts: Term<'a>) -> NifResult<Term<'a>> {
+    let result = parse_unseal_opts(opts)
+        .and_then(|allow_software| unseal_data(blob.as_slice(), allow_software))
+        .and_then(|data| encode_binary(env, &data));
+    Ok(encode_result(env, result))
+}
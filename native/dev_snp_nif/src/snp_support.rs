@@ -1,7 +1,14 @@
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::Path;
 use rustler::{Encoder, Env, NifResult, Term};
-use rustler::types::atom::ok;
-use sev::firmware::guest::Firmware;
+use rustler::types::atom::{error, ok};
+use crate::derived_key::KeyRequest;
+use crate::error::{encode_result, SnpError, SnpResult};
+use crate::firmware::{self, GuestFirmware, DEVICE_PATH};
 use crate::logging::log_message;
+use crate::product::Product;
+use crate::tcb::Tcb;
 
 /// Checks if Secure Nested Paging (SNP) is supported by the system.
 ///
@@ -17,24 +24,24 @@ use crate::logging::log_message;
 /// ```erlang
 /// {ok, Supported} = dev_snp_nif:check_snp_support().
 /// ```
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn check_snp_support<'a>(env: Env<'a>) -> NifResult<Term<'a>> {
-    //log_message("INFO", file!(), line!(), "Checking SNP support...");
+    log_message("DEBUG", file!(), line!(), "Checking SNP support...");
 
     // Step 1: Attempt to open the firmware interface.
     // If the firmware is accessible, SNP is supported; otherwise, it is not.
-    let is_supported = match Firmware::open() {
+    let is_supported = match firmware::open() {
         Ok(_) => {
-            //log_message("INFO", file!(), line!(), "SNP is supported.");
+            log_message("DEBUG", file!(), line!(), "SNP is supported.");
             true // SNP is supported.
         }
         Err(_) => {
-            // log_message(
-            //     "ERROR",
-            //     file!(),
-            //     line!(),
-            //     "Failed to open firmware. SNP is not supported.",
-            // );
+            log_message(
+                "DEBUG",
+                file!(),
+                line!(),
+                "Failed to open firmware. SNP is not supported.",
+            );
             false // SNP is not supported.
         }
     };
@@ -42,3 +49,158 @@ pub fn check_snp_support<'a>(env: Env<'a>) -> NifResult<Term<'a>> {
     // Step 2: Return the result as a tuple with the `ok` atom and the boolean value.
     Ok((ok(), is_supported).encode(env))
 }
+
+/// What the probe found out about the guest firmware.
+#[derive(Debug, Default)]
+struct Probe {
+    device_present: bool,
+    /// The outcome of opening the device: `None` on success, or the kind
+    /// (`not_found`, `permission_denied` or `other`) and description of the
+    /// error.
+    open_error: Option<(&'static str, String)>,
+    backend: Option<&'static str>,
+    report_version: Option<u32>,
+    firmware_version: Option<String>,
+    product: Option<Product>,
+    current_tcb: Option<Tcb>,
+    vmpls: Vec<u32>,
+    extended_report: bool,
+    derived_key: bool,
+}
+
+/// Classifies the error from opening the guest device.
+fn open_error_kind(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::NotFound => "not_found",
+        ErrorKind::PermissionDenied => "permission_denied",
+        _ => "other",
+    }
+}
+
+/// Exercises the firmware: which VMPLs reports can be requested for, and
+/// whether extended reports and derived keys work.
+fn probe_firmware(probe: &mut Probe, firmware: &mut dyn GuestFirmware) {
+    probe.backend = Some(firmware.backend());
+    let unique_data = [0u8; 64];
+    for vmpl in 0..=3 {
+        let report = match firmware.get_report(unique_data, vmpl) {
+            Ok(report) => report,
+            Err(_) => continue,
+        };
+        if probe.vmpls.is_empty() {
+            let product = Product::of_report(&report, None);
+            probe.report_version = Some(report.version);
+            probe.firmware_version = Some(format!(
+                "{}.{}.{}",
+                report.current_major, report.current_minor, report.current_build
+            ));
+            probe.product = Some(product);
+            probe.current_tcb = Some(Tcb::new(product, &report.current_tcb));
+        }
+        probe.vmpls.push(vmpl);
+    }
+    // A guest can only request reports and keys for its own VMPL or above.
+    let vmpl = match probe.vmpls.first() {
+        Some(vmpl) => *vmpl,
+        None => return,
+    };
+    probe.extended_report = firmware.get_ext_report(unique_data, vmpl).is_ok();
+    let request = KeyRequest {
+        vmpl,
+        ..KeyRequest::default()
+    };
+    probe.derived_key = firmware.get_derived_key(&request).is_ok();
+}
+
+/// Probes the guest device and firmware.
+fn probe() -> Probe {
+    let mut probe = Probe {
+        device_present: Path::new(DEVICE_PATH).exists(),
+        ..Probe::default()
+    };
+    if let Err(err) = OpenOptions::new().read(true).write(true).open(DEVICE_PATH) {
+        probe.open_error = Some((open_error_kind(err.kind()), err.to_string()));
+    }
+    // With the `mock-firmware` feature this succeeds even without the device.
+    if let Ok(mut firmware) = firmware::open() {
+        probe_firmware(&mut probe, firmware.as_mut());
+    }
+    probe
+}
+
+/// Encodes a probe as an Erlang map, omitting what could not be determined.
+fn encode_probe<'a>(env: Env<'a>, probe: &Probe) -> SnpResult<Term<'a>> {
+    let map_err = |_| SnpError::Serialization {
+        detail: "Failed to build probe map".to_string(),
+    };
+    let atom = |name: &str| rustler::Atom::from_str(env, name).map_err(map_err);
+    let mut entries: Vec<(&str, Term<'a>)> = vec![
+        ("device", DEVICE_PATH.encode(env)),
+        ("device_present", probe.device_present.encode(env)),
+    ];
+    let open = match &probe.open_error {
+        None => ok().encode(env),
+        Some((kind, detail)) => (error(), (atom(kind)?, detail)).encode(env),
+    };
+    entries.push(("open", open));
+    if let Some(backend) = probe.backend {
+        entries.push(("backend", atom(backend)?.encode(env)));
+        entries.push(("vmpls", probe.vmpls.encode(env)));
+        entries.push(("extended_report", probe.extended_report.encode(env)));
+        entries.push(("derived_key", probe.derived_key.encode(env)));
+    }
+    if let Some(version) = probe.report_version {
+        entries.push(("report_version", version.encode(env)));
+    }
+    if let Some(version) = &probe.firmware_version {
+        entries.push(("firmware_version", version.encode(env)));
+    }
+    if let Some(product) = probe.product {
+        entries.push(("product", product.to_atom().encode(env)));
+    }
+    if let Some(tcb) = &probe.current_tcb {
+        let mut tcb_map = Term::map_new(env);
+        for (component, level) in tcb.components() {
+            tcb_map = tcb_map.map_put(atom(component)?, level).map_err(map_err)?;
+        }
+        entries.push(("current_tcb", tcb_map));
+    }
+
+    let mut map = Term::map_new(env);
+    for (key, value) in entries {
+        map = map.map_put(atom(key)?, value).map_err(map_err)?;
+    }
+    Ok(map)
+}
+
+/// Describes the SNP capabilities of the machine, to diagnose nodes on which
+/// `check_snp_support/0` returns `false`.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+///
+/// # Returns
+/// A tuple containing an `ok` atom and a map with the keys:
+/// - `device`: The path of the guest device (`/dev/sev-guest`).
+/// - `device_present`: Whether the device exists.
+/// - `open`: `ok` if the device could be opened, or
+///   `{error, {not_found | permission_denied | other, Detail}}`.
+///
+/// If the firmware could be opened, also:
+/// - `backend`: `sev_guest`, or `mock` with the `mock-firmware` feature.
+/// - `vmpls`: The VMPLs reports can be requested for.
+/// - `extended_report`, `derived_key`: Whether these requests succeed.
+///
+/// And if a report could be requested, also `report_version`,
+/// `firmware_version` (e.g. `<<"1.55.21">>`), `product` and `current_tcb`
+/// (a map of component SPLs).
+///
+/// # Example
+/// ```erlang
+/// {ok, #{ open := {error, {permission_denied, _}} }} = dev_snp_nif:probe_snp_support().
+/// ```
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn probe_snp_support<'a>(env: Env<'a>) -> NifResult<Term<'a>> {
+    let probe = probe();
+    Ok(encode_result(env, encode_probe(env, &probe)))
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use rustler::{MapIterator, Term};
+use sev::firmware::guest::AttestationReport;
+use sev::firmware::host::TcbVersion;
+use crate::error::{SnpError, SnpResult};
+use crate::product::{tcb_bytes, Product};
+
+/// The TCB versions of a report that are checked against a minimum.
+const CHECKED_TCBS: [&str; 3] = ["reported_tcb", "committed_tcb", "launch_tcb"];
+
+/// The security patch levels (SPLs) of a TCB version, decoded according to
+/// the layout of its product line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tcb {
+    /// Only present on Turin and later.
+    pub fmc: Option<u8>,
+    pub bootloader: u8,
+    pub tee: u8,
+    pub snp: u8,
+    pub microcode: u8,
+}
+
+impl Tcb {
+    /// Decodes a TCB version. Turin moves the SNP SPL to byte 3 and adds an
+    /// FMC SPL in byte 0; earlier parts keep the layout of `TcbVersion`.
+    pub fn new(product: Product, tcb: &TcbVersion) -> Tcb {
+        let raw = tcb_bytes(tcb);
+        match product {
+            Product::Turin => Tcb {
+                fmc: Some(raw[0]),
+                bootloader: raw[1],
+                tee: raw[2],
+                snp: raw[3],
+                microcode: raw[7],
+            },
+            _ => Tcb {
+                fmc: None,
+                bootloader: raw[0],
+                tee: raw[1],
+                snp: raw[6],
+                microcode: raw[7],
+            },
+        }
+    }
+
+    /// The named components of this TCB, in the order they are compared.
+    pub fn components(&self) -> Vec<(&'static str, u8)> {
+        let mut components = Vec::with_capacity(5);
+        if let Some(fmc) = self.fmc {
+            components.push(("fmc", fmc));
+        }
+        components.extend([
+            ("bootloader", self.bootloader),
+            ("tee", self.tee),
+            ("snp", self.snp),
+            ("microcode", self.microcode),
+        ]);
+        components
+    }
+
+    /// Returns the components that are below the given minimum, as
+    /// `(component, actual, minimum)`. Components the minimum does not set,
+    /// or that this TCB does not have, are not compared.
+    pub fn below(&self, min: &MinTcb) -> Vec<(&'static str, u8, u8)> {
+        self.components()
+            .into_iter()
+            .filter_map(|(name, actual)| match min.get(name) {
+                Some(min) if actual < min => Some((name, actual, min)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Minimum SPLs for the components of a TCB version.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MinTcb {
+    pub fmc: Option<u8>,
+    pub bootloader: Option<u8>,
+    pub tee: Option<u8>,
+    pub snp: Option<u8>,
+    pub microcode: Option<u8>,
+}
+
+impl MinTcb {
+    /// The minimum for a named component, if set.
+    fn get(&self, component: &str) -> Option<u8> {
+        match component {
+            "fmc" => self.fmc,
+            "bootloader" => self.bootloader,
+            "tee" => self.tee,
+            "snp" => self.snp,
+            "microcode" => self.microcode,
+            _ => None,
+        }
+    }
+}
+
+/// Minimum TCB versions, either for every product line or per product line.
+#[derive(Debug, Default)]
+pub struct MinTcbPolicy {
+    default: Option<MinTcb>,
+    per_product: HashMap<Product, MinTcb>,
+}
+
+impl MinTcbPolicy {
+    /// The minimum that applies to a product line, if any.
+    pub fn for_product(&self, product: Product) -> Option<&MinTcb> {
+        self.per_product.get(&product).or(self.default.as_ref())
+    }
+
+    /// Checks the reported, committed and launch TCB versions of a report
+    /// against the minimum for its product line.
+    ///
+    /// # Errors
+    /// Returns `TcbBelowMinimum` naming the first component below its
+    /// minimum (e.g. `committed_tcb.microcode`).
+    pub fn check(&self, report: &AttestationReport, product: Product) -> SnpResult<()> {
+        let min = match self.for_product(product) {
+            Some(min) => min,
+            None => return Ok(()),
+        };
+        for (name, tcb) in report_tcbs(report, product) {
+            if let Some((component, actual, min)) = tcb.below(min).into_iter().next() {
+                return Err(SnpError::TcbBelowMinimum {
+                    field: format!("{name}.{component}"),
+                    reason: format!("SPL {actual} is below the minimum of {min}"),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes every TCB component of a report that is below its minimum.
+    pub fn violations(&self, report: &AttestationReport, product: Product) -> Vec<String> {
+        let min = match self.for_product(product) {
+            Some(min) => min,
+            None => return Vec::new(),
+        };
+        report_tcbs(report, product)
+            .into_iter()
+            .flat_map(|(name, tcb)| {
+                tcb.below(min)
+                    .into_iter()
+                    .map(move |(component, actual, min)| format!("{name}.{component} {actual} < {min}"))
+            })
+            .collect()
+    }
+}
+
+/// The checked TCB versions of a report, decoded for its product line.
+fn report_tcbs(report: &AttestationReport, product: Product) -> Vec<(&'static str, Tcb)> {
+    let tcbs = [&report.reported_tcb, &report.committed_tcb, &report.launch_tcb];
+    CHECKED_TCBS
+        .iter()
+        .zip(tcbs)
+        .map(|(name, tcb)| (*name, Tcb::new(product, tcb)))
+        .collect()
+}
+
+/// Sets the minimum for a named component.
+fn set_component(min_tcb: &mut MinTcb, field: &str, component: &str, value: Term) -> SnpResult<()> {
+    let field = format!("{field}.{component}");
+    let level = Some(value.decode().map_err(|_| {
+        SnpError::invalid_argument(&field, "expected a level from 0 to 255")
+    })?);
+    match component {
+        "fmc" => min_tcb.fmc = level,
+        "bootloader" => min_tcb.bootloader = level,
+        "tee" => min_tcb.tee = level,
+        "snp" => min_tcb.snp = level,
+        "microcode" => min_tcb.microcode = level,
+        _ => return Err(SnpError::invalid_argument(&field, "unknown TCB component")),
+    }
+    Ok(())
+}
+
+/// Decodes a map of component minimums, e.g. `#{ snp => 22, microcode => 213 }`.
+fn parse_min_tcb(field: &str, value: Term) -> SnpResult<MinTcb> {
+    let map_iter = MapIterator::new(value)
+        .ok_or_else(|| SnpError::invalid_argument(field, "expected a map"))?;
+    let mut min_tcb = MinTcb::default();
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument(field, "keys must be atoms"))?;
+        set_component(&mut min_tcb, field, &key_str, value)?;
+    }
+    Ok(min_tcb)
+}
+
+/// Decodes a minimum TCB passed from Erlang. The map either gives component
+/// minimums directly, applying to every product line, or maps product lines
+/// to component minimums, which take precedence:
+/// ```erlang
+/// #{ snp => 22, microcode => 213 }
+/// #{ milan => #{ snp => 22 }, turin => #{ fmc => 1, snp => 1 } }
+/// ```
+pub fn parse_min_tcb_policy(field: &str, value: Term) -> SnpResult<MinTcbPolicy> {
+    let map_iter = MapIterator::new(value)
+        .ok_or_else(|| SnpError::invalid_argument(field, "expected a map"))?;
+    let mut policy = MinTcbPolicy::default();
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument(field, "keys must be atoms"))?;
+        match Product::from_name(&key_str) {
+            Some(product) => {
+                let product_field = format!("{field}.{key_str}");
+                let min_tcb = parse_min_tcb(&product_field, value)?;
+                policy.per_product.insert(product, min_tcb);
+            }
+            None => {
+                let default = policy.default.get_or_insert_with(MinTcb::default);
+                set_component(default, field, &key_str, value)?;
+            }
+        }
+    }
+    Ok(policy)
+}
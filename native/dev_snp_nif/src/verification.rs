@@ -1,12 +1,19 @@
-use rustler::{Binary, Encoder, Env, NifResult, Term};
-use rustler::types::atom::{self, ok};
-use serde_json::Value;
+use std::time::Duration;
+use rustler::{Binary, Encoder, Env, MapIterator, NifResult, Term};
+use rustler::types::atom;
 use serde::Deserialize;
-use sev::certs::snp::{ecdsa::Signature, Chain, Verifiable};
-use sev::firmware::host::TcbVersion;
-use sev::firmware::guest::{AttestationReport, GuestPolicy, PlatformInfo};
-use crate::helpers::{request_cert_chain, request_vcek};
+use sev::certs::snp::{ca, Certificate, Verifiable};
+use sev::firmware::guest::AttestationReport;
+use crate::async_nif;
+use crate::crl::VerifiedCrl;
+use crate::error::{encode_error, encode_result, SnpError, SnpResult};
+use crate::freshness::{self, NonceBinding};
+use crate::helpers::{request_cert_chain, request_vcek, SuppliedCerts};
+use crate::kds::KeyType;
 use crate::logging::log_message;
+use crate::product::{decode_product, Product};
+use crate::report;
+use crate::tcb::{parse_min_tcb_policy, MinTcbPolicy};
 
 /// Verifies whether the measurement in the attestation report matches the expected measurement.
 ///
@@ -18,14 +25,15 @@ use crate::logging::log_message;
 /// # Returns
 /// A tuple with:
 /// - `ok` atom and a success message if the measurements match.
-/// - `error` atom and an error message if the measurements do not match.
+/// - `error` atom and `false` if the measurements do not match.
+/// - `{error, {invalid_report, <<"measurement">>}}` if the report cannot be parsed.
 #[rustler::nif]
 fn verify_measurement<'a>(
     env: Env<'a>,
     _report: Binary,
     _expected_measurement: Binary,
 ) -> NifResult<Term<'a>> {
-    //log_message("INFO", file!(), line!(), "Starting measurement verification...");
+    log_message("DEBUG", file!(), line!(), "Starting measurement verification...");
 
     // Define a struct for deserializing the attestation report.
     #[derive(Debug, Deserialize)]
@@ -37,49 +45,47 @@ fn verify_measurement<'a>(
     // Step 1: Deserialize the JSON report.
     let report: AttestationReport = match serde_json::from_slice(_report.as_slice()) {
         Ok(parsed_report) => {
-            //log_message(
-            //    "INFO",
-            //    file!(),
-            //    line!(),
-            //    &format!("Successfully parsed report: {:?}", parsed_report),
-            //);
-            parsed_report
-        }
-        Err(err) => {
             log_message(
-                "ERROR",
+                "DEBUG",
                 file!(),
                 line!(),
-                &format!("Failed to deserialize report: {:?}", err),
+                &format!("Successfully parsed report: {:?}", parsed_report),
             );
-            return Ok((atom::error(), "Invalid report format").encode(env));
+            parsed_report
+        }
+        Err(err) => {
+            let err = SnpError::InvalidReport {
+                field: "measurement".to_string(),
+                reason: err.to_string(),
+            };
+            return Ok(encode_error(env, &err));
         }
     };
 
     // Step 2: Extract the actual measurement from the report.
     let actual_measurement = &report.measurement;
-    // log_message(
-    //     "INFO",
-    //     file!(),
-    //     line!(),
-    //     &format!("Extracted actual measurement: {:?}", actual_measurement),
-    // );
+    log_message(
+        "DEBUG",
+        file!(),
+        line!(),
+        &format!("Extracted actual measurement: {:?}", actual_measurement),
+    );
 
     // Step 3: Decode the expected measurement from the input binary.
     let expected_measurement: Vec<u8> = _expected_measurement.as_slice().to_vec();
-    // log_message(
-    //     "INFO",
-    //     file!(),
-    //     line!(),
-    //     &format!("Decoded expected measurement: {:?}", expected_measurement),
-    // );
+    log_message(
+        "DEBUG",
+        file!(),
+        line!(),
+        &format!("Decoded expected measurement: {:?}", expected_measurement),
+    );
 
     // Step 4: Compare the actual and expected measurements.
     if actual_measurement == &expected_measurement {
-        //log_message("INFO", file!(), line!(), "Measurements match.");
+        log_message("DEBUG", file!(), line!(), "Measurements match.");
         Ok((atom::ok(), true).encode(env))
     } else {
-        //log_message("ERROR", file!(), line!(), "Measurements do not match.");
+        log_message("DEBUG", file!(), line!(), "Measurements do not match.");
         Ok((atom::error(), false).encode(env))
     }
 }
@@ -87,224 +93,321 @@ fn verify_measurement<'a>(
 
 /// Verifies the signature of an attestation report.
 ///
+/// The product line used to fetch certificates is derived from the report's
+/// CPUID fields (report version 3 onwards), falling back to Milan.
+///
 /// # Arguments
 /// * `env` - The Rustler environment, used to encode the return value.
-/// * `report` - A binary containing the serialized attestation report.
+/// * `report` - A binary containing the attestation report, either raw (as
+///   produced by the firmware) or serialized as JSON.
 ///
 /// # Returns
-/// A tuple with:
-/// - `ok` atom and a success message if the signature is valid.
-/// - `error` atom and an error message if the signature verification fails.
-#[rustler::nif]
+/// `{ok, true}` if the signature is valid, or `{error, {Kind, Detail}}` where
+/// `Kind` is one of `invalid_report`, `kds_unreachable`, `invalid_certificate`,
+/// `cert_chain_invalid`, `crl_invalid`, `certificate_revoked` or
+/// `signature_invalid`.
+#[rustler::nif(schedule = "DirtyIo")]
 fn verify_signature<'a>(
     env: Env<'a>,
     report: Binary<'a>,
 ) ->  NifResult<Term<'a>>  {
-    // log_message("INFO", file!(), line!(), "Verifying signature...");
+    let opts = VerifyOpts::default();
+    Ok(encode_result(env, verify_report_signature(report.as_slice(), &opts)))
+}
 
-    // Step 1: Parse the report JSON into a serde Value object.
-    let json_data = match serde_json::from_slice::<Value>(report.as_slice()) {
-        Ok(data) => data,
-        Err(err) => {
-            return Ok((
-                rustler::types::atom::error(),
-                format!("Failed to parse JSON: {}", err),
-            )
-                .encode(env));
-        }
+/// Verifies the signature of an attestation report with explicit options.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `report` - A binary containing the serialized attestation report.
+/// * `opts` - An Erlang map of verification options.
+///
+/// # Expected Input Map Keys:
+/// - `"product"`: The product line (`milan`, `genoa`, `bergamo`, `siena` or
+///   `turin`), overriding the one derived from the report.
+/// - `"vlek"`: The VLEK of a report signed with one, as a PEM or DER
+///   certificate, or as a PEM chain of VLEK, ASVK and ARK. Without a chain,
+///   the ASVK + ARK chain is fetched from the KDS VLEK endpoint.
+/// - `"certs"`: Certificates to use instead of fetching them, as returned in
+///   the `certs` map of `generate_extended_report/2` (`ark`, `ask` or `asvk`,
+///   and `vcek` or `vlek`, each PEM or DER). A supplied ARK must be pinned.
+/// - `"min_tcb"`: The minimum TCB that the reported, committed and launch TCB
+///   versions must meet, either as component levels (`fmc`, `bootloader`,
+///   `tee`, `snp`, `microcode`) or as a map from product line to those.
+/// - `"replay_ttl"`: Rejects replays: a report is remembered for this many
+///   milliseconds once verified, and any report with the same `report_id` and
+///   `report_data` is rejected meanwhile. At most 65536 reports are
+///   remembered, the oldest being forgotten first.
+/// - `"nonce"`: A nonce from `issue_nonce/1` that the report must be bound
+///   to, consumed once the report has passed every other check. Either the
+///   nonce itself, which must start the report's `report_data`, or
+///   `{Nonce, Fields, Hash}` if `report_data` binds `[Nonce | Fields]` as
+///   computed by `compute_report_data/2`.
+///
+/// # Returns
+/// The same result as `verify_signature/1`, or
+/// `{error, {tcb_below_minimum, Component}}` (e.g. `<<"reported_tcb.snp">>`)
+/// if a TCB version is below the minimum, `{error, {invalid_report,
+/// <<"report_data">>}}` if the report is not bound to the nonce, or
+/// `{error, {replayed_report, _}}` if the report has already been verified
+/// or the nonce is not outstanding.
+///
+/// # Example
+/// ```erlang
+/// {ok, true} = dev_snp_nif:verify_signature(JsonReport, #{ product => genoa }).
+/// {ok, true} =
+///     dev_snp_nif:verify_signature(JsonReport, #{ min_tcb => #{ milan => #{ snp => 22 } } }).
+/// ```
+#[rustler::nif(name = "verify_signature", schedule = "DirtyIo")]
+fn verify_signature_with_opts<'a>(
+    env: Env<'a>,
+    report: Binary<'a>,
+    opts: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let map_iter = MapIterator::new(opts).ok_or(rustler::Error::BadArg)?;
+    let opts = match parse_verify_opts(map_iter) {
+        Ok(opts) => opts,
+        Err(err) => return Ok(encode_error(env, &err)),
     };
+    Ok(encode_result(env, verify_report_signature(report.as_slice(), &opts)))
+}
 
-    // Step 2: Map JSON fields to the AttestationReport struct.
-    // Each field is individually parsed to ensure type safety.
-    let attestation_report = AttestationReport {
-        version: json_data["version"].as_u64().unwrap_or(0) as u32,
-        guest_svn: json_data["guest_svn"].as_u64().unwrap_or(0) as u32,
-        policy: GuestPolicy(json_data["policy"].as_u64().unwrap_or(0)),
-        family_id: json_data["family_id"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 16]),
-        image_id: json_data["image_id"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 16]),
-        vmpl: json_data["vmpl"].as_u64().unwrap_or(0) as u32,
-        sig_algo: json_data["sig_algo"].as_u64().unwrap_or(0) as u32,
-        current_tcb: TcbVersion {
-            bootloader: json_data["current_tcb"]["bootloader"].as_u64().unwrap_or(0) as u8,
-            tee: json_data["current_tcb"]["tee"].as_u64().unwrap_or(0) as u8,
-            snp: json_data["current_tcb"]["snp"].as_u64().unwrap_or(0) as u8,
-            microcode: json_data["current_tcb"]["microcode"].as_u64().unwrap_or(0) as u8,
-            _reserved: [0; 4],
-        },
-        plat_info: PlatformInfo(json_data["plat_info"].as_u64().unwrap_or(0)),
-        _author_key_en: json_data["_author_key_en"].as_u64().unwrap_or(0) as u32,
-        _reserved_0: json_data["_reserved_0"].as_u64().unwrap_or(0) as u32,
-        report_data: json_data["report_data"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 64]),
-        measurement: json_data["measurement"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 48]),
-        host_data: json_data["host_data"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 32]),
-        id_key_digest: json_data["id_key_digest"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 48]),
-        author_key_digest: json_data["author_key_digest"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 48]),
-        report_id: json_data["report_id"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 32]),
-        report_id_ma: json_data["report_id_ma"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 32]),
-        reported_tcb: TcbVersion {
-            bootloader: json_data["reported_tcb"]["bootloader"]
-                .as_u64()
-                .unwrap_or(0) as u8,
-            tee: json_data["reported_tcb"]["tee"].as_u64().unwrap_or(0) as u8,
-            snp: json_data["reported_tcb"]["snp"].as_u64().unwrap_or(0) as u8,
-            microcode: json_data["reported_tcb"]["microcode"].as_u64().unwrap_or(0) as u8,
-            _reserved: [0; 4],
-        },
-        _reserved_1: [0; 24],
-        chip_id: json_data["chip_id"]
-            .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|v| v.as_u64().unwrap_or(0) as u8)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([0; 64]),
-        committed_tcb: TcbVersion {
-            bootloader: json_data["committed_tcb"]["bootloader"]
-                .as_u64()
-                .unwrap_or(0) as u8,
-            tee: json_data["committed_tcb"]["tee"].as_u64().unwrap_or(0) as u8,
-            snp: json_data["committed_tcb"]["snp"].as_u64().unwrap_or(0) as u8,
-            microcode: json_data["committed_tcb"]["microcode"]
-                .as_u64()
-                .unwrap_or(0) as u8,
-            _reserved: [0; 4],
-        },
-        current_build: json_data["current_build"].as_u64().unwrap_or(0) as u8,
-        current_minor: json_data["current_minor"].as_u64().unwrap_or(0) as u8,
-        current_major: json_data["current_major"].as_u64().unwrap_or(0) as u8,
-        _reserved_2: json_data["_reserved_2"].as_u64().unwrap_or(0) as u8,
-        committed_build: json_data["committed_build"].as_u64().unwrap_or(0) as u8,
-        committed_minor: json_data["committed_minor"].as_u64().unwrap_or(0) as u8,
-        committed_major: json_data["committed_major"].as_u64().unwrap_or(0) as u8,
-        _reserved_3: json_data["_reserved_3"].as_u64().unwrap_or(0) as u8,
-        launch_tcb: TcbVersion {
-            bootloader: json_data["launch_tcb"]["bootloader"].as_u64().unwrap_or(0) as u8,
-            tee: json_data["launch_tcb"]["tee"].as_u64().unwrap_or(0) as u8,
-            snp: json_data["launch_tcb"]["snp"].as_u64().unwrap_or(0) as u8,
-            microcode: json_data["launch_tcb"]["microcode"].as_u64().unwrap_or(0) as u8,
-            _reserved: [0; 4],
-        },
-        _reserved_4: [0; 168],
-        signature: Signature {
-            r: json_data["signature"]["r"]
-                .as_array()
-                .unwrap_or(&vec![])
-                .iter()
-                .map(|v| v.as_u64().unwrap_or(0) as u8)
-                .collect::<Vec<u8>>()
-                .try_into()
-                .unwrap_or([0; 72]),
-            s: json_data["signature"]["s"]
-                .as_array()
-                .unwrap_or(&vec![])
-                .iter()
-                .map(|v| v.as_u64().unwrap_or(0) as u8)
-                .collect::<Vec<u8>>()
-                .try_into()
-                .unwrap_or([0; 72]),
-            _reserved: [0; 368],
-        },
+/// Verifies the signature of an attestation report without blocking the
+/// caller, which is sent the result once the certificates have been fetched.
+///
+/// # Arguments
+/// * `env` - The Rustler environment, used to encode the return value.
+/// * `report` - A binary containing the serialized attestation report.
+/// * `opts` - An Erlang map of verification options, as for `verify_signature/2`.
+///
+/// # Returns
+/// `{ok, Ref}`, `{error, {invalid_argument, Detail}}` if the options are
+/// invalid, or `{error, {busy, Detail}}` if too many async calls are already
+/// running. The result of `verify_signature/2` is later sent to the caller as
+/// `{snp_result, Ref, Result}`.
+///
+/// # Example
+/// ```erlang
+/// {ok, Ref} = dev_snp_nif:verify_signature_async(JsonReport, #{}),
+/// receive {snp_result, Ref, {ok, true}} -> ok end.
+/// ```
+#[rustler::nif]
+fn verify_signature_async<'a>(
+    env: Env<'a>,
+    report: Binary<'a>,
+    opts: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let map_iter = MapIterator::new(opts).ok_or(rustler::Error::BadArg)?;
+    let opts = match parse_verify_opts(map_iter) {
+        Ok(opts) => opts,
+        Err(err) => return Ok(encode_error(env, &err)),
     };
+    let report = report.as_slice().to_vec();
+    async_nif::spawn(env, move |env| {
+        encode_result(env, verify_report_signature(&report, &opts))
+    })
+}
+
+/// Options accepted by `verify_signature/2`.
+#[derive(Debug, Default)]
+pub struct VerifyOpts {
+    /// The product line, overriding the one derived from the report.
+    pub product: Option<Product>,
+    /// Certificates supplied instead of fetched, including the VLEK of
+    /// VLEK-signed reports.
+    pub certs: SuppliedCerts,
+    /// The minimum TCB the report must meet.
+    pub min_tcb: Option<MinTcbPolicy>,
+    /// How long a verified report is remembered, to reject replays of it.
+    pub replay_ttl: Option<Duration>,
+    /// The issued nonce the report must be bound to.
+    pub nonce: Option<NonceBinding>,
+}
+
+/// Decodes the `verify_signature/2` options map.
+pub fn parse_verify_opts(map_iter: MapIterator) -> SnpResult<VerifyOpts> {
+    let mut opts = VerifyOpts::default();
+    for (key, value) in map_iter {
+        let key_str = key
+            .atom_to_string()
+            .map_err(|_| SnpError::invalid_argument("opts", "keys must be atoms"))?;
+        match key_str.as_str() {
+            "product" => {
+                opts.product = Some(decode_product(value).map_err(|_| {
+                    SnpError::invalid_argument("product", "unknown product line")
+                })?)
+            }
+            "vlek" => {
+                let vlek: Binary = value
+                    .decode()
+                    .map_err(|_| SnpError::invalid_argument("vlek", "expected a binary"))?;
+                opts.certs.add_vlek(vlek.as_slice())?
+            }
+            "certs" => opts.certs.add_map("certs", value)?,
+            "min_tcb" => opts.min_tcb = Some(parse_min_tcb_policy("min_tcb", value)?),
+            "replay_ttl" => opts.replay_ttl = Some(freshness::decode_ttl("replay_ttl", value)?),
+            "nonce" => opts.nonce = Some(NonceBinding::decode("nonce", value)?),
+            _ => log_message("WARN", file!(), line!(), &format!("Unexpected key: {}", key_str)),
+        }
+    }
+    Ok(opts)
+}
+
+/// Shared implementation of `verify_signature/1,2`.
+fn verify_report_signature(report: &[u8], opts: &VerifyOpts) -> SnpResult<bool> {
+    log_message("DEBUG", file!(), line!(), "Verifying signature...");
+
+    // Step 1: Parse the report, accepting both the raw and JSON encodings.
+    let attestation_report = report::parse(report)?;
+
+    // Steps 2-3: Verify the report and its TCB versions.
+    check_report(&attestation_report, opts, None)
+}
+
+/// Verifies a parsed report against the AMD certificate chain and checks its
+/// (then authenticated) TCB versions against the minimum.
+///
+/// # Arguments
+/// * `attestation_report` - The report to verify.
+/// * `opts` - The verification options.
+/// * `signer` - The already resolved certificates of the report's signer, if
+///   any. Otherwise they are fetched.
+pub fn check_report(
+    attestation_report: &AttestationReport,
+    opts: &VerifyOpts,
+    signer: Option<&Signer>,
+) -> SnpResult<bool> {
+    match signer {
+        Some(signer) => signer.verify(attestation_report)?,
+        None => check_signature(attestation_report, opts.product, &opts.certs)?,
+    }
+    if let Some(min_tcb) = &opts.min_tcb {
+        let product = Product::of_report(attestation_report, opts.product);
+        min_tcb.check(attestation_report, product)?;
+    }
+    // Only authenticated reports consume nonces or are remembered, so
+    // forgeries cannot block genuine ones.
+    if let Some(nonce) = &opts.nonce {
+        nonce.consume(attestation_report)?;
+    }
+    if let Some(ttl) = opts.replay_ttl {
+        freshness::record_report(attestation_report, ttl)?;
+    }
+    Ok(true)
+}
 
-    // Step 3: Extract the chip ID and TCB version.
-    let chip_id_array: [u8; 64] = attestation_report
-        .chip_id
-        .try_into()
-        .expect("chip_id must be 64 bytes");
-    let tcb_version = attestation_report.current_tcb;
+/// Verifies the signature of a parsed attestation report against the AMD
+/// certificate chain of its product line.
+///
+/// # Arguments
+/// * `attestation_report` - The report to verify.
+/// * `product` - The product line, overriding the one derived from the report.
+/// * `certs` - Caller-supplied certificates, used instead of fetched ones. A
+///   VLEK must be supplied if the report is signed with one.
+pub fn check_signature(
+    attestation_report: &AttestationReport,
+    product: Option<Product>,
+    certs: &SuppliedCerts,
+) -> SnpResult<()> {
+    // Step 1: Determine the product line and the type of key that signed the report.
+    let product = Product::of_report(attestation_report, product);
+    let key_type = KeyType::from_report(attestation_report)?;
+
+    // Steps 2-3: Resolve the certificate chain, from the cache if possible.
+    let authority = Authority::resolve(product, key_type, certs)?;
+    let signer = authority.signer(attestation_report, certs)?;
 
-    // Step 4: Request the certificate chain and VCEK.
-    let ca = request_cert_chain("Milan").unwrap();
-    let vcek = request_vcek(chip_id_array, tcb_version).unwrap();
+    // Step 4: Verify the attestation report.
+    signer.verify(attestation_report)
+}
 
-    // Step 5: Verify the certificate chain.
-    if let Err(e) = ca.verify() {
-        log_message(
-            "ERROR",
-            file!(),
-            line!(),
-            &format!("CA chain verification failed: {:?}", e),
-        );
-        return Ok((atom::error(), format!("CA verification failed: {:?}", e)).encode(env));
+/// The ARK and ASK (or ASVK) of a product line and key type, verified
+/// against each other, with the CRL signed by the ARK.
+pub struct Authority {
+    product: Product,
+    key_type: KeyType,
+    ca: ca::Chain,
+    crl: VerifiedCrl,
+}
+
+impl Authority {
+    /// Requests and verifies the certificate authority that issues the keys
+    /// of a product line, and checks that its intermediate is not revoked.
+    ///
+    /// # Errors
+    /// Returns `InvalidArgument` for `vlek` if `key_type` is `Vlek` and no
+    /// VLEK is supplied, since the KDS does not serve VLEKs.
+    pub fn resolve(product: Product, key_type: KeyType, certs: &SuppliedCerts) -> SnpResult<Authority> {
+        if key_type == KeyType::Vlek && certs.vek.is_none() {
+            return Err(SnpError::invalid_argument(
+                "vlek",
+                "the report is signed with a VLEK, which must be supplied",
+            ));
+        }
+        // Reject a malformed supplied key before requesting anything.
+        certs.vek(key_type)?;
+
+        // Request and verify the certificate chain, from the cache if possible.
+        let ca = match certs.chain(product)? {
+            Some(chain) => chain,
+            None => request_cert_chain(product, key_type)?,
+        };
+        ca.verify().map_err(|e| SnpError::CertChainInvalid {
+            detail: format!("{:?}", e),
+        })?;
+        log_message("DEBUG", file!(), line!(), "CA chain verification successful.");
+
+        // Check the intermediate against the CRL signed by the ARK.
+        let crl = VerifiedCrl::load(product, key_type, &ca.ark)?;
+        let intermediate = match key_type {
+            KeyType::Vcek => "ASK",
+            KeyType::Vlek => "ASVK",
+        };
+        crl.ensure_not_revoked(intermediate, &ca.ask)?;
+        Ok(Authority {
+            product,
+            key_type,
+            ca,
+            crl,
+        })
     }
-    //log_message("INFO", file!(), line!(), "CA chain verification successful.");
 
-    // Step 6: Verify the attestation report.
-    let cert_chain = Chain { ca, vek: vcek };
-    if let Err(e) = (&cert_chain, &attestation_report).verify() {
-        log_message(
-            "ERROR",
-            file!(),
-            line!(),
-            &format!("Attestation report verification failed: {:?}", e),
-        );
-        return Ok((atom::error(), format!("Report verification failed: {:?}", e)).encode(env));
+    /// Resolves the key that signed a report, supplied or requested for the
+    /// report's chip and reported TCB, and checks that it was issued by this
+    /// authority and is not revoked.
+    pub fn signer(&self, attestation_report: &AttestationReport, certs: &SuppliedCerts) -> SnpResult<Signer> {
+        let vek = match certs.vek(self.key_type)? {
+            Some(vek) => vek,
+            None => request_vcek(
+                self.product,
+                attestation_report.chip_id,
+                attestation_report.reported_tcb,
+            )?,
+        };
+        (&self.ca.ask, &vek)
+            .verify()
+            .map_err(|e| SnpError::CertChainInvalid {
+                detail: format!("{} not issued by the intermediate: {:?}", self.key_type.name(), e),
+            })?;
+        self.crl.ensure_not_revoked(self.key_type.name(), &vek)?;
+        Ok(Signer { vek })
     }
+}
+
+/// A verified VCEK or VLEK, which may sign any number of reports.
+pub struct Signer {
+    vek: Certificate,
+}
 
-    //log_message("INFO", file!(), line!(), "Signature verification successful.");
-    Ok((ok(), true).encode(env))
+impl Signer {
+    /// Verifies the signature of a report.
+    pub fn verify(&self, attestation_report: &AttestationReport) -> SnpResult<()> {
+        (&self.vek, attestation_report)
+            .verify()
+            .map_err(|e| SnpError::SignatureInvalid {
+                detail: format!("{:?}", e),
+            })?;
+        log_message("DEBUG", file!(), line!(), "Signature verification successful.");
+        Ok(())
+    }
 }
@@ -0,0 +1,161 @@
+// Generates the SEV-SNP ID block and ID authentication information passed to
+// SNP_LAUNCH_FINISH (e.g. QEMU's `id-block` and `id-auth`), and the key
+// digests that the guest's attestation reports will carry.
+
+use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use openssl::ec::{EcKey, EcKeyRef};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{HasParams, HasPublic, PKey, Private, Public};
+
+/// Identifier of ECDSA P-384 with SHA-384, the only ID key algorithm.
+const ALGO_ECDSA_P384_SHA384: u32 = 1;
+/// Identifier of the P-384 curve in public keys.
+const CURVE_P384: u32 = 2;
+/// Size of a public key in the SNP ABI's format.
+const PUBLIC_KEY_SIZE: usize = 0x404;
+/// Size of a signature in the SNP ABI's format.
+const SIGNATURE_SIZE: usize = 0x200;
+/// Size of a little-endian ECDSA parameter (coordinate, `r` or `s`).
+const PARAM_SIZE: usize = 72;
+
+/// The fields of an ID block.
+pub struct IdBlock {
+    pub measurement: [u8; 48],
+    pub family_id: [u8; 16],
+    pub image_id: [u8; 16],
+    pub guest_svn: u32,
+    pub policy: u64,
+}
+
+impl IdBlock {
+    /// Encodes the ID block in the 96-byte layout of the SNP ABI.
+    fn encode(&self) -> [u8; 0x60] {
+        let mut out = [0u8; 0x60];
+        out[0x00..0x30].copy_from_slice(&self.measurement);
+        out[0x30..0x40].copy_from_slice(&self.family_id);
+        out[0x40..0x50].copy_from_slice(&self.image_id);
+        out[0x50..0x54].copy_from_slice(&1u32.to_le_bytes());
+        out[0x54..0x58].copy_from_slice(&self.guest_svn.to_le_bytes());
+        out[0x58..0x60].copy_from_slice(&self.policy.to_le_bytes());
+        out
+    }
+}
+
+/// The signed ID block, ID authentication information and key digests.
+pub struct SignedIdBlock {
+    pub id_block: [u8; 0x60],
+    pub id_auth: Vec<u8>,
+    pub id_key_digest: [u8; 48],
+    pub author_key_digest: Option<[u8; 48]>,
+}
+
+/// Writes a big-endian parameter as the little-endian, zero-padded value the
+/// SNP ABI expects.
+fn write_param(out: &mut [u8], value: &BigNumRef) -> Result<(), String> {
+    let mut bytes = value
+        .to_vec_padded(PARAM_SIZE as i32)
+        .map_err(|e| format!("Failed to encode key parameter: {:?}", e))?;
+    bytes.reverse();
+    out[..PARAM_SIZE].copy_from_slice(&bytes);
+    Ok(())
+}
+
+/// Encodes a P-384 public key in the SNP ABI's format.
+fn encode_public_key<T: HasParams + HasPublic>(
+    key: &EcKeyRef<T>,
+) -> Result<[u8; PUBLIC_KEY_SIZE], String> {
+    let mut ctx = BigNumContext::new().map_err(|e| format!("{:?}", e))?;
+    let mut x = BigNum::new().map_err(|e| format!("{:?}", e))?;
+    let mut y = BigNum::new().map_err(|e| format!("{:?}", e))?;
+    key.public_key()
+        .affine_coordinates(key.group(), &mut x, &mut y, &mut ctx)
+        .map_err(|e| format!("Failed to read public key: {:?}", e))?;
+    let mut out = [0u8; PUBLIC_KEY_SIZE];
+    out[0..4].copy_from_slice(&CURVE_P384.to_le_bytes());
+    write_param(&mut out[0x004..0x04C], &x)?;
+    write_param(&mut out[0x04C..0x094], &y)?;
+    Ok(out)
+}
+
+/// The SHA-384 digest of a public key, as found in attestation reports.
+pub fn key_digest<T: HasParams + HasPublic>(key: &EcKeyRef<T>) -> Result<[u8; 48], String> {
+    let digest = hash(MessageDigest::sha384(), &encode_public_key(key)?)
+        .map_err(|e| format!("Failed to hash public key: {:?}", e))?;
+    let mut out = [0u8; 48];
+    out.copy_from_slice(&digest);
+    Ok(out)
+}
+
+/// Signs `data` with ECDSA P-384 and SHA-384, in the SNP ABI's format.
+fn sign(key: &EcKeyRef<Private>, data: &[u8]) -> Result<[u8; SIGNATURE_SIZE], String> {
+    let digest = hash(MessageDigest::sha384(), data)
+        .map_err(|e| format!("Failed to hash data: {:?}", e))?;
+    let signature =
+        EcdsaSig::sign(&digest, key).map_err(|e| format!("Failed to sign: {:?}", e))?;
+    let mut out = [0u8; SIGNATURE_SIZE];
+    write_param(&mut out[0x00..0x48], signature.r())?;
+    write_param(&mut out[0x48..0x90], signature.s())?;
+    Ok(out)
+}
+
+/// Checks that a key is on the P-384 curve.
+fn ensure_p384<T: HasParams>(key: EcKey<T>) -> Result<EcKey<T>, String> {
+    match key.group().curve_name() {
+        Some(Nid::SECP384R1) => Ok(key),
+        _ => Err("Expected an ECDSA P-384 key".to_string()),
+    }
+}
+
+/// Parses a P-384 private key from PEM or DER.
+pub fn parse_private_key(bytes: &[u8]) -> Result<EcKey<Private>, String> {
+    let key = PKey::private_key_from_pem(bytes)
+        .or_else(|_| PKey::private_key_from_der(bytes))
+        .and_then(|key| key.ec_key())
+        .map_err(|e| format!("Failed to parse private key: {:?}", e))?;
+    ensure_p384(key)
+}
+
+/// Parses a P-384 public key from PEM or DER, accepting private keys too.
+pub fn parse_public_key(bytes: &[u8]) -> Result<EcKey<Public>, String> {
+    let public = PKey::public_key_from_pem(bytes)
+        .or_else(|_| PKey::public_key_from_der(bytes))
+        .and_then(|key| key.ec_key());
+    if let Ok(key) = public {
+        return ensure_p384(key);
+    }
+    let private = parse_private_key(bytes)?;
+    EcKey::from_public_key(private.group(), private.public_key())
+        .map_err(|e| format!("Failed to read public key: {:?}", e))
+}
+
+/// Signs an ID block with the ID key and, if given, the ID key with the
+/// author key.
+pub fn sign_id_block(
+    block: &IdBlock,
+    id_key: &EcKeyRef<Private>,
+    author_key: Option<&EcKeyRef<Private>>,
+) -> Result<SignedIdBlock, String> {
+    let id_block = block.encode();
+    let id_public_key = encode_public_key(id_key)?;
+    let mut id_auth = vec![0u8; 0x1000];
+    id_auth[0x000..0x004].copy_from_slice(&ALGO_ECDSA_P384_SHA384.to_le_bytes());
+    id_auth[0x040..0x240].copy_from_slice(&sign(id_key, &id_block)?);
+    id_auth[0x240..0x644].copy_from_slice(&id_public_key);
+    let author_key_digest = match author_key {
+        Some(author_key) => {
+            id_auth[0x004..0x008].copy_from_slice(&ALGO_ECDSA_P384_SHA384.to_le_bytes());
+            id_auth[0x680..0x880].copy_from_slice(&sign(author_key, &id_public_key)?);
+            id_auth[0x880..0xC84].copy_from_slice(&encode_public_key(author_key)?);
+            Some(key_digest(author_key)?)
+        }
+        None => None,
+    };
+    Ok(SignedIdBlock {
+        id_block,
+        id_auth,
+        id_key_digest: key_digest(id_key)?,
+        author_key_digest,
+    })
+}
@@ -4,8 +4,11 @@
 // and generates the corresponding launch digest required for secure attestation 
 // in SEV-SNP environments.
 
-use bincode;
-use clap::{App, Arg};
+mod id_block;
+
+use clap::{App, AppSettings, Arg, ArgMatches};
+use id_block::{key_digest, parse_private_key, parse_public_key, sign_id_block, IdBlock};
+use openssl::base64;
 use serde::{Deserialize, Serialize};
 use sev::error::MeasurementError;
 use sev::measurement::sev_hashes::SevHashes;
@@ -87,11 +90,73 @@ pub fn get_hashes_from_files(
     SevHashes::new(kernel_file, initrd_file, append)
 }
 
-fn main() {
-    // Starting message
-    println!("=== Digest Calculator Starting ===");
+/// Parses a fixed-length hexadecimal string, with or without a `0x` prefix.
+fn hex_to_bytes<const N: usize>(name: &str, hex: &str) -> Result<[u8; N], String> {
+    let hex = hex.trim_start_matches("0x");
+    if hex.len() != N * 2 {
+        return Err(format!("{} must be {} hex encoded bytes", name, N));
+    }
+    let mut out = [0u8; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|e| format!("{} is not valid hex: {:?}", name, e))?;
+    }
+    Ok(out)
+}
 
-    println!("\n=== Getting Command Line Arguments ===");
+/// Parses an integer argument, in decimal or `0x`-prefixed hex.
+fn parse_int(name: &str, value: &str) -> Result<u64, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| format!("{} is not a valid integer: {:?}", name, e))
+}
+
+/// Generates a signed ID block and ID auth for the `id-block` subcommand.
+fn generate_id_block(matches: &ArgMatches) -> Result<(), String> {
+    let read_key = |path: &str| {
+        fs::read(path)
+            .map_err(|e| format!("Failed to read key file {}: {:?}", path, e))
+            .and_then(|bytes| parse_private_key(&bytes))
+    };
+    let block = IdBlock {
+        measurement: hex_to_bytes("measurement", matches.value_of("measurement").unwrap())?,
+        family_id: hex_to_bytes("family_id", matches.value_of("family_id").unwrap())?,
+        image_id: hex_to_bytes("image_id", matches.value_of("image_id").unwrap())?,
+        guest_svn: u32::try_from(parse_int("guest_svn", matches.value_of("guest_svn").unwrap())?)
+            .map_err(|_| "guest_svn must fit in 32 bits".to_string())?,
+        policy: parse_int("policy", matches.value_of("policy").unwrap())?,
+    };
+    let id_key = read_key(matches.value_of("id_key").unwrap())?;
+    let author_key = matches.value_of("author_key").map(read_key).transpose()?;
+    let signed = sign_id_block(&block, &id_key, author_key.as_deref())?;
+
+    println!("\n===== ID Block =====");
+    println!("{}", base64::encode_block(&signed.id_block));
+    println!("\n===== ID Auth =====");
+    println!("{}", base64::encode_block(&signed.id_auth));
+    println!("\n===== ID Key Digest =====");
+    println!("{}", bytes_to_hex(&signed.id_key_digest));
+    if let Some(author_key_digest) = signed.author_key_digest {
+        println!("\n===== Author Key Digest =====");
+        println!("{}", bytes_to_hex(&author_key_digest));
+    }
+    Ok(())
+}
+
+/// Prints the digest a report carries for a key, for the `key-digest` subcommand.
+fn print_key_digest(matches: &ArgMatches) -> Result<(), String> {
+    let path = matches.value_of("key").unwrap();
+    let bytes =
+        fs::read(path).map_err(|e| format!("Failed to read key file {}: {:?}", path, e))?;
+    let key = parse_public_key(&bytes)?;
+    let digest = key_digest(&key)?;
+    println!("{}", bytes_to_hex(&digest));
+    Ok(())
+}
+
+fn main() {
     // Parse command line arguments using the clap library
     let matches = App::new("SEV SNP Measurement")
         .version("1.0")
@@ -106,6 +171,51 @@ fn main() {
             3. Use a different VMM type and guest features:\n\
                 ./sev_snp_measurement --kernel_file /path/to/kernel --ovmf_file /path/to/ovmf --cmdline \"root=/dev/sda console=ttyS0\" --vcpus 4 --vcpu_type EpycMilan --vmm_type EC2 --guest_features 0x2\n"
         )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(App::new("id-block")
+            .about("Generates an ID block and ID auth signed with ECDSA P-384 keys, printed \
+                as base64 for QEMU's id-block and id-auth, along with the key digests \
+                reports launched with them will carry")
+            .arg(Arg::new("measurement")
+                .long("measurement")
+                .help("The expected launch digest, hex encoded (required)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::new("id_key")
+                .long("id-key")
+                .help("Path to the ECDSA P-384 private key signing the ID block, PEM or DER (required)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::new("author_key")
+                .long("author-key")
+                .help("Path to the ECDSA P-384 private key signing the ID key, PEM or DER")
+                .takes_value(true))
+            .arg(Arg::new("family_id")
+                .long("family-id")
+                .help("The family ID, 16 hex encoded bytes (default: zero)")
+                .takes_value(true)
+                .default_value("00000000000000000000000000000000"))
+            .arg(Arg::new("image_id")
+                .long("image-id")
+                .help("The image ID, 16 hex encoded bytes (default: zero)")
+                .takes_value(true)
+                .default_value("00000000000000000000000000000000"))
+            .arg(Arg::new("guest_svn")
+                .long("guest-svn")
+                .help("The guest SVN (default: 0)")
+                .takes_value(true)
+                .default_value("0"))
+            .arg(Arg::new("policy")
+                .long("policy")
+                .help("The guest policy, which must match the launch policy (default: 0x30000)")
+                .takes_value(true)
+                .default_value("0x30000")))
+        .subcommand(App::new("key-digest")
+            .about("Prints the digest reports carry for an ECDSA P-384 ID or author key")
+            .arg(Arg::new("key")
+                .help("Path to the key, public or private, PEM or DER (required)")
+                .required(true)
+                .takes_value(true)))
         .arg(Arg::new("config")
         .help("Path to the YAML configuration file")
         .takes_value(true))
@@ -184,6 +294,24 @@ fn main() {
             .default_value("0x1"))
         .get_matches();
 
+    // The ID block subcommands do not compute a launch digest
+    let subcommand = match matches.subcommand() {
+        Some(("id-block", sub_matches)) => Some(generate_id_block(sub_matches)),
+        Some(("key-digest", sub_matches)) => Some(print_key_digest(sub_matches)),
+        _ => None,
+    };
+    if let Some(result) = subcommand {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Starting message, kept off the output of the subcommands
+    println!("=== Digest Calculator Starting ===");
+
+    println!("\n=== Getting Command Line Arguments ===");
     // Store the parsed command line arguments
     let args = Arguments {
         config: matches.value_of("config").map(String::from),
@@ -227,23 +355,23 @@ fn main() {
     // Retrieve the kernel file from either the config or the command line arguments
     let kernel_file = config
         .as_ref()
-        .and_then(|c| Some(c.kernel_file.clone()))
+        .map(|c| c.kernel_file.clone())
         .unwrap_or_else(|| matches.value_of("kernel_file").unwrap().to_owned());
 
     // Process other command line arguments or config values similarly...
     let initrd_file = config
         .as_ref()
-        .and_then(|c| Some(c.initrd_file.clone()))
+        .map(|c| c.initrd_file.clone())
         .or_else(|| matches.value_of("initrd_file").map(|s| s.to_owned()));
 
     let ovmf_file = config
         .as_ref()
-        .and_then(|c| Some(c.ovmf_file.clone()))
+        .map(|c| c.ovmf_file.clone())
         .unwrap_or_else(|| matches.value_of("ovmf_file").unwrap().to_owned());
 
     let cmdline = config
         .as_ref()
-        .and_then(|c| Some(c.cmdline.clone()))
+        .map(|c| c.cmdline.clone())
         .unwrap_or_else(|| matches.value_of("cmdline").unwrap().to_owned());
 
     let vcpus: u32 = config
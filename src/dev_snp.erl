@@ -55,6 +55,61 @@ real_node_test() ->
         ?assertEqual({ok, true}, Result)
     end.
 
+mock_generate_verify_test() ->
+    % The full pipeline, launch digest included, on the emulated firmware: the
+    % report measures the trusted software, given the node's OVMF image.
+    Opts = #{ snp_ovmf_files => ["test/OVMF-1.55.fd"] },
+    {ok, _} = dev_snp_nif:register_ovmf("test/OVMF-1.55.fd"),
+    {ok, #{ measurement := Measurement }} =
+        dev_snp_nif:compute_launch_digest(?TEST_TRUSTED_SOFTWARE),
+    case mock_commitment(?TEST_TRUSTED_SOFTWARE, Measurement) of
+        skip ->
+            {skip, <<"NIF built without mock firmware.">>};
+        {Report, GenOpts} ->
+            ?assertEqual(
+                {ok, <<"true">>},
+                verify(#{}, #{ <<"body">> => Report }, maps:merge(GenOpts, Opts))
+            ),
+            % A report of other software fails the measurement check
+            {Other, OtherOpts} =
+                mock_commitment(?TEST_TRUSTED_SOFTWARE, crypto:strong_rand_bytes(48)),
+            ?assertEqual(
+                {ok, <<"false">>},
+                verify(#{}, #{ <<"body">> => Other }, maps:merge(OtherOpts, Opts))
+            )
+    end.
+
+verify_unregistered_firmware_test() ->
+    % A `firmware' hash without a registered OVMF image fails the measurement
+    % check, rather than crashing the verifier.
+    Trusted = ?TEST_TRUSTED_SOFTWARE#{ firmware => binary:copy(<<"ab">>, 48) },
+    case mock_commitment(Trusted, crypto:strong_rand_bytes(48)) of
+        skip ->
+            {skip, <<"NIF built without mock firmware.">>};
+        {Report, Opts} ->
+            ?assertEqual(
+                {error, {invalid_argument, <<"firmware">>}},
+                dev_snp_nif:compute_launch_digest(Trusted)
+            ),
+            ?assertEqual(
+                {ok, <<"false">>},
+                verify(#{}, #{ <<"body">> => Report }, Opts)
+            )
+    end.
+
+%% @doc Generate a commitment report for the given trusted software on the
+%% emulated firmware, reporting `Measurement'. Returns `skip' if the NIF is
+%% built without the `mock-firmware' feature.
+mock_commitment(Trusted, Measurement) ->
+    try dev_snp_nif:configure_mock_firmware(#{ measurement => Measurement }) of
+        ok ->
+            application:ensure_all_started(hb),
+            Opts = #{ priv_wallet => ar_wallet:new(), snp_trusted => [Trusted] },
+            {ok, Report} = generate(#{}, #{}, Opts),
+            {Report, Opts}
+    catch
+        error:{not_loaded, _} -> skip
+    end.
 
 %% @doc Verify an commitment report message; validating the identity of a 
 %% remote node, its ephemeral private address, and the integrity of the report.
@@ -102,7 +157,7 @@ verify(M1, M2, NodeOpts) ->
     ?event({snp_node_msg_id, NodeMsgID}),
     Nonce = hb_util:decode(hb_ao:get(<<"nonce">>, Msg, NodeOpts)),
     ?event({snp_nonce, Nonce}),
-    NonceMatches = report_data_matches(Address, NodeMsgID, Nonce),
+    NonceMatches = report_data_matches(ReportJSON, Address, NodeMsgID, Nonce),
     ?event({nonce_matches, NonceMatches}),
     % Step 2: Verify the address and the signature.
     Signers = hb_message:signers(MsgWithJSONReport),
@@ -112,7 +167,7 @@ verify(M1, M2, NodeOpts) ->
     AddressIsValid = lists:member(Address, Signers),
     ?event({address_is_valid, AddressIsValid, {signer, Signers}, {address, Address}}),
     % Step 3: Verify that the debug flag is disabled.
-    DebugDisabled = not is_debug(Msg),
+    DebugDisabled = not is_debug(ReportJSON),
     ?event({debug_disabled, DebugDisabled}),
     % Step 4: Verify measurement data (firmware, kernel, OS image) is trusted.
     IsTrustedSoftware = execute_is_trusted(M1, Msg, NodeOpts),
@@ -134,17 +189,39 @@ verify(M1, M2, NodeOpts) ->
             )
         ),
     ?event({args, { explicit, Args}}),
-    {ok,Expected} = dev_snp_nif:compute_launch_digest(Args),
-    ExpectedBin = list_to_binary(Expected),
-    ?event({expected_measurement, ExpectedBin}),
-    Measurement = hb_ao:get(<<"measurement">>, Msg, NodeOpts),
-    ?event({measurement, {explicit,Measurement}}),
-    {Status, MeasurementIsValid} =
-        dev_snp_nif:verify_measurement(
-            ReportJSON,
-            ExpectedBin
-        ),
-    ?event({status, Status}),
+    % The digest is computed from the OVMF image with the committed `firmware'
+    % hash, so the images the node trusts must be registered first.
+    register_ovmf_files(NodeOpts),
+    MeasurementIsValid =
+        case dev_snp_nif:compute_launch_digest(Args) of
+            {ok, #{ measurement := ExpectedBin } = Expected} ->
+                ?event({expected_measurement, ExpectedBin}),
+                Measurement = hb_ao:get(<<"measurement">>, Msg, NodeOpts),
+                ?event({measurement, {explicit,Measurement}}),
+                {Status, Matches} =
+                    dev_snp_nif:verify_measurement(
+                        ReportJSON,
+                        ExpectedBin
+                    ),
+                ?event({status, Status}),
+                case Matches of
+                    true -> ok;
+                    false ->
+                        % Log what went into the expected measurement, so
+                        % that the mismatching component can be identified.
+                        ?event(
+                            {measurement_mismatch,
+                                {expected, maps:get(measurement_hex, Expected)},
+                                {components, maps:get(components, Expected)},
+                                {vm, maps:without([firmware, kernel, initrd, append], Args)}
+                            }
+                        )
+                end,
+                Matches;
+            {error, Reason} ->
+                ?event({launch_digest_failed, Reason}),
+                false
+        end,
     ?event({measurement_is_valid, MeasurementIsValid}),
     % Step 6: Check the report's integrity.
     {ok, ReportIsValid} = dev_snp_nif:verify_signature(ReportJSON),
@@ -165,6 +242,21 @@ verify(M1, M2, NodeOpts) ->
     ?event({final_validation_result, Valid}),
     {ok, hb_util:bin(Valid)}.
 
+%% @doc Register the OVMF images listed in the `snp_ovmf_files' node option,
+%% so that launch digests can be computed from their `firmware' hashes.
+%% Images that cannot be read are logged and skipped: reports measured with
+%% them then fail the measurement check.
+register_ovmf_files(NodeOpts) ->
+    lists:foreach(
+        fun(Path) ->
+            case dev_snp_nif:register_ovmf(Path) of
+                {ok, Hash} -> ?event({snp_ovmf_registered, Path, Hash});
+                {error, Reason} -> ?event({snp_ovmf_not_registered, Path, Reason})
+            end
+        end,
+        hb_opts:get(snp_ovmf_files, [], NodeOpts)
+    ).
+
 %% @doc Generate an commitment report and emit it as a message, including all of 
 %% the necessary data to generate the nonce (ephemeral node address + node
 %% message ID), as well as the expected measurement (firmware, kernel, and VMSAs
@@ -187,13 +279,14 @@ generate(_M1, _M2, Opts) ->
     ?event({snp_node_msg_id_bin, {explicit, io:format("~p", [RawPublicNodeMsgID])}}),
     % Generate the commitment report.
     ?event({snp_address,  byte_size(Address)}),
-    ReportData = generate_nonce(Address, RawPublicNodeMsgID),
+    NonceFields = nonce_fields(Address, RawPublicNodeMsgID),
+    {ok, ReportData} = dev_snp_nif:compute_report_data(NonceFields, none),
     ?event({snp_report_data, byte_size(ReportData)}),
 
     LocalHashes = hd(hb_opts:get(snp_trusted, [#{}], Opts)),
     ?event(snp_local_hashes, {explicit, LocalHashes}),
     
-    {ok, ReportJSON} = dev_snp_nif:generate_attestation_report(ReportData, 1),
+    {ok, ReportJSON} = dev_snp_nif:generate_bound_report(NonceFields, none, 1),
     ?event({snp_report_json, ReportJSON}),
 
     ?event(
@@ -215,8 +308,9 @@ generate(_M1, _M2, Opts) ->
     {ok, ReportMsg}.
 
 %% @doc Ensure that the node's debug policy is disabled.
-is_debug(Report) ->
-    (hb_ao:get(<<"policy">>, Report, #{}) band (1 bsl 19)) =/= 0.
+is_debug(ReportJSON) ->
+    {ok, #{ policy := #{ debug := Debug } }} = dev_snp_nif:decode_report(ReportJSON),
+    Debug.
 
 %% @doc Ensure that all of the software hashes are trusted. The caller may set
 %% a specific device to use for the `is-trusted' key. The device must then
@@ -302,17 +396,22 @@ trusted(_Msg1, Msg2, NodeOpts) ->
     %% Return the trust validation result
     {ok, IsTrusted}.
 
-%% @doc Ensure that the report data matches the expected report data.
-report_data_matches(Address, NodeMsgID, ReportData) ->
-    ?event({generated_nonce, {explicit, generate_nonce(Address, NodeMsgID)}}),
+%% @doc Ensure that the report data matches the expected report data: both
+%% the nonce carried by the message and the `report_data' of the report must
+%% bind the address and node message ID.
+report_data_matches(ReportJSON, Address, NodeMsgID, ReportData) ->
+    Fields = nonce_fields(Address, NodeMsgID),
+    {ok, Expected} = dev_snp_nif:compute_report_data(Fields, none),
+    ?event({generated_nonce, {explicit, Expected}}),
     ?event({expected_nonce, {explicit, ReportData}}),
-    generate_nonce(Address, NodeMsgID) == ReportData.
+    (Expected == ReportData) andalso
+        (dev_snp_nif:verify_report_data(ReportJSON, Fields, none) == {ok, true}).
 
-%% @doc Generate the nonce to use in the commitment report.
-generate_nonce(RawAddress, RawNodeMsgID) ->
-    Address = hb_util:native_id(RawAddress),
-    NodeMsgID = hb_util:native_id(RawNodeMsgID),
-    << Address/binary, NodeMsgID/binary >>.
+%% @doc The fields bound into the commitment report's `report_data'. They are
+%% concatenated without hashing, so the nonce is the raw address followed by
+%% the raw node message ID.
+nonce_fields(RawAddress, RawNodeMsgID) ->
+    [hb_util:native_id(RawAddress), hb_util:native_id(RawNodeMsgID)].
 
 %% Generate an commitment report and emit it via HTTP.
 % generate_test() ->
@@ -1,6 +1,18 @@
 -module(dev_snp_nif).
 -export([generate_attestation_report/2, compute_launch_digest/1, check_snp_support/0]).
--export([verify_measurement/2, verify_signature/1]).
+-export([verify_measurement/2, verify_signature/1, verify_signature/2]).
+-export([report_product/1, convert_report/2]).
+-export([set_cert_cache_dir/1, cert_cache_dir/0, seed_cert_cache/1, configure_cert_provider/1]).
+-export([register_ovmf/1, verify_report/2, configure_crl/1, pin_ark/2, unpin_ark/2]).
+-export([generate_extended_report/2, derive_key/1, seal/2, unseal/1, unseal/2]).
+-export([configure_mock_firmware/1, probe_snp_support/0]).
+-export([compute_report_data/2, generate_bound_report/3, verify_report_data/3]).
+-export([verify_signature_async/2, compute_launch_digest_async/1]).
+-export([verify_reports/1, verify_reports/2]).
+-export([configure_logging/1, log_to_events/1]).
+-export([decode_report/1]).
+-export([generate_id_block/1, verify_id_key/2]).
+-export([issue_nonce/1, consume_nonce/1]).
 -include("include/cargo.hrl").
 -include("include/hb.hrl").
 -include_lib("eunit/include/eunit.hrl").
@@ -11,24 +23,167 @@
 check_snp_support() ->
 	?NOT_LOADED.
 
+probe_snp_support() ->
+	?NOT_LOADED.
+
 generate_attestation_report(_UniqueData, _VMPL) ->
     ?NOT_LOADED.
 
+generate_extended_report(_UniqueData, _VMPL) ->
+	?NOT_LOADED.
+
+derive_key(_Opts) ->
+	?NOT_LOADED.
+
+seal(_Data, _Policy) ->
+	?NOT_LOADED.
+
+unseal(Blob) ->
+	unseal(Blob, #{}).
+
+unseal(_Blob, _Opts) ->
+	?NOT_LOADED.
+
+compute_report_data(_Fields, _Hash) ->
+	?NOT_LOADED.
+
+generate_bound_report(_Fields, _Hash, _VMPL) ->
+	?NOT_LOADED.
+
+verify_report_data(_Report, _Fields, _Hash) ->
+	?NOT_LOADED.
+
+%% Only loaded when the NIF is built with the `mock-firmware' feature.
+configure_mock_firmware(_Opts) ->
+	?NOT_LOADED.
+
 compute_launch_digest(_Args) ->
 	?NOT_LOADED.
 
+compute_launch_digest_async(_Args) ->
+	?NOT_LOADED.
+
 verify_measurement(_Report, _Expected) ->
 	?NOT_LOADED.
 
 verify_signature(_Report) ->
 	?NOT_LOADED.
 
+verify_signature(_Report, _Opts) ->
+	?NOT_LOADED.
+
+verify_signature_async(_Report, _Opts) ->
+	?NOT_LOADED.
+
+verify_reports(_Reports) ->
+	?NOT_LOADED.
+
+verify_reports(_Reports, _Opts) ->
+	?NOT_LOADED.
+
+report_product(_Report) ->
+	?NOT_LOADED.
+
+decode_report(_Report) ->
+	?NOT_LOADED.
+
+generate_id_block(_Opts) ->
+	?NOT_LOADED.
+
+verify_id_key(_Report, _Opts) ->
+	?NOT_LOADED.
+
+issue_nonce(_Opts) ->
+	?NOT_LOADED.
+
+consume_nonce(_Nonce) ->
+	?NOT_LOADED.
+
+convert_report(_Report, _Format) ->
+	?NOT_LOADED.
+
+set_cert_cache_dir(_Dir) ->
+	?NOT_LOADED.
+
+cert_cache_dir() ->
+	?NOT_LOADED.
+
+seed_cert_cache(_Dir) ->
+	?NOT_LOADED.
+
+configure_cert_provider(_Opts) ->
+	?NOT_LOADED.
+
+register_ovmf(_Path) ->
+	?NOT_LOADED.
+
+verify_report(_Report, _Policy) ->
+	?NOT_LOADED.
+
+configure_crl(_Opts) ->
+	?NOT_LOADED.
+
+pin_ark(_Product, _Pin) ->
+	?NOT_LOADED.
+
+unpin_ark(_Product, _Pin) ->
+	?NOT_LOADED.
+
+configure_logging(_Opts) ->
+	?NOT_LOADED.
+
+%% @doc Route the NIF's log events to `?event', alongside other node events.
+%% `Opts' are those of `configure_logging/1', without `pid'.
+log_to_events(Opts) ->
+	Forwarder = spawn(fun forward_log_events/0),
+	configure_logging(Opts#{ pid => Forwarder }).
+
+forward_log_events() ->
+	receive
+		{snp_log, Event = #{ level := Level, target := Target }} ->
+			?event(snp_nif, {Level, Target, Event}),
+			forward_log_events()
+	end.
+
 init() ->
     ?load_nif_from_crate(dev_snp_nif, 0).
 
 not_loaded(Line) ->
     erlang:nif_error({not_loaded, [{module, ?MODULE}, {line, Line}]}).
 
+logging_test() ->
+	ok =
+		dev_snp_nif:configure_logging(
+			#{ level => none, targets => #{ snp_support => debug }, pid => self() }
+		),
+	{ok, _} = dev_snp_nif:check_snp_support(),
+	receive
+		{snp_log, #{ level := debug, target := <<"snp_support">>, line := Line }} ->
+			?assert(is_integer(Line))
+	after 5000 -> ?assert(false)
+	end,
+	%% Invalid options leave the configuration unchanged
+	?assertMatch(
+		{error, {invalid_argument, <<"level">>}},
+		dev_snp_nif:configure_logging(#{ level => loud, pid => undefined })
+	),
+	%% Modules without a threshold of their own follow `level'
+	_ = dev_snp_nif:compute_report_data([], md5),
+	receive
+		{snp_log, #{ target := <<"error">> }} -> ?assert(false)
+	after 100 -> ok
+	end,
+	%% Unknown options are ignored with a warning, as elsewhere
+	ok = dev_snp_nif:configure_logging(#{ targets => #{ logging => warn }, colour => true }),
+	receive
+		{snp_log, #{ level := warn, message := <<"Unexpected key: colour">> }} -> ok
+	after 5000 -> ?assert(false)
+	end,
+	ok =
+		dev_snp_nif:configure_logging(
+			#{ level => info, targets => #{ snp_support => info, logging => info }, pid => undefined }
+		).
+
 generate_attestation_report_test() ->
 	%% Call check_support() to determine if SNP is supported
 	case dev_snp_nif:check_snp_support() of
@@ -46,6 +201,140 @@ generate_attestation_report_test() ->
 			?assertEqual(ok, ok)
 	end.
 
+probe_snp_support_test() ->
+	{ok, Probe} = dev_snp_nif:probe_snp_support(),
+	?event({snp_probe, Probe}),
+	?assertMatch(#{ device := <<"/dev/sev-guest">>, device_present := _, open := _ }, Probe),
+	case dev_snp_nif:check_snp_support() of
+		{ok, true} ->
+			%% The firmware answers for at least one VMPL
+			?assertMatch(#{ vmpls := [_ | _], current_tcb := #{ snp := _ } }, Probe);
+		{ok, false} ->
+			?assertMatch(#{ open := {error, {_, _}} }, Probe),
+			?assertNot(maps:is_key(backend, Probe))
+	end.
+
+generate_extended_report_test() ->
+	case dev_snp_nif:check_snp_support() of
+		{ok, true} ->
+			UniqueData = crypto:strong_rand_bytes(64),
+			{ok, #{ report := Report, certs := Certs }} =
+				dev_snp_nif:generate_extended_report(UniqueData, 1),
+			?assert(is_binary(Report)),
+			?assert(is_map(Certs));
+		{ok, false} ->
+			?event("SNP not supported on machine, skipping test..."),
+			?assertEqual(ok, ok)
+	end,
+	?assertMatch(
+		{error, {invalid_argument, <<"unique_data">>}},
+		dev_snp_nif:generate_extended_report(<<"short">>, 1)
+	).
+
+derive_key_test() ->
+	Opts = #{ fields => [measurement, guest_policy], vmpl => 1 },
+	case dev_snp_nif:check_snp_support() of
+		{ok, true} ->
+			%% Keys bound to the same fields are stable, and differ otherwise
+			{ok, Key} = dev_snp_nif:derive_key(Opts),
+			?assertEqual(32, byte_size(Key)),
+			?assertEqual({ok, Key}, dev_snp_nif:derive_key(Opts)),
+			?assertNotEqual({ok, Key}, dev_snp_nif:derive_key(Opts#{ fields => [] }));
+		{ok, false} ->
+			?event("SNP not supported on machine, skipping test..."),
+			?assertMatch({error, {firmware_error, _}}, dev_snp_nif:derive_key(Opts))
+	end,
+	?assertEqual(
+		{error, {invalid_argument, <<"fields.host_data">>}},
+		dev_snp_nif:derive_key(#{ fields => [host_data] })
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"vmpl">>}},
+		dev_snp_nif:derive_key(#{ vmpl => 4 })
+	).
+
+seal_test() ->
+	%% Without `/dev/sev-guest' the software key derivation stands in, if the
+	%% NIF is built with the `software-sealing' feature
+	Policy = #{ fields => [measurement], software_fallback => true },
+	case dev_snp_nif:seal(<<"wallet key">>, Policy) of
+		{error, {invalid_argument, <<"software_fallback">>}} ->
+			?event("NIF built without software sealing, skipping...");
+		{ok, Blob} ->
+			Accept = #{ software_fallback => true },
+			?assertEqual({ok, <<"wallet key">>}, dev_snp_nif:unseal(Blob, Accept)),
+			%% Software blobs are only unsealed when asked for
+			case Blob of
+				<<"SNPS", 1, 1, _/binary>> ->
+					?assertMatch({error, {unseal_failed, _}}, dev_snp_nif:unseal(Blob));
+				_ ->
+					ok
+			end,
+			%% The header is authenticated: changing the binding (here the
+			%% VMPL) or the ciphertext makes the blob unsealable
+			<<Magic:7/binary, VMPL, Rest/binary>> = Blob,
+			?assertMatch(
+				{error, {unseal_failed, _}},
+				dev_snp_nif:unseal(<<Magic/binary, (VMPL + 1), Rest/binary>>, Accept)
+			),
+			<<Header:40/binary, First, Tail/binary>> = Blob,
+			?assertMatch(
+				{error, {unseal_failed, _}},
+				dev_snp_nif:unseal(<<Header/binary, (First bxor 1), Tail/binary>>, Accept)
+			)
+	end,
+	?assertEqual(
+		{error, {invalid_argument, <<"blob">>}},
+		dev_snp_nif:unseal(<<"not sealed">>)
+	),
+	case dev_snp_nif:check_snp_support() of
+		{ok, true} -> ok;
+		{ok, false} ->
+			?assertMatch(
+				{error, {firmware_error, _}},
+				dev_snp_nif:seal(<<"wallet key">>, #{})
+			)
+	end.
+
+report_data_test() ->
+	Address = crypto:strong_rand_bytes(32),
+	NodeMsgID = crypto:strong_rand_bytes(32),
+	%% Unhashed fields are concatenated
+	?assertEqual(
+		{ok, << Address/binary, NodeMsgID/binary >>},
+		dev_snp_nif:compute_report_data([Address, NodeMsgID], none)
+	),
+	{ok, Short} = dev_snp_nif:compute_report_data([<<"ab">>], none),
+	?assertEqual(<< "ab", 0:(62 * 8) >>, Short),
+	%% Hashed fields are length-prefixed, so their boundaries matter
+	{ok, Hashed} = dev_snp_nif:compute_report_data([<<"ab">>, <<"c">>], sha512),
+	?assertEqual(64, byte_size(Hashed)),
+	?assertNotEqual(
+		{ok, Hashed},
+		dev_snp_nif:compute_report_data([<<"a">>, <<"bc">>], sha512)
+	),
+	?assertMatch(
+		{error, {invalid_argument, <<"fields">>}},
+		dev_snp_nif:compute_report_data([Address, NodeMsgID, <<0>>], none)
+	),
+	?assertMatch(
+		{error, {invalid_argument, <<"hash">>}},
+		dev_snp_nif:compute_report_data([Address], md5)
+	),
+	%% Verification recomputes the binding from the fields
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	ReportData = list_to_binary(
+		maps:get(<<"report_data">>, hb_json:decode(MockAttestation))
+	),
+	?assertEqual(
+		{ok, true},
+		dev_snp_nif:verify_report_data(MockAttestation, [ReportData], none)
+	),
+	?assertEqual(
+		{ok, false},
+		dev_snp_nif:verify_report_data(MockAttestation, [Address, NodeMsgID], sha512)
+	).
+
 compute_launch_digest_test() ->
 	%% Define the data structure
 	ArgsMap = #{ 
@@ -60,13 +349,210 @@ compute_launch_digest_test() ->
 	},
 
 	?event(ArgsMap),
+	%% The image measured for the `firmware' hash must be registered
+	{ok, _} = dev_snp_nif:register_ovmf("test/OVMF-1.55.fd"),
 
 		%% Call the NIF
 	{ok, Result} = dev_snp_nif:compute_launch_digest(ArgsMap),
+	#{ measurement := Measurement, measurement_hex := MeasurementHex } = Result,
 	%% Expected result
     EncTestVector =
         <<"wmSDSQYuzE2M3rQcourJnDJHgalADM8TBev3gyjM5ObRNOn8oglvVznFbaWhajU_">>,
-	?assertMatch(EncTestVector, hb_util:encode(Result)).
+	?assertMatch(EncTestVector, hb_util:encode(Measurement)),
+	?assertEqual(MeasurementHex, string:lowercase(binary:encode_hex(Measurement))),
+	%% The measured component hashes are returned alongside the digest
+	?assertEqual(
+		#{
+			firmware => list_to_binary(maps:get(firmware, ArgsMap)),
+			kernel => list_to_binary(maps:get(kernel, ArgsMap)),
+			initrd => list_to_binary(maps:get(initrd, ArgsMap)),
+			append => list_to_binary(maps:get(append, ArgsMap))
+		},
+		maps:get(components, Result)
+	).
+
+async_test() ->
+	ArgsMap = #{
+		vcpus => 1,
+		vcpu_type => 5,
+		vmm_type => 1,
+		guest_features => 16#1,
+		firmware => "b8c5d4082d5738db6b0fb0294174992738645df70c44cdecf7fad3a62244b788e7e408c582ee48a74b289f3acec78510",
+		kernel => "69d0cd7d13858e4fcef6bc7797aebd258730f215bc5642c4ad8e4b893cc67576",
+		initrd => "02e28b6c718bf0a5260d6f34d3c8fe0d71bf5f02af13e1bc695c6bc162120da1",
+		append => "56e1e5190622c8c6b9daa4fe3ad83f3831c305bb736735bf795b284cb462c9e7"
+	},
+	{ok, _} = dev_snp_nif:register_ovmf("test/OVMF-1.55.fd"),
+	%% The result is sent to the caller, tagged with the returned reference
+	{ok, DigestRef} = dev_snp_nif:compute_launch_digest_async(ArgsMap),
+	?assert(is_reference(DigestRef)),
+	Expected = dev_snp_nif:compute_launch_digest(ArgsMap),
+	receive
+		{snp_result, DigestRef, Digest} -> ?assertEqual(Expected, Digest)
+	after 10000 -> ?assert(false)
+	end,
+	with_milan_fixture(
+		fun(Report) ->
+			{ok, VerifyRef} = dev_snp_nif:verify_signature_async(Report, #{}),
+			receive
+				{snp_result, VerifyRef, Result} -> ?assertEqual({ok, true}, Result)
+			after 30000 -> ?assert(false)
+			end,
+			%% Invalid arguments are reported without spawning any work
+			?assertMatch(
+				{error, {invalid_argument, <<"product">>}},
+				dev_snp_nif:verify_signature_async(Report, #{ product => pentium })
+			)
+		end
+	),
+	%% Past the limit of running calls, callers are told to back off instead
+	%% of more threads being started, and every started call still completes
+	Calls = [dev_snp_nif:compute_launch_digest_async(ArgsMap) || _ <- lists:seq(1, 256)],
+	?assertEqual(
+		[],
+		[Call || Call <- Calls, not is_ok_ref(Call), not is_busy(Call)]
+	),
+	lists:foreach(
+		fun({ok, Ref}) ->
+			receive
+				{snp_result, Ref, Digest} -> ?assertEqual(Expected, Digest)
+			after 10000 -> ?assert(false)
+			end
+		end,
+		[Call || Call <- Calls, is_ok_ref(Call)]
+	).
+
+is_ok_ref({ok, Ref}) -> is_reference(Ref);
+is_ok_ref(_) -> false.
+
+is_busy({error, {busy, _}}) -> true;
+is_busy(_) -> false.
+
+compute_launch_digest_errors_test() ->
+	%% Malformed inputs are reported by field instead of crashing the caller
+	ArgsMap = #{
+		vcpus => 32,
+		vcpu_type => 5,
+		vmm_type => 1,
+		guest_features => 16#1,
+		firmware => "b8c5d4082d5738db6b0fb0294174992738645df70c44cdecf7fad3a62244b788e7e408c582ee48a74b289f3acec78510",
+		kernel => "69d0cd7d13858e4fcef6bc7797aebd258730f215bc5642c4ad8e4b893cc67576",
+		initrd => "02e28b6c718bf0a5260d6f34d3c8fe0d71bf5f02af13e1bc695c6bc162120da1",
+		append => "56e1e5190622c8c6b9daa4fe3ad83f3831c305bb736735bf795b284cb462c9e7"
+	},
+	?assertEqual(
+		{error, {bad_hex, <<"kernel">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ kernel => "not hex" })
+	),
+	?assertEqual(
+		{error, {bad_hex, <<"initrd">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ initrd => "abcd" })
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"vcpus">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ vcpus => <<"32">> })
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"vcpus">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ vcpus => 0 })
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"vcpus">>}},
+		dev_snp_nif:compute_launch_digest(maps:remove(vcpus, ArgsMap))
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"vcpu_type">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ vcpu_type => 255 })
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"vmm_type">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ vmm_type => 255 })
+	).
+
+compute_launch_digest_configurations_test() ->
+	%% The VM shape supplied by the caller is part of the measurement
+	ArgsMap = #{
+		vcpus => 32,
+		vcpu_type => 5,
+		vmm_type => 1,
+		guest_features => 16#1,
+		firmware => "b8c5d4082d5738db6b0fb0294174992738645df70c44cdecf7fad3a62244b788e7e408c582ee48a74b289f3acec78510",
+		kernel => "69d0cd7d13858e4fcef6bc7797aebd258730f215bc5642c4ad8e4b893cc67576",
+		initrd => "02e28b6c718bf0a5260d6f34d3c8fe0d71bf5f02af13e1bc695c6bc162120da1",
+		append => "56e1e5190622c8c6b9daa4fe3ad83f3831c305bb736735bf795b284cb462c9e7"
+	},
+	{ok, _} = dev_snp_nif:register_ovmf("test/OVMF-1.55.fd"),
+	Configurations = [
+		ArgsMap,
+		ArgsMap#{ vcpus => 1 },
+		ArgsMap#{ vcpus => 4 },
+		ArgsMap#{ vcpu_type => 6 },
+		ArgsMap#{ guest_features => 16#0 }
+	],
+	Digests =
+		lists:map(
+			fun(Args) ->
+				{ok, #{ measurement := Digest }} =
+					dev_snp_nif:compute_launch_digest(Args),
+				Digest
+			end,
+			Configurations
+		),
+	?assertEqual(length(Configurations), length(lists:usort(Digests))),
+	%% The computation is deterministic for a given configuration
+	OneVcpuDigest = lists:nth(2, Digests),
+	?assertMatch(
+		{ok, #{ measurement := OneVcpuDigest }},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ vcpus => 1 })
+	).
+
+compute_launch_digest_ovmf_test() ->
+	ArgsMap = #{
+		vcpus => 32,
+		vcpu_type => 5,
+		vmm_type => 1,
+		guest_features => 16#1,
+		kernel => "69d0cd7d13858e4fcef6bc7797aebd258730f215bc5642c4ad8e4b893cc67576",
+		initrd => "02e28b6c718bf0a5260d6f34d3c8fe0d71bf5f02af13e1bc695c6bc162120da1",
+		append => "56e1e5190622c8c6b9daa4fe3ad83f3831c305bb736735bf795b284cb462c9e7"
+	},
+	%% Registering an image yields the hash expected in the `firmware' key
+	{ok, FirmwareHash} = dev_snp_nif:register_ovmf("test/OVMF-1.55.fd"),
+	?assertEqual(
+		<<"b8c5d4082d5738db6b0fb0294174992738645df70c44cdecf7fad3a62244b788e7e408c582ee48a74b289f3acec78510">>,
+		FirmwareHash
+	),
+	%% The image can be given per call, by path or by registered hash
+	{ok, ByHash} = dev_snp_nif:compute_launch_digest(ArgsMap#{ firmware => FirmwareHash }),
+	{ok, ByFile} =
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ ovmf_file => "test/OVMF-1.55.fd" }),
+	?assertEqual(ByHash, ByFile),
+	%% A hash given along with the file must be the file's
+	?assertEqual(
+		{ok, ByFile},
+		dev_snp_nif:compute_launch_digest(
+			ArgsMap#{ firmware => FirmwareHash, ovmf_file => "test/OVMF-1.55.fd" }
+		)
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"firmware">>}},
+		dev_snp_nif:compute_launch_digest(
+			ArgsMap#{ firmware => binary:copy(<<"00">>, 48), ovmf_file => "test/OVMF-1.55.fd" }
+		)
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"ovmf_file">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ ovmf_file => "test/missing.fd" })
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"firmware">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap)
+	),
+	%% The digest cannot be computed from a hash without its image
+	?assertEqual(
+		{error, {invalid_argument, <<"firmware">>}},
+		dev_snp_nif:compute_launch_digest(ArgsMap#{ firmware => binary:copy(<<"ab">>, 48) })
+	).
 
 verify_measurement_test() ->
 	%% Define a mock report (JSON string) as binary
@@ -82,3 +568,862 @@ verify_signature_test() ->
     {ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
 	Result = dev_snp_nif:verify_signature(MockAttestation),
 	?assertMatch({ok, true}, Result).
+
+seed_cert_cache_test() ->
+	%% Seed a fresh cache from the AMD chains shipped in `certificates/'
+	CacheDir = "_build/test-snp-cert-cache",
+	with_cert_env(CacheDir, [], [],
+		fun() ->
+			?assertMatch({ok, 3}, dev_snp_nif:seed_cert_cache("certificates")),
+			?assertEqual({ok, list_to_binary(CacheDir)}, dev_snp_nif:cert_cache_dir()),
+			lists:foreach(
+				fun(Product) ->
+					?assert(
+						filelib:is_regular(filename:join([CacheDir, Product, "cert_chain.pem"]))
+					)
+				end,
+				["Milan", "Genoa", "Turin"]
+			)
+		end
+	).
+
+shipped_chains_test() ->
+	%% Every shipped chain ends in an embedded pin, so it is accepted without
+	%% `pin_ark/2' and verification proceeds until the (uncached) CRL is needed.
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	with_cert_env("_build/test-snp-shipped-cache", ["certificates"], [],
+		fun() ->
+			lists:foreach(
+				fun(Product) ->
+					?assertMatch(
+						{error, {kds_unreachable, _}},
+						dev_snp_nif:verify_signature(MockAttestation, #{ product => Product })
+					)
+				end,
+				[milan, genoa, bergamo, turin]
+			)
+		end
+	).
+
+local_kds_test() ->
+	%% Serve the AMD chain shipped in `certificates/' from a local KDS stand-in
+	Root = "_build/test-snp-kds",
+	ChainDir = filename:join([Root, "vcek", "v1", "Milan"]),
+	ok = filelib:ensure_dir(filename:join(ChainDir, "cert_chain")),
+	{ok, _} =
+		file:copy(
+			"certificates/amd-vcek-v1-Milan-cert_chain.pem",
+			filename:join(ChainDir, "cert_chain")
+		),
+	application:ensure_all_started(inets),
+	{ok, Httpd} =
+		inets:start(httpd, [
+			{port, 0},
+			{server_name, "localhost"},
+			{bind_address, {127, 0, 0, 1}},
+			{server_root, Root},
+			{document_root, Root}
+		]),
+	[{port, Port}] = httpd:info(Httpd, [port]),
+	CacheDir = "_build/test-snp-kds-cache",
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	try
+		with_cert_env(CacheDir, [], [],
+			fun() ->
+				ok = dev_snp_nif:configure_cert_provider(#{
+					base_url => iolist_to_binary(["http://127.0.0.1:", integer_to_list(Port)])
+				}),
+				%% The stand-in has no VCEK for the report's chip, so
+				%% verification fails, but only after the chain has been
+				%% fetched and cached.
+				?assertMatch({error, _}, dev_snp_nif:verify_signature(MockAttestation)),
+				?assert(filelib:is_regular(filename:join([CacheDir, "Milan", "cert_chain.pem"])))
+			end
+		)
+	after
+		inets:stop(httpd, Httpd)
+	end.
+
+report_product_test() ->
+	%% Version 2 reports carry no CPUID information and are treated as Milan
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	?assertEqual({ok, milan}, dev_snp_nif:report_product(MockAttestation)),
+	%% From version 3 the CPUID family and model IDs identify the generation
+	Report = hb_json:decode(MockAttestation),
+	WithCpuid =
+		fun(Family, Model) ->
+			hb_json:encode(Report#{
+				<<"version">> => 3,
+				<<"_reserved_1">> => [Family, Model | lists:duplicate(22, 0)]
+			})
+		end,
+	?assertEqual({ok, milan}, dev_snp_nif:report_product(WithCpuid(16#19, 16#01))),
+	?assertEqual({ok, genoa}, dev_snp_nif:report_product(WithCpuid(16#19, 16#11))),
+	?assertEqual({ok, bergamo}, dev_snp_nif:report_product(WithCpuid(16#19, 16#A0))),
+	?assertEqual({ok, turin}, dev_snp_nif:report_product(WithCpuid(16#1A, 16#02))).
+
+product_fixtures_test() ->
+	%% Synthetic, locally signed Genoa and Turin reports with their chain, CRL
+	%% and VCEK, served by a KDS stand-in that only answers the exact URLs the
+	%% KDS uses. Turin VCEKs are looked up by the first eight bytes of the chip
+	%% ID, with a TCB that starts with the FMC SPL.
+	lists:foreach(
+		fun({Product, Name, VcekPath}) ->
+			Dir = filename:join("test/snp-fixtures", Name),
+			ChainFile = filename:join(Dir, "cert_chain.pem"),
+			[VcekFile] = filelib:wildcard(filename:join([Dir, "vcek", "*", "*.der"])),
+			{ok, Report} =
+				file:read_file("test/snp-" ++ atom_to_list(Product) ++ "-attestation.json"),
+			?assertEqual({ok, Product}, dev_snp_nif:report_product(Report)),
+			Base = list_to_binary("/vcek/v1/" ++ Name),
+			{Listen, Port} =
+				kds_stub(#{
+					<<Base/binary, "/cert_chain">> => ChainFile,
+					<<Base/binary, "/crl">> => filename:join(Dir, "crl.der"),
+					VcekPath => VcekFile
+				}),
+			CacheDir = "_build/test-snp-" ++ atom_to_list(Product) ++ "-cache",
+			try
+				with_cert_env(CacheDir, [], [{Product, ChainFile}],
+					fun() ->
+						ok = dev_snp_nif:configure_cert_provider(#{
+							base_url =>
+								iolist_to_binary(["http://127.0.0.1:", integer_to_list(Port)])
+						}),
+						?assertEqual({ok, true}, dev_snp_nif:verify_signature(Report)),
+						?assert(lists:member(VcekPath, kds_stub_requests())),
+						%% Everything is now cached, so the report also verifies
+						%% offline
+						ok = dev_snp_nif:configure_cert_provider(#{ mode => local }),
+						?assertEqual({ok, true}, dev_snp_nif:verify_signature(Report))
+					end
+				)
+			after
+				gen_tcp:close(Listen)
+			end
+		end,
+		[
+			{genoa, "Genoa",
+				<<"/vcek/v1/Genoa/00f7ff15b404d4b8923c4091223e85da97d559d490d300047540a08e28fe6dbb"
+					"5c465836b5a10859b6a41f61f23c12d186fb32f81ef014af6774341a0add77a9"
+					"?blSPL=07&teeSPL=00&snpSPL=21&ucodeSPL=72">>},
+			{turin, "Turin",
+				<<"/vcek/v1/Turin/20dbb9d750023ce3"
+					"?fmcSPL=01&blSPL=01&teeSPL=00&snpSPL=03&ucodeSPL=76">>}
+		]
+	).
+
+%% @doc Serves the files in `Routes' (keyed by request path, including the
+%% query string) over HTTP on a local port, answering 404 to anything else.
+%% Each request path is sent to the calling process.
+kds_stub(Routes) ->
+	{ok, Listen} =
+		gen_tcp:listen(0, [
+			binary,
+			{packet, http_bin},
+			{active, false},
+			{ip, {127, 0, 0, 1}}
+		]),
+	{ok, Port} = inet:port(Listen),
+	Parent = self(),
+	spawn_link(fun() -> kds_stub_loop(Listen, Routes, Parent) end),
+	{Listen, Port}.
+
+kds_stub_loop(Listen, Routes, Parent) ->
+	case gen_tcp:accept(Listen) of
+		{ok, Socket} ->
+			{ok, {http_request, _, {abs_path, Path}, _}} = gen_tcp:recv(Socket, 0),
+			kds_stub_skip_headers(Socket),
+			Parent ! {kds_stub_request, Path},
+			{Status, Body} =
+				case maps:find(Path, Routes) of
+					{ok, File} ->
+						{ok, Contents} = file:read_file(File),
+						{<<"200 OK">>, Contents};
+					error ->
+						{<<"404 Not Found">>, <<>>}
+				end,
+			ok = inet:setopts(Socket, [{packet, raw}]),
+			gen_tcp:send(Socket, [
+				<<"HTTP/1.1 ">>, Status,
+				<<"\r\nContent-Length: ">>, integer_to_binary(byte_size(Body)),
+				<<"\r\nConnection: close\r\n\r\n">>, Body
+			]),
+			gen_tcp:close(Socket),
+			kds_stub_loop(Listen, Routes, Parent);
+		{error, _} ->
+			ok
+	end.
+
+kds_stub_skip_headers(Socket) ->
+	case gen_tcp:recv(Socket, 0) of
+		{ok, {http_header, _, _, _, _}} -> kds_stub_skip_headers(Socket);
+		{ok, http_eoh} -> ok
+	end.
+
+%% @doc The request paths received by `kds_stub/1' so far.
+kds_stub_requests() ->
+	receive
+		{kds_stub_request, Path} -> [Path | kds_stub_requests()]
+	after 0 ->
+		[]
+	end.
+
+%% @doc Runs `Fun' with certificates looked up only in a fresh cache at
+%% `CacheDir', seeded from the directories in `Seeds', and with the ARKs of
+%% the chain files in `Pins' (`{Product, ChainFile}') pinned. The cache
+%% directory, provider and pins are restored afterwards, so that no test
+%% depends on those run before it.
+with_cert_env(CacheDir, Seeds, Pins, Fun) ->
+	{ok, PreviousDir} = dev_snp_nif:cert_cache_dir(),
+	Arks = [{Product, chain_ark(ChainFile)} || {Product, ChainFile} <- Pins],
+	file:del_dir_r(CacheDir),
+	try
+		ok = dev_snp_nif:set_cert_cache_dir(CacheDir),
+		lists:foreach(fun(Dir) -> {ok, _} = dev_snp_nif:seed_cert_cache(Dir) end, Seeds),
+		lists:foreach(fun({Product, Ark}) -> ok = dev_snp_nif:pin_ark(Product, Ark) end, Arks),
+		ok = dev_snp_nif:configure_cert_provider(#{ mode => local }),
+		Fun()
+	after
+		lists:foreach(fun({Product, Ark}) -> dev_snp_nif:unpin_ark(Product, Ark) end, Arks),
+		dev_snp_nif:configure_cert_provider(#{ mode => kds }),
+		dev_snp_nif:set_cert_cache_dir(PreviousDir)
+	end.
+
+%% @doc Runs `Fun' on the synthetic, locally signed Milan report of
+%% `test/snp-fixtures', whose certificates are all cached, so that it verifies
+%% without contacting the KDS.
+with_milan_fixture(Fun) ->
+	{ok, Report} = file:read_file("test/snp-milan-attestation.json"),
+	with_cert_env(
+		"_build/test-snp-milan-fixture-cache",
+		["test/snp-fixtures"],
+		[{milan, "test/snp-fixtures/Milan/cert_chain.pem"}],
+		fun() -> Fun(Report) end
+	).
+
+%% @doc The ARK of a PEM chain file (its last certificate), as PEM.
+chain_ark(ChainFile) ->
+	{ok, Chain} = file:read_file(ChainFile),
+	public_key:pem_encode([lists:last(public_key:pem_decode(Chain))]).
+
+decode_report_test() ->
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	{ok, Decoded} = dev_snp_nif:decode_report(MockAttestation),
+	?assertMatch(
+		#{
+			version := 2,
+			vmpl := 1,
+			product := milan,
+			policy :=
+				#{
+					raw := 16#30000,
+					abi_major := 0,
+					abi_minor := 0,
+					smt_allowed := true,
+					migrate_ma := false,
+					debug := false,
+					single_socket := false
+				},
+			platform_info := #{ smt_enabled := true, tsme_enabled := true, ecc_enabled := false },
+			signing_key := vcek,
+			signature_algorithm := ecdsa_p384_sha384,
+			current_tcb := #{ bootloader := 4, tee := 0, snp := 22, microcode := 213 },
+			current_firmware := <<"1.55.20">>
+		},
+		Decoded
+	),
+	?assertEqual(96, byte_size(maps:get(measurement, Decoded))),
+	%% The raw encoding decodes to the same values
+	{ok, RawReport} = dev_snp_nif:convert_report(MockAttestation, binary),
+	?assertEqual({ok, Decoded}, dev_snp_nif:decode_report(RawReport)),
+	Debug = hb_json:encode((hb_json:decode(MockAttestation))#{ <<"policy">> => 16#B0000 }),
+	?assertMatch(
+		{ok, #{ policy := #{ debug := true } }},
+		dev_snp_nif:decode_report(Debug)
+	).
+
+id_block_test() ->
+	NewKey =
+		fun() ->
+			Key = public_key:generate_key({namedCurve, secp384r1}),
+			public_key:pem_encode([public_key:pem_entry_encode('ECPrivateKey', Key)])
+		end,
+	IdKey = NewKey(),
+	AuthorKey = NewKey(),
+	Measurement = crypto:strong_rand_bytes(48),
+	ImageID = binary:copy(<<1>>, 16),
+	{ok, #{
+		id_block := IdBlock,
+		id_auth := IdAuth,
+		id_key_digest := IdDigest,
+		author_key_digest := AuthorDigest
+	}} =
+		dev_snp_nif:generate_id_block(
+			#{
+				measurement => Measurement,
+				id_key => IdKey,
+				author_key => AuthorKey,
+				image_id => ImageID
+			}
+		),
+	?assertMatch(
+		<< Measurement:48/binary, 0:128, ImageID:16/binary, 1:32/little, 0:32/little,
+			16#30000:64/little >>,
+		IdBlock
+	),
+	?assertEqual(4096, byte_size(IdAuth)),
+	?assertEqual(96, byte_size(IdDigest)),
+	?assertMatch(
+		{ok, #{ author_key_digest := none, id_key_digest := IdDigest }},
+		dev_snp_nif:generate_id_block(#{ measurement => Measurement, id_key => IdKey })
+	),
+	?assertMatch(
+		{error, {invalid_argument, <<"id_key">>}},
+		dev_snp_nif:generate_id_block(#{ measurement => Measurement })
+	),
+	%% A report launched with the ID block carries the key digests
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	Launched =
+		hb_json:encode(
+			(hb_json:decode(MockAttestation))#{
+				<<"id_key_digest">> => binary_to_list(binary:decode_hex(IdDigest)),
+				<<"author_key_digest">> => binary_to_list(binary:decode_hex(AuthorDigest))
+			}
+		),
+	?assertEqual(
+		{ok, true},
+		dev_snp_nif:verify_id_key(Launched, #{ id_key => IdKey, author_key => AuthorDigest })
+	),
+	?assertEqual(
+		{ok, false},
+		dev_snp_nif:verify_id_key(Launched, #{ author_key => [IdKey, NewKey()] })
+	),
+	?assertEqual(
+		{ok, false},
+		dev_snp_nif:verify_id_key(MockAttestation, #{ id_key => IdKey })
+	),
+	?assertMatch(
+		{error, {invalid_argument, <<"id_key">>}},
+		dev_snp_nif:verify_id_key(Launched, #{ id_key => <<"not a key">> })
+	),
+	%% Identity policies trust the keys rather than the measurement
+	?assertMatch(
+		{ok, #{ valid := true, checks := #{ id_key := pass, author_key := pass } }},
+		dev_snp_nif:verify_report(Launched, #{ id_keys => IdKey, author_keys => [AuthorKey] })
+	).
+
+digest_calc_id_block_test() ->
+	%% The `id-block' subcommand of digest_calc has its own copy of the ID
+	%% block code, which must produce the same bytes as `generate_id_block/1'.
+	Built =
+		[
+			Path
+		||
+			Path <- [
+				"native/digest_calc/target/release/digest_calc",
+				"native/digest_calc/target/debug/digest_calc"
+			],
+			filelib:is_regular(Path)
+		],
+	case Built of
+		[] ->
+			?event("digest_calc is not built, skipping test..."),
+			?assertEqual(ok, ok);
+		[DigestCalc | _] ->
+			digest_calc_id_block(DigestCalc)
+	end.
+
+digest_calc_id_block(DigestCalc) ->
+	Dir = "_build/test-digest-calc",
+	file:del_dir_r(Dir),
+	ok = filelib:ensure_path(Dir),
+	NewKey =
+		fun(Name) ->
+			Key = public_key:generate_key({namedCurve, secp384r1}),
+			Pem = public_key:pem_encode([public_key:pem_entry_encode('ECPrivateKey', Key)]),
+			Path = filename:join(Dir, Name),
+			ok = file:write_file(Path, Pem),
+			{Pem, Path}
+		end,
+	{IdKey, IdKeyPath} = NewKey("id_key.pem"),
+	{AuthorKey, AuthorKeyPath} = NewKey("author_key.pem"),
+	Measurement = crypto:strong_rand_bytes(48),
+	FamilyID = crypto:strong_rand_bytes(16),
+	ImageID = crypto:strong_rand_bytes(16),
+	{ok, Expected} =
+		dev_snp_nif:generate_id_block(
+			#{
+				measurement => Measurement,
+				family_id => FamilyID,
+				image_id => ImageID,
+				guest_svn => 7,
+				policy => 16#30000,
+				id_key => IdKey,
+				author_key => AuthorKey
+			}
+		),
+	Output =
+		os:cmd(
+			lists:join(
+				" ",
+				[
+					DigestCalc, "id-block",
+					"--measurement", binary_to_list(binary:encode_hex(Measurement)),
+					"--family-id", binary_to_list(binary:encode_hex(FamilyID)),
+					"--image-id", binary_to_list(binary:encode_hex(ImageID)),
+					"--guest-svn", "7",
+					"--policy", "0x30000",
+					"--id-key", IdKeyPath,
+					"--author-key", AuthorKeyPath
+				]
+			)
+		),
+	Sections = digest_calc_sections(string:split(Output, "\n", all), undefined, #{}),
+	?assertEqual(
+		maps:get(id_block, Expected),
+		base64:decode(maps:get("ID Block", Sections))
+	),
+	?assertEqual(
+		string:lowercase(maps:get(id_key_digest, Expected)),
+		list_to_binary(maps:get("ID Key Digest", Sections))
+	),
+	?assertEqual(
+		string:lowercase(maps:get(author_key_digest, Expected)),
+		list_to_binary(maps:get("Author Key Digest", Sections))
+	),
+	%% ECDSA signatures are randomized, so only the rest of the ID auth matches
+	Unsigned =
+		fun(<< Head:16#40/binary, _:16#200/binary, Keys:16#440/binary, _:16#200/binary,
+				Tail/binary >>) ->
+			<< Head/binary, Keys/binary, Tail/binary >>
+		end,
+	?assertEqual(
+		Unsigned(maps:get(id_auth, Expected)),
+		Unsigned(base64:decode(maps:get("ID Auth", Sections)))
+	),
+	%% Values that do not fit the ID block are rejected
+	?assertNotEqual(
+		nomatch,
+		string:find(
+			os:cmd(
+				lists:join(
+					" ",
+					[
+						DigestCalc, "id-block",
+						"--measurement", binary_to_list(binary:encode_hex(Measurement)),
+						"--id-key", IdKeyPath,
+						"--guest-svn", "4294967296"
+					]
+				)
+			),
+			"guest_svn must fit in 32 bits"
+		)
+	),
+	file:del_dir_r(Dir).
+
+%% Splits digest_calc output into a map from `===== Name =====' headers to
+%% the line that follows each.
+digest_calc_sections([], _, Sections) ->
+	Sections;
+digest_calc_sections(["===== " ++ Header | Lines], _, Sections) ->
+	digest_calc_sections(Lines, string:trim(Header, trailing, " ="), Sections);
+digest_calc_sections(["" | Lines], Name, Sections) ->
+	digest_calc_sections(Lines, Name, Sections);
+digest_calc_sections([_ | Lines], undefined, Sections) ->
+	digest_calc_sections(Lines, undefined, Sections);
+digest_calc_sections([Line | Lines], Name, Sections) ->
+	digest_calc_sections(Lines, undefined, Sections#{ Name => Line }).
+
+convert_report_test() ->
+	%% A JSON report converts to the 1184-byte firmware encoding and back
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	{ok, Raw} = dev_snp_nif:convert_report(MockAttestation, binary),
+	?assertEqual(1184, byte_size(Raw)),
+	{ok, JSON} = dev_snp_nif:convert_report(Raw, json),
+	?assertEqual(hb_json:decode(MockAttestation), hb_json:decode(JSON)),
+	%% Missing or malformed fields are reported by name
+	Report = hb_json:decode(MockAttestation),
+	?assertEqual(
+		{error, {invalid_report, <<"measurement">>}},
+		dev_snp_nif:convert_report(
+			hb_json:encode(maps:remove(<<"measurement">>, Report)),
+			binary
+		)
+	),
+	?assertEqual(
+		{error, {invalid_report, <<"current_tcb.snp">>}},
+		dev_snp_nif:verify_signature(
+			hb_json:encode(Report#{
+				<<"current_tcb">> =>
+					(maps:get(<<"current_tcb">>, Report))#{ <<"snp">> => 256 }
+			})
+		)
+	).
+
+verify_report_test() ->
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	Measurement =
+		<<"4460a293f792d04ad40b37d775212a7a11157c76cbb5a98a36da62a2d6131038"
+			"238e40e5f08ff9529e7d3d1019d93160">>,
+	Policy = #{
+		measurements => [Measurement],
+		min_tcb => #{ bootloader => 4, snp => 22, microcode => 213 },
+		guest_policy => #{ debug => false, smt => true },
+		vmpl => 1,
+		family_ids => [<<0:128>>],
+		host_data => <<0:256>>
+	},
+	{ok, #{ valid := true, checks := Checks }} =
+		dev_snp_nif:verify_report(MockAttestation, Policy),
+	?assertEqual(
+		[family_id, guest_policy, host_data, measurement, min_tcb, vmpl],
+		lists:sort(maps:keys(Checks))
+	),
+	?assert(lists:all(fun(Result) -> Result == pass end, maps:values(Checks))),
+	%% Every failing check is reported, not just the first
+	{ok, #{ valid := false, checks := FailedChecks }} =
+		dev_snp_nif:verify_report(
+			MockAttestation,
+			Policy#{
+				min_tcb => #{ snp => 23 },
+				guest_policy => #{ migrate_ma => true },
+				vmpl => 0
+			}
+		),
+	?assertEqual(pass, maps:get(measurement, FailedChecks)),
+	?assertMatch({fail, _}, maps:get(min_tcb, FailedChecks)),
+	?assertMatch({fail, _}, maps:get(guest_policy, FailedChecks)),
+	?assertMatch({fail, _}, maps:get(vmpl, FailedChecks)),
+	%% Unknown policy entries are ignored, malformed ones rejected
+	?assertMatch(
+		{ok, #{ valid := true }},
+		dev_snp_nif:verify_report(MockAttestation, Policy#{ measurments => [] })
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"nonce">>}},
+		dev_snp_nif:verify_report(MockAttestation, #{ nonce => <<>> })
+	),
+	?assertEqual(
+		{error, {bad_hex, <<"host_data">>}},
+		dev_snp_nif:verify_report(MockAttestation, #{ host_data => <<"00">> })
+	).
+
+min_tcb_test() ->
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	Check =
+		fun(MinTcb, Opts) ->
+			{ok, #{ checks := #{ min_tcb := Result } }} =
+				dev_snp_nif:verify_report(MockAttestation, Opts#{ min_tcb => MinTcb }),
+			Result
+		end,
+	%% The report is from a Milan part at SNP SPL 22 and microcode SPL 213
+	?assertEqual(pass, Check(#{ snp => 22, microcode => 213 }, #{})),
+	?assertMatch({fail, _}, Check(#{ microcode => 214 }, #{})),
+	%% Per-product floors take precedence over the generic one
+	?assertEqual(pass, Check(#{ snp => 99, milan => #{ snp => 20 } }, #{})),
+	?assertEqual(pass, Check(#{ genoa => #{ snp => 99 } }, #{})),
+	?assertMatch({fail, _}, Check(#{ genoa => #{ snp => 99 } }, #{ product => genoa })),
+	%% Turin lays out its TCB differently: byte 3 holds the SNP SPL
+	?assertMatch({fail, _}, Check(#{ snp => 1 }, #{ product => turin })),
+	?assertEqual(
+		{error, {invalid_argument, <<"min_tcb.milan.ucode">>}},
+		dev_snp_nif:verify_report(
+			MockAttestation,
+			#{ min_tcb => #{ milan => #{ ucode => 1 } } }
+		)
+	),
+	%% `verify_signature/2' rejects reports below the floor with the component
+	?assertEqual(
+		{error, {tcb_below_minimum, <<"reported_tcb.snp">>}},
+		dev_snp_nif:verify_signature(
+			MockAttestation,
+			#{ min_tcb => #{ milan => #{ snp => 23 } } }
+		)
+	).
+
+untrusted_crl_test() ->
+	%% A CRL that is not signed by the ARK is rejected before the VCEK is
+	%% fetched, so this runs against the AMD chain shipped in `certificates/'.
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	with_cert_env("_build/test-snp-crl-cache", ["certificates"], [],
+		fun() ->
+			ok =
+				dev_snp_nif:configure_crl(
+					#{ mode => file, path => "test/snp-untrusted-crl.der" }
+				),
+			try
+				?assertMatch(
+					{error, {crl_invalid, _}},
+					dev_snp_nif:verify_signature(MockAttestation)
+				)
+			after
+				dev_snp_nif:configure_crl(#{ mode => provider })
+			end
+		end
+	),
+	?assertEqual(
+		{error, {invalid_argument, <<"path">>}},
+		dev_snp_nif:configure_crl(#{ mode => file })
+	).
+
+pinned_ark_test() ->
+	%% A chain whose ARK is not pinned is rejected, even from the cache
+	CacheDir = "_build/test-snp-pin-cache",
+	MilanChainFile = "certificates/amd-vcek-v1-Milan-cert_chain.pem",
+	{ok, MilanChain} = file:read_file(MilanChainFile),
+	ArkPem = chain_ark(MilanChainFile),
+	{ok, MockAttestation} = file:read_file("test/snp-attestation.json"),
+	with_cert_env(CacheDir, [], [],
+		fun() ->
+			GenoaDir = filename:join([CacheDir, "Genoa"]),
+			ok = filelib:ensure_dir(filename:join(GenoaDir, "cert_chain.pem")),
+			ok = file:write_file(filename:join(GenoaDir, "cert_chain.pem"), MilanChain),
+			?assertMatch(
+				{error, {untrusted_root, _}},
+				dev_snp_nif:verify_signature(MockAttestation, #{ product => genoa })
+			),
+			%% Pins can be added from Erlang, as fingerprints or certificates
+			?assertEqual(
+				{error, {bad_hex, <<"pin">>}},
+				dev_snp_nif:pin_ark(genoa, <<"not a pin">>)
+			),
+			ok = dev_snp_nif:pin_ark(genoa, ArkPem),
+			try
+				%% With the ARK pinned the chain is accepted, and verification
+				%% proceeds until the (uncached) CRL is needed.
+				?assertMatch(
+					{error, {kds_unreachable, _}},
+					dev_snp_nif:verify_signature(MockAttestation, #{ product => genoa })
+				),
+				%% and removing the pin distrusts the chain again
+				ok = dev_snp_nif:unpin_ark(genoa, ArkPem),
+				?assertMatch(
+					{error, {untrusted_root, _}},
+					dev_snp_nif:verify_signature(MockAttestation, #{ product => genoa })
+				)
+			after
+				dev_snp_nif:unpin_ark(genoa, ArkPem)
+			end
+		end
+	).
+
+verify_reports_test() ->
+	with_milan_fixture(fun verify_reports/1).
+
+verify_reports(MockAttestation) ->
+	Report = hb_json:decode(MockAttestation),
+	Tampered = hb_json:encode(Report#{ <<"vmpl">> => 0 }),
+	Unsigned = hb_json:encode(Report#{ <<"_author_key_en">> => 7 bsl 2 }),
+	%% Results are returned per report, in order
+	{ok, Results} =
+		dev_snp_nif:verify_reports(
+			[MockAttestation, Tampered, <<"not a report">>, MockAttestation, Unsigned]
+		),
+	?assertMatch(
+		[
+			{ok, true},
+			{error, {signature_invalid, _}},
+			{error, {invalid_report, _}},
+			{ok, true},
+			{error, {signature_invalid, _}}
+		],
+		Results
+	),
+	?assertEqual({ok, []}, dev_snp_nif:verify_reports([])),
+	?assertMatch(
+		{error, {invalid_argument, <<"product">>}},
+		dev_snp_nif:verify_reports([MockAttestation], #{ product => pentium })
+	).
+
+reported_tcb_test() ->
+	%% The VCEK is issued for the reported TCB, which trails the current TCB
+	%% of this (synthetic, locally signed) report. Only the former is cached.
+	with_milan_fixture(
+		fun(Report) ->
+			#{ <<"current_tcb">> := CurrentTcb, <<"reported_tcb">> := ReportedTcb } =
+				hb_json:decode(Report),
+			?assertNotEqual(CurrentTcb, ReportedTcb),
+			?assertEqual({ok, true}, dev_snp_nif:verify_signature(Report)),
+			?assertEqual(
+				{ok, [{ok, true}, {ok, true}]},
+				dev_snp_nif:verify_reports([Report, Report])
+			)
+		end
+	).
+
+replay_test() ->
+	with_milan_fixture(fun replay/1).
+
+replay(MockAttestation) ->
+	%% Without `replay_ttl' a report can be verified any number of times
+	?assertEqual({ok, true}, dev_snp_nif:verify_signature(MockAttestation)),
+	?assertEqual({ok, true}, dev_snp_nif:verify_signature(MockAttestation)),
+	%% With it, a report is only accepted once within the TTL
+	Opts = #{ replay_ttl => 500 },
+	?assertEqual({ok, true}, dev_snp_nif:verify_signature(MockAttestation, Opts)),
+	?assertMatch(
+		{error, {replayed_report, _}},
+		dev_snp_nif:verify_signature(MockAttestation, Opts)
+	),
+	timer:sleep(600),
+	%% Copies within a batch are replays of each other
+	{ok, Results} = dev_snp_nif:verify_reports([MockAttestation, MockAttestation], Opts),
+	?assertMatch(
+		[{error, {replayed_report, _}}, {ok, true}],
+		lists:sort(Results)
+	),
+	?assertMatch(
+		{error, {invalid_argument, <<"replay_ttl">>}},
+		dev_snp_nif:verify_signature(MockAttestation, #{ replay_ttl => -1 })
+	).
+
+challenge_nonce_test() ->
+	{ok, Nonce} = dev_snp_nif:issue_nonce(#{}),
+	?assertEqual(32, byte_size(Nonce)),
+	%% Each nonce is consumed once
+	?assertEqual({ok, true}, dev_snp_nif:consume_nonce(Nonce)),
+	?assertEqual({ok, false}, dev_snp_nif:consume_nonce(Nonce)),
+	?assertEqual({ok, false}, dev_snp_nif:consume_nonce(crypto:strong_rand_bytes(32))),
+	?assertEqual({ok, false}, dev_snp_nif:consume_nonce(<<"short">>)),
+	%% Expired nonces are rejected
+	{ok, Expired} = dev_snp_nif:issue_nonce(#{ ttl => 0 }),
+	?assertEqual({ok, false}, dev_snp_nif:consume_nonce(Expired)),
+	%% Nonces are bound into `report_data' like any other field
+	{ok, Challenge} = dev_snp_nif:issue_nonce(#{ ttl => 60000 }),
+	{ok, ReportData} = dev_snp_nif:compute_report_data([Challenge], none),
+	?assertEqual(<< Challenge/binary, 0:256 >>, ReportData),
+	?assertEqual({ok, true}, dev_snp_nif:consume_nonce(Challenge)),
+	?assertMatch(
+		{error, {invalid_argument, <<"ttl">>}},
+		dev_snp_nif:issue_nonce(#{ ttl => forever })
+	).
+
+nonce_option_test() ->
+	with_milan_fixture(fun nonce_option/1).
+
+nonce_option(MockAttestation) ->
+	Report = hb_json:decode(MockAttestation),
+	WithReportData =
+		fun(ReportData) ->
+			hb_json:encode(Report#{ <<"report_data">> => binary_to_list(ReportData) })
+		end,
+	{ok, Nonce} = dev_snp_nif:issue_nonce(#{}),
+	{ok, Other} = dev_snp_nif:issue_nonce(#{}),
+	{ok, Bound} = dev_snp_nif:compute_report_data([Nonce, <<"peer">>], sha256),
+	Challenged = WithReportData(Bound),
+	Binding = {Nonce, [<<"peer">>], sha256},
+	%% A report bound to another nonce is rejected
+	{ok, #{ valid := false, checks := #{ nonce := {fail, _} } }} =
+		dev_snp_nif:verify_report(Challenged, #{ nonce => {Other, [<<"peer">>], sha256} }),
+	%% The nonce is not consumed by a report that fails other checks...
+	{ok, #{ valid := false, checks := #{ vmpl := {fail, _}, nonce := {fail, _} } }} =
+		dev_snp_nif:verify_report(Challenged, #{ nonce => Binding, vmpl => 0 }),
+	%% ...nor by one whose signature does not verify
+	?assertMatch(
+		{error, {signature_invalid, _}},
+		dev_snp_nif:verify_signature(Challenged, #{ nonce => Binding })
+	),
+	%% but is consumed, once, by one that passes
+	?assertMatch(
+		{ok, #{ valid := true, checks := #{ nonce := pass } }},
+		dev_snp_nif:verify_report(Challenged, #{ nonce => Binding })
+	),
+	?assertMatch(
+		{ok, #{ valid := false, checks := #{ nonce := {fail, _} } }},
+		dev_snp_nif:verify_report(Challenged, #{ nonce => Binding })
+	),
+	%% A nonce given alone must start `report_data'
+	{ok, Raw} = dev_snp_nif:issue_nonce(#{}),
+	?assertMatch(
+		{ok, #{ valid := true }},
+		dev_snp_nif:verify_report(WithReportData(<< Raw/binary, 0:256 >>), #{ nonce => Raw })
+	),
+	?assertEqual({ok, true}, dev_snp_nif:consume_nonce(Other)),
+	?assertEqual(
+		{error, {invalid_argument, <<"nonce">>}},
+		dev_snp_nif:verify_signature(MockAttestation, #{ nonce => <<"short">> })
+	).
+
+vlek_test() ->
+	%% Bits 2-4 of the author key field select the signing key: the VCEK (0),
+	%% a VLEK (1) or none (7).
+	with_milan_fixture(fun vlek/1).
+
+vlek(MockAttestation) ->
+	Report = hb_json:decode(MockAttestation),
+	VlekSigned = hb_json:encode(Report#{ <<"_author_key_en">> => 1 bsl 2 }),
+	Unsigned = hb_json:encode(Report#{ <<"_author_key_en">> => 7 bsl 2 }),
+	%% VLEKs are not served by the KDS, so they must be supplied
+	?assertEqual(
+		{error, {invalid_argument, <<"vlek">>}},
+		dev_snp_nif:verify_signature(VlekSigned)
+	),
+	?assertMatch(
+		{error, {invalid_certificate, _}},
+		dev_snp_nif:verify_signature(VlekSigned, #{ vlek => <<"not a certificate">> })
+	),
+	?assertMatch({error, {signature_invalid, _}}, dev_snp_nif:verify_signature(Unsigned)).
+
+supplied_certs_test() ->
+	%% Certificates from an extended report are used instead of fetched ones,
+	%% but are held to the same checks.
+	with_milan_fixture(fun supplied_certs/1).
+
+supplied_certs(MockAttestation) ->
+	{ok, MilanChain} = file:read_file("test/snp-fixtures/Milan/cert_chain.pem"),
+	[{_, Ask, _}, {_, Ark, _}] = public_key:pem_decode(MilanChain),
+	?assertMatch(
+		{error, {invalid_certificate, _}},
+		dev_snp_nif:verify_signature(
+			MockAttestation,
+			#{ certs => #{ ark => Ark, ask => Ask, vcek => <<"not a certificate">> } }
+		)
+	),
+	%% A supplied chain must still end in a pinned ARK
+	?assertMatch(
+		{error, {untrusted_root, _}},
+		dev_snp_nif:verify_signature(MockAttestation, #{ certs => #{ ark => Ask, ask => Ark } })
+	).
+
+mock_firmware_test() ->
+	%% Only runs when the NIF is built with the `mock-firmware' feature, on a
+	%% machine without `/dev/sev-guest'.
+	try dev_snp_nif:configure_mock_firmware(#{}) of
+		ok ->
+			Measurement = crypto:strong_rand_bytes(48),
+			ok = dev_snp_nif:configure_mock_firmware(#{ measurement => Measurement }),
+			UniqueData = crypto:strong_rand_bytes(64),
+			{ok, Report} = dev_snp_nif:generate_attestation_report(UniqueData, 1),
+			?assertEqual({ok, true}, dev_snp_nif:verify_signature(Report)),
+			?assertEqual(
+				{ok, true},
+				dev_snp_nif:verify_measurement(Report, Measurement)
+			),
+			%% The extended report carries the mock chain
+			{ok, #{ report := ExtReport, certs := Certs }} =
+				dev_snp_nif:generate_extended_report(UniqueData, 1),
+			?assertEqual([ark, ask, crl, vcek], lists:sort(maps:keys(Certs))),
+			?assertEqual(
+				{ok, true},
+				dev_snp_nif:verify_signature(ExtReport, #{ certs => Certs })
+			),
+			%% Tampering with a signed field breaks the signature
+			Tampered = (hb_json:decode(Report))#{ <<"vmpl">> => 0 },
+			?assertMatch(
+				{error, {signature_invalid, _}},
+				dev_snp_nif:verify_signature(hb_json:encode(Tampered))
+			),
+			%% A report bound to an issued nonce is verified and consumes it
+			%% in one step
+			{ok, Nonce} = dev_snp_nif:issue_nonce(#{}),
+			{ok, Bound} = dev_snp_nif:generate_bound_report([Nonce], none, 1),
+			?assertEqual({ok, true}, dev_snp_nif:verify_signature(Bound, #{ nonce => Nonce })),
+			?assertMatch(
+				{error, {replayed_report, _}},
+				dev_snp_nif:verify_signature(Bound, #{ nonce => Nonce })
+			)
+	catch
+		error:{not_loaded, _} ->
+			?event("NIF built without mock firmware, skipping test...")
+	end.
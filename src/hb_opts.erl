@@ -134,6 +134,8 @@ default_message() ->
         debug_committers => false,
         debug_show_priv => false,
         snp_trusted => [],
+        % OVMF images whose `firmware' hashes `snp@1.0' can measure.
+        snp_ovmf_files => [],
         routes => [
             #{
                 % Routes for the genesis-wasm device to use a local CU, if requested.